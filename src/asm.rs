@@ -0,0 +1,852 @@
+//! Textual assembly format for the linear IR.
+//! `Display` prints blocks and instructions in a compact syntax and `FromStr` reads it back,
+//! so IR can be hand written and compared in tests without going through `little_parser`.
+//!
+//! ```text
+//! block main {
+//!     lookup static0{f} -> vreg0 @current
+//!     push vreg0
+//!     cond1: if vreg2 {
+//!         load static2{"yes"} -> vreg3
+//!         push vreg3
+//!     }
+//!     cond1: end
+//! }
+//! ```
+//! Static refs carry their data in braces, `;` starts a comment until the end of the line.
+
+use std::{fmt, str::FromStr};
+
+use crate::{
+    value::Builtin, Branch, FunctionPointer, LinearBlock, LinearInstruction, Register, Scope,
+    StaticData, StaticRef,
+};
+
+const INDENT: &str = "    ";
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.virtual_ident)
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Scope::Global => write!(f, "@global"),
+            Scope::Current => write!(f, "@current"),
+            Scope::Custom(register) => write!(f, "@[{}]", register),
+        }
+    }
+}
+
+impl fmt::Display for StaticData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StaticData::Void => write!(f, "#void"),
+            StaticData::Bool(true) => write!(f, "#t"),
+            StaticData::Bool(false) => write!(f, "#f"),
+            StaticData::Integer(int) => write!(f, "{}", int),
+            StaticData::String(string) => write_escaped(f, string, '"'),
+            StaticData::Identifier(ident) => {
+                if is_plain_symbol(ident) {
+                    write!(f, "{}", ident)
+                } else {
+                    write_escaped(f, ident, '|')
+                }
+            }
+            StaticData::List(list) => {
+                write!(f, "(")?;
+                for (i, item) in list.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl fmt::Display for StaticRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{{{}}}", self.refname, self.reftype)
+    }
+}
+
+impl fmt::Display for LinearInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_instruction(f, self, 0)
+    }
+}
+
+impl fmt::Display for LinearBlock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "block {} {{", self.ident)?;
+        write_program(f, &self.program, 1)?;
+        write!(f, "}}")
+    }
+}
+
+fn write_program(
+    f: &mut fmt::Formatter<'_>,
+    program: &[LinearInstruction],
+    depth: usize,
+) -> fmt::Result {
+    for instr in program {
+        write!(f, "{}", INDENT.repeat(depth))?;
+        write_instruction(f, instr, depth)?;
+        writeln!(f)?;
+    }
+    Ok(())
+}
+
+/// Only Cond spans multiple lines, its branch is indented one level deeper than `depth`
+fn write_instruction(
+    f: &mut fmt::Formatter<'_>,
+    instr: &LinearInstruction,
+    depth: usize,
+) -> fmt::Result {
+    match instr {
+        LinearInstruction::AcceptToFormals {
+            static_formals_list,
+        } => write!(f, "accept {}", static_formals_list),
+        LinearInstruction::NewScopeAttachedToAndReplacingCurrent => write!(f, "scope.new"),
+        LinearInstruction::PopScopeAndReplaceWithUpper => write!(f, "scope.pop"),
+        LinearInstruction::StaticRefToRegister { static_ref, to_reg } => {
+            write!(f, "load {} -> {}", static_ref, to_reg)
+        }
+        LinearInstruction::PushToStack { register } => write!(f, "push {}", register),
+        LinearInstruction::PopFromStack { register } => write!(f, "pop {}", register),
+        LinearInstruction::LinkedListInit { output_reg } => {
+            write!(f, "list.init -> {}", output_reg)
+        }
+        LinearInstruction::LinkedListAdd {
+            linked_list_reg,
+            input_reg,
+            output_reg,
+        } => write!(
+            f,
+            "list.add {} <- {} -> {}",
+            linked_list_reg, input_reg, output_reg
+        ),
+        LinearInstruction::Assign {
+            identifier,
+            from_reg,
+            scope,
+        } => write!(f, "assign {} <- {} {}", identifier, from_reg, scope),
+        LinearInstruction::Call {
+            output_reg,
+            function_pointer,
+            arguments,
+        } => write!(
+            f,
+            "call {} {} -> {}",
+            function_pointer, arguments, output_reg
+        ),
+        LinearInstruction::Lookup {
+            identifier,
+            to_reg,
+            scope,
+        } => write!(f, "lookup {} -> {} {}", identifier, to_reg, scope),
+        LinearInstruction::Cond {
+            cond_name,
+            condition,
+            branc_if_true,
+        } => {
+            writeln!(f, "{}: if {} {{", cond_name, condition)?;
+            write_program(f, &branc_if_true.program, depth + 1)?;
+            write!(f, "{}}}", INDENT.repeat(depth))
+        }
+        LinearInstruction::EndOfCond { cond_name } => write!(f, "{}: end", cond_name),
+        LinearInstruction::Return { value } => write!(f, "ret {}", value),
+        LinearInstruction::TailCall {
+            function_pointer,
+            arguments,
+        } => write!(f, "tailcall {} {}", function_pointer, arguments),
+        LinearInstruction::InitializeFunctionPointer {
+            function,
+            from_scope,
+            outpu_reg,
+        } => write!(
+            f,
+            "closure {} {} {} -> {}",
+            function.actual_func, function.formals_list, from_scope, outpu_reg
+        ),
+        LinearInstruction::Move { from_reg, to_reg } => {
+            write!(f, "move {} -> {}", from_reg, to_reg)
+        }
+        LinearInstruction::Phi {
+            cond_name,
+            inputs,
+            output_reg,
+        } => {
+            write!(f, "phi {} [", cond_name)?;
+            for (i, (clause, input)) in inputs.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " ")?;
+                }
+                match clause {
+                    Some(clause) => write!(f, "{}: {}", clause, input)?,
+                    None => write!(f, "_: {}", input)?,
+                }
+            }
+            write!(f, "] -> {}", output_reg)
+        }
+        LinearInstruction::SpillToSlot { from_reg, slot } => {
+            write!(f, "spill {} -> slot{}", from_reg, slot)
+        }
+        LinearInstruction::ReloadFromSlot { slot, to_reg } => {
+            write!(f, "reload slot{} -> {}", slot, to_reg)
+        }
+        LinearInstruction::MakeClosure {
+            function,
+            captures,
+            output_reg,
+        } => {
+            write!(
+                f,
+                "closure.flat {} {} [",
+                function.actual_func, function.formals_list
+            )?;
+            for (i, capture) in captures.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " ")?;
+                }
+                write!(f, "{}", capture)?;
+            }
+            write!(f, "] -> {}", output_reg)
+        }
+        LinearInstruction::LoadCapture { index, to_reg } => {
+            write!(f, "capture {} -> {}", index, to_reg)
+        }
+        LinearInstruction::LoadLocal {
+            depth,
+            slot,
+            to_reg,
+        } => write!(f, "local.load {} {} -> {}", depth, slot, to_reg),
+        LinearInstruction::StoreLocal {
+            depth,
+            slot,
+            from_reg,
+        } => write!(f, "local.store {} {} <- {}", depth, slot, from_reg),
+        LinearInstruction::Label { name } => write!(f, "label {}", name),
+        LinearInstruction::Jump { label } => write!(f, "jump {}", label),
+        LinearInstruction::JumpIfFalse { condition, label } => {
+            write!(f, "jump.false {} {}", condition, label)
+        }
+        LinearInstruction::PrimOp {
+            op,
+            arguments,
+            output_reg,
+        } => {
+            write!(f, "prim {}", op.name())?;
+            for argument in arguments {
+                write!(f, " {}", argument)?;
+            }
+            write!(f, " -> {}", output_reg)
+        }
+    }
+}
+
+fn write_escaped(f: &mut fmt::Formatter<'_>, string: &str, quote: char) -> fmt::Result {
+    write!(f, "{}", quote)?;
+    for c in string.chars() {
+        match c {
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\t' => write!(f, "\\t")?,
+            '\r' => write!(f, "\\r")?,
+            c if c == quote => write!(f, "\\{}", c)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "{}", quote)
+}
+
+/// Chars that end a bare symbol inside static data
+fn is_data_delimiter(c: char) -> bool {
+    c.is_whitespace() || "(){}\"|;".contains(c)
+}
+
+/// Chars that end names, registers and keywords
+fn is_word_delimiter(c: char) -> bool {
+    c.is_whitespace() || "(){}[]\"|;:@".contains(c)
+}
+
+/// Symbols that read back as themselves without `|...|` quoting
+fn is_plain_symbol(symbol: &str) -> bool {
+    !symbol.is_empty()
+        && !symbol.starts_with('#')
+        && !symbol.chars().any(is_data_delimiter)
+        && symbol.parse::<i32>().is_err()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+impl FromStr for LinearBlock {
+    type Err = AsmError;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        let mut parser = AsmParser::new(src);
+        let block = parser.block()?;
+        parser.end()?;
+        Ok(block)
+    }
+}
+
+impl FromStr for LinearInstruction {
+    type Err = AsmError;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        let mut parser = AsmParser::new(src);
+        let instr = parser.instruction()?;
+        parser.end()?;
+        Ok(instr)
+    }
+}
+
+/// Reads any number of `block` definitions, e.g. main followed by every lambda
+pub fn parse_blocks(src: &str) -> Result<Vec<LinearBlock>, AsmError> {
+    let mut parser = AsmParser::new(src);
+    let mut blocks = vec![];
+    while !parser.at_end() {
+        blocks.push(parser.block()?);
+    }
+    Ok(blocks)
+}
+
+/// Reads bare instructions without the surrounding `block name { }`
+pub fn parse_program(src: &str) -> Result<Vec<LinearInstruction>, AsmError> {
+    let mut parser = AsmParser::new(src);
+    let mut program = vec![];
+    while !parser.at_end() {
+        program.push(parser.instruction()?);
+    }
+    Ok(program)
+}
+
+struct AsmParser<'s> {
+    src: &'s str,
+    pos: usize,
+}
+
+impl<'s> AsmParser<'s> {
+    fn new(src: &'s str) -> AsmParser<'s> {
+        AsmParser { src, pos: 0 }
+    }
+    fn error(&self, message: impl Into<String>) -> AsmError {
+        let before = &self.src[..self.pos];
+        let line = before.matches('\n').count() + 1;
+        let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+        AsmError {
+            line,
+            column,
+            message: message.into(),
+        }
+    }
+    fn rest(&self) -> &'s str {
+        &self.src[self.pos..]
+    }
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+    fn skip_whitespace(&mut self) {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => {
+                    self.bump();
+                }
+                Some(';') => while !matches!(self.bump(), Some('\n') | None) {},
+                _ => return,
+            }
+        }
+    }
+    fn at_end(&mut self) -> bool {
+        self.skip_whitespace();
+        self.peek().is_none()
+    }
+    fn end(&mut self) -> Result<(), AsmError> {
+        if self.at_end() {
+            Ok(())
+        } else {
+            Err(self.error("unexpected trailing input"))
+        }
+    }
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+    fn expect(&mut self, c: char) -> Result<(), AsmError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected `{}`", c)))
+        }
+    }
+    fn take_while(&mut self, pred: impl Fn(char) -> bool) -> &'s str {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if pred(c)) {
+            self.bump();
+        }
+        &self.src[start..self.pos]
+    }
+    fn word(&mut self) -> Result<String, AsmError> {
+        self.skip_whitespace();
+        let word = self.take_while(|c| !is_word_delimiter(c));
+        if word.is_empty() {
+            Err(self.error("expected a name"))
+        } else {
+            Ok(word.to_string())
+        }
+    }
+    fn expect_word(&mut self, expected: &str) -> Result<(), AsmError> {
+        let word = self.word()?;
+        if word == expected {
+            Ok(())
+        } else {
+            Err(self.error(format!("expected `{}` but found `{}`", expected, word)))
+        }
+    }
+    fn register(&mut self) -> Result<Register, AsmError> {
+        Ok(Register {
+            virtual_ident: self.word()?,
+        })
+    }
+    fn slot(&mut self) -> Result<usize, AsmError> {
+        let word = self.word()?;
+        word.strip_prefix("slot")
+            .and_then(|slot| slot.parse().ok())
+            .ok_or_else(|| self.error(format!("expected a slot, found `{}`", word)))
+    }
+    fn index(&mut self, what: &str) -> Result<usize, AsmError> {
+        let word = self.word()?;
+        word.parse()
+            .map_err(|_| self.error(format!("expected a {}, found `{}`", what, word)))
+    }
+    fn scope(&mut self) -> Result<Scope, AsmError> {
+        self.expect('@')?;
+        if self.eat('[') {
+            let register = self.register()?;
+            self.expect(']')?;
+            return Ok(Scope::Custom(register));
+        }
+        match self.word()?.as_str() {
+            "global" => Ok(Scope::Global),
+            "current" => Ok(Scope::Current),
+            other => Err(self.error(format!("unknown scope `{}`", other))),
+        }
+    }
+    fn static_ref(&mut self) -> Result<StaticRef, AsmError> {
+        let refname = self.word()?;
+        self.expect('{')?;
+        let reftype = self.static_data()?;
+        self.expect('}')?;
+        Ok(StaticRef { refname, reftype })
+    }
+    fn static_data(&mut self) -> Result<StaticData, AsmError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some('(') => {
+                self.bump();
+                let mut list = vec![];
+                while !self.eat(')') {
+                    list.push(self.static_data()?);
+                }
+                Ok(StaticData::List(list))
+            }
+            Some('"') => Ok(StaticData::String(self.escaped('"')?)),
+            Some('|') => Ok(StaticData::Identifier(self.escaped('|')?)),
+            Some(_) => {
+                let token = self.take_while(|c| !is_data_delimiter(c));
+                match token {
+                    "" => Err(self.error("expected static data")),
+                    "#void" => Ok(StaticData::Void),
+                    "#t" => Ok(StaticData::Bool(true)),
+                    "#f" => Ok(StaticData::Bool(false)),
+                    _ if token.starts_with('#') => {
+                        Err(self.error(format!("unknown literal `{}`", token)))
+                    }
+                    _ => Ok(token
+                        .parse::<i32>()
+                        .map(StaticData::Integer)
+                        .unwrap_or_else(|_| StaticData::Identifier(token.to_string()))),
+                }
+            }
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+    fn escaped(&mut self, quote: char) -> Result<String, AsmError> {
+        self.bump();
+        let mut out = String::new();
+        loop {
+            match self.bump() {
+                Some('\\') => match self.bump() {
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some('r') => out.push('\r'),
+                    Some(c) if c == '\\' || c == quote => out.push(c),
+                    _ => return Err(self.error("bad escape")),
+                },
+                Some(c) if c == quote => return Ok(out),
+                Some(c) => out.push(c),
+                None => return Err(self.error("unterminated quote")),
+            }
+        }
+    }
+    fn arrow(&mut self, arrow: &str) -> Result<(), AsmError> {
+        self.skip_whitespace();
+        if self.rest().starts_with(arrow) {
+            self.pos += arrow.len();
+            Ok(())
+        } else {
+            Err(self.error(format!("expected `{}`", arrow)))
+        }
+    }
+    fn block(&mut self) -> Result<LinearBlock, AsmError> {
+        self.expect_word("block")?;
+        let ident = self.word()?;
+        self.expect('{')?;
+        let program = self.program_until_close()?;
+        Ok(LinearBlock { ident, program })
+    }
+    /// Instructions up to and including the closing `}`
+    fn program_until_close(&mut self) -> Result<Vec<LinearInstruction>, AsmError> {
+        let mut program = vec![];
+        while !self.eat('}') {
+            if self.at_end() {
+                return Err(self.error("expected `}`"));
+            }
+            program.push(self.instruction()?);
+        }
+        Ok(program)
+    }
+    fn instruction(&mut self) -> Result<LinearInstruction, AsmError> {
+        let mnemonic = self.word()?;
+        let instr = match mnemonic.as_str() {
+            "accept" => LinearInstruction::AcceptToFormals {
+                static_formals_list: self.static_ref()?,
+            },
+            "scope.new" => LinearInstruction::NewScopeAttachedToAndReplacingCurrent,
+            "scope.pop" => LinearInstruction::PopScopeAndReplaceWithUpper,
+            "load" => {
+                let static_ref = self.static_ref()?;
+                self.arrow("->")?;
+                LinearInstruction::StaticRefToRegister {
+                    static_ref,
+                    to_reg: self.register()?,
+                }
+            }
+            "push" => LinearInstruction::PushToStack {
+                register: self.register()?,
+            },
+            "pop" => LinearInstruction::PopFromStack {
+                register: self.register()?,
+            },
+            "list.init" => {
+                self.arrow("->")?;
+                LinearInstruction::LinkedListInit {
+                    output_reg: self.register()?,
+                }
+            }
+            "list.add" => {
+                let linked_list_reg = self.register()?;
+                self.arrow("<-")?;
+                let input_reg = self.register()?;
+                self.arrow("->")?;
+                LinearInstruction::LinkedListAdd {
+                    linked_list_reg,
+                    input_reg,
+                    output_reg: self.register()?,
+                }
+            }
+            "assign" => {
+                let identifier = self.static_ref()?;
+                self.arrow("<-")?;
+                LinearInstruction::Assign {
+                    identifier,
+                    from_reg: self.register()?,
+                    scope: self.scope()?,
+                }
+            }
+            "call" => {
+                let function_pointer = self.register()?;
+                let arguments = self.register()?;
+                self.arrow("->")?;
+                LinearInstruction::Call {
+                    output_reg: self.register()?,
+                    function_pointer,
+                    arguments,
+                }
+            }
+            "tailcall" => LinearInstruction::TailCall {
+                function_pointer: self.register()?,
+                arguments: self.register()?,
+            },
+            "lookup" => {
+                let identifier = self.static_ref()?;
+                self.arrow("->")?;
+                LinearInstruction::Lookup {
+                    identifier,
+                    to_reg: self.register()?,
+                    scope: self.scope()?,
+                }
+            }
+            "ret" => LinearInstruction::Return {
+                value: self.register()?,
+            },
+            "closure" => {
+                let actual_func = self.word()?;
+                let formals_list = self.static_ref()?;
+                let from_scope = self.scope()?;
+                self.arrow("->")?;
+                LinearInstruction::InitializeFunctionPointer {
+                    function: FunctionPointer {
+                        actual_func,
+                        formals_list,
+                    },
+                    from_scope,
+                    outpu_reg: self.register()?,
+                }
+            }
+            "move" => {
+                let from_reg = self.register()?;
+                self.arrow("->")?;
+                LinearInstruction::Move {
+                    from_reg,
+                    to_reg: self.register()?,
+                }
+            }
+            "phi" => {
+                let cond_name = self.word()?;
+                self.expect('[')?;
+                let mut inputs = vec![];
+                while !self.eat(']') {
+                    let clause = match self.word()?.as_str() {
+                        "_" => None,
+                        clause => Some(clause.parse().map_err(|_| {
+                            self.error(format!("expected a clause index, found `{}`", clause))
+                        })?),
+                    };
+                    self.expect(':')?;
+                    inputs.push((clause, self.register()?));
+                }
+                self.arrow("->")?;
+                LinearInstruction::Phi {
+                    cond_name,
+                    inputs,
+                    output_reg: self.register()?,
+                }
+            }
+            "spill" => {
+                let from_reg = self.register()?;
+                self.arrow("->")?;
+                LinearInstruction::SpillToSlot {
+                    from_reg,
+                    slot: self.slot()?,
+                }
+            }
+            "reload" => {
+                let slot = self.slot()?;
+                self.arrow("->")?;
+                LinearInstruction::ReloadFromSlot {
+                    slot,
+                    to_reg: self.register()?,
+                }
+            }
+            "closure.flat" => {
+                let actual_func = self.word()?;
+                let formals_list = self.static_ref()?;
+                self.expect('[')?;
+                let mut captures = vec![];
+                while !self.eat(']') {
+                    captures.push(self.register()?);
+                }
+                self.arrow("->")?;
+                LinearInstruction::MakeClosure {
+                    function: FunctionPointer {
+                        actual_func,
+                        formals_list,
+                    },
+                    captures,
+                    output_reg: self.register()?,
+                }
+            }
+            "capture" => {
+                let index = self.index("capture index")?;
+                self.arrow("->")?;
+                LinearInstruction::LoadCapture {
+                    index,
+                    to_reg: self.register()?,
+                }
+            }
+            "local.load" => {
+                let (depth, slot) = (self.index("depth")?, self.index("slot")?);
+                self.arrow("->")?;
+                LinearInstruction::LoadLocal {
+                    depth,
+                    slot,
+                    to_reg: self.register()?,
+                }
+            }
+            "label" => LinearInstruction::Label { name: self.word()? },
+            "jump" => LinearInstruction::Jump {
+                label: self.word()?,
+            },
+            "jump.false" => LinearInstruction::JumpIfFalse {
+                condition: self.register()?,
+                label: self.word()?,
+            },
+            "local.store" => {
+                let (depth, slot) = (self.index("depth")?, self.index("slot")?);
+                self.arrow("<-")?;
+                LinearInstruction::StoreLocal {
+                    depth,
+                    slot,
+                    from_reg: self.register()?,
+                }
+            }
+            "prim" => {
+                let name = self.word()?;
+                let op = Builtin::from_name(&name)
+                    .ok_or_else(|| self.error(format!("unknown builtin `{}`", name)))?;
+                let mut arguments = vec![];
+                loop {
+                    self.skip_whitespace();
+                    if self.rest().starts_with("->") {
+                        break;
+                    }
+                    arguments.push(self.register()?);
+                }
+                self.arrow("->")?;
+                LinearInstruction::PrimOp {
+                    op,
+                    arguments,
+                    output_reg: self.register()?,
+                }
+            }
+            cond_name if self.eat(':') => match self.word()?.as_str() {
+                "if" => {
+                    let condition = self.register()?;
+                    self.expect('{')?;
+                    LinearInstruction::Cond {
+                        cond_name: cond_name.to_string(),
+                        condition,
+                        branc_if_true: Branch {
+                            program: self.program_until_close()?,
+                        },
+                    }
+                }
+                "end" => LinearInstruction::EndOfCond {
+                    cond_name: cond_name.to_string(),
+                },
+                other => {
+                    return Err(self.error(format!("expected `if` or `end`, found `{}`", other)))
+                }
+            },
+            other => return Err(self.error(format!("unknown instruction `{}`", other))),
+        };
+        Ok(instr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use little_parser::{AtomTypes, Expression, Programm};
+
+    use super::parse_program;
+    use crate::{
+        test_ast::{boolean, call, ident, lambda},
+        LinearBlock, LinearInstruction, Register, StaticData, StaticRef, Translator,
+    };
+
+    #[test]
+    fn translated_program_round_trips() {
+        let program = Programm::Expression(vec![
+            Expression::Define(
+                "pick".into(),
+                Rc::new(lambda(
+                    &["x"],
+                    vec![Expression::Cond(vec![
+                        (
+                            ident("x"),
+                            Expression::Atom(AtomTypes::String("a \"b\"".into())),
+                        ),
+                        (
+                            boolean(true),
+                            Expression::Quote(AtomTypes::List(vec![
+                                AtomTypes::Symbol("odd sym".into()),
+                                AtomTypes::Integer(-3),
+                            ])),
+                        ),
+                    ])],
+                )),
+            ),
+            call(ident("pick"), vec![boolean(false)]),
+        ]);
+        let mut translator = Translator::default();
+        translator
+            .ast_to_intermediate_representation(program)
+            .unwrap();
+
+        for block in translator.lambda_map.values() {
+            let text = block.to_string();
+            assert_eq!(text.parse::<LinearBlock>(), Ok(block.clone()), "{}", text);
+        }
+    }
+
+    #[test]
+    fn parses_hand_written_snippet() {
+        let program = parse_program(
+            "load static0{5} -> vreg0 ; comment\n\
+             push vreg0",
+        )
+        .unwrap();
+        assert_eq!(
+            program,
+            vec![
+                LinearInstruction::StaticRefToRegister {
+                    static_ref: StaticRef {
+                        refname: "static0".into(),
+                        reftype: StaticData::Integer(5),
+                    },
+                    to_reg: Register {
+                        virtual_ident: "vreg0".into(),
+                    },
+                },
+                LinearInstruction::PushToStack {
+                    register: Register {
+                        virtual_ident: "vreg0".into(),
+                    },
+                },
+            ]
+        );
+        assert_eq!(program[0].to_string(), "load static0{5} -> vreg0");
+    }
+}
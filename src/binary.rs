@@ -0,0 +1,1032 @@
+//! Compact binary encoding of compiled IR so programs can be cached without re-parsing Scheme.
+//!
+//! Every encoding starts with `MAGIC`, the little endian `VERSION` and a byte saying what
+//! follows. Integers are little endian, strings and sequences are prefixed with a u32 length.
+
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    module::{IrModule, ModuleMetadata},
+    span::{BlockSpans, InstructionSpan, SourceMap, Span},
+    value::Builtin,
+    Branch, FunctionPointer, LinearBlock, LinearInstruction, Register, Scope, StaticData,
+    StaticRef,
+};
+
+pub const MAGIC: [u8; 4] = *b"LIRB";
+/// Bump whenever the layout of anything below changes
+pub const VERSION: u16 = 4;
+
+/// Nested Cond branches deeper than this are rejected instead of blowing the stack
+const MAX_NESTING: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadKind {
+    Instruction = 0,
+    Block = 1,
+    StaticData = 2,
+    StaticTable = 3,
+    Module = 4,
+}
+
+impl PayloadKind {
+    fn from_byte(byte: u8) -> Option<PayloadKind> {
+        Some(match byte {
+            0 => PayloadKind::Instruction,
+            1 => PayloadKind::Block,
+            2 => PayloadKind::StaticData,
+            3 => PayloadKind::StaticTable,
+            4 => PayloadKind::Module,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    WrongPayload {
+        expected: PayloadKind,
+        found: u8,
+    },
+    /// Input ended while `needed` more bytes were expected at `offset`
+    Truncated {
+        offset: usize,
+        needed: usize,
+    },
+    UnknownOpcode {
+        offset: usize,
+        opcode: u8,
+    },
+    UnknownTag {
+        offset: usize,
+        what: &'static str,
+        tag: u8,
+    },
+    InvalidUtf8 {
+        offset: usize,
+    },
+    TooDeeplyNested {
+        offset: usize,
+    },
+    TrailingBytes {
+        offset: usize,
+    },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::BadMagic => write!(f, "not an encoded IR file"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported version {} (expected {})", version, VERSION)
+            }
+            DecodeError::WrongPayload { expected, found } => {
+                write!(
+                    f,
+                    "expected payload {:?} but found kind {}",
+                    expected, found
+                )
+            }
+            DecodeError::Truncated { offset, needed } => {
+                write!(
+                    f,
+                    "truncated at byte {}, {} more bytes needed",
+                    offset, needed
+                )
+            }
+            DecodeError::UnknownOpcode { offset, opcode } => {
+                write!(f, "unknown opcode {} at byte {}", opcode, offset)
+            }
+            DecodeError::UnknownTag { offset, what, tag } => {
+                write!(f, "unknown {} tag {} at byte {}", what, tag, offset)
+            }
+            DecodeError::InvalidUtf8 { offset } => write!(f, "invalid utf-8 at byte {}", offset),
+            DecodeError::TooDeeplyNested { offset } => {
+                write!(f, "branches nested too deeply at byte {}", offset)
+            }
+            DecodeError::TrailingBytes { offset } => {
+                write!(f, "unexpected trailing bytes at byte {}", offset)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub fn encode_module(module: &IrModule) -> Vec<u8> {
+    let mut encoder = Encoder::new(PayloadKind::Module);
+    encoder.module(module);
+    encoder.finish()
+}
+
+pub fn decode_module(bytes: &[u8]) -> Result<IrModule, DecodeError> {
+    let mut decoder = Decoder::new(bytes, PayloadKind::Module)?;
+    let module = decoder.module()?;
+    decoder.finish()?;
+    Ok(module)
+}
+
+pub fn encode_block(block: &LinearBlock) -> Vec<u8> {
+    let mut encoder = Encoder::new(PayloadKind::Block);
+    encoder.block(block);
+    encoder.finish()
+}
+
+pub fn decode_block(bytes: &[u8]) -> Result<LinearBlock, DecodeError> {
+    let mut decoder = Decoder::new(bytes, PayloadKind::Block)?;
+    let block = decoder.block()?;
+    decoder.finish()?;
+    Ok(block)
+}
+
+pub fn encode_instruction(instr: &LinearInstruction) -> Vec<u8> {
+    let mut encoder = Encoder::new(PayloadKind::Instruction);
+    encoder.instruction(instr);
+    encoder.finish()
+}
+
+pub fn decode_instruction(bytes: &[u8]) -> Result<LinearInstruction, DecodeError> {
+    let mut decoder = Decoder::new(bytes, PayloadKind::Instruction)?;
+    let instr = decoder.instruction()?;
+    decoder.finish()?;
+    Ok(instr)
+}
+
+pub fn encode_static_data(data: &StaticData) -> Vec<u8> {
+    let mut encoder = Encoder::new(PayloadKind::StaticData);
+    encoder.static_data(data);
+    encoder.finish()
+}
+
+pub fn decode_static_data(bytes: &[u8]) -> Result<StaticData, DecodeError> {
+    let mut decoder = Decoder::new(bytes, PayloadKind::StaticData)?;
+    let data = decoder.static_data()?;
+    decoder.finish()?;
+    Ok(data)
+}
+
+pub fn encode_static_table(table: &HashMap<String, StaticData>) -> Vec<u8> {
+    let mut encoder = Encoder::new(PayloadKind::StaticTable);
+    encoder.static_table(table);
+    encoder.finish()
+}
+
+pub fn decode_static_table(bytes: &[u8]) -> Result<HashMap<String, StaticData>, DecodeError> {
+    let mut decoder = Decoder::new(bytes, PayloadKind::StaticTable)?;
+    let table = decoder.static_table()?;
+    decoder.finish()?;
+    Ok(table)
+}
+
+#[derive(Debug)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    /// Writes the header, `kind` says what the decoder should expect next
+    pub fn new(kind: PayloadKind) -> Encoder {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.push(kind as u8);
+        Encoder { buf }
+    }
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+    fn u8(&mut self, byte: u8) {
+        self.buf.push(byte);
+    }
+    fn u32(&mut self, int: u32) {
+        self.buf.extend_from_slice(&int.to_le_bytes());
+    }
+    fn len(&mut self, len: usize) {
+        self.u32(u32::try_from(len).expect("sequence too long to encode"));
+    }
+    fn string(&mut self, string: &str) {
+        self.len(string.len());
+        self.buf.extend_from_slice(string.as_bytes());
+    }
+    fn register(&mut self, register: &Register) {
+        self.string(&register.virtual_ident);
+    }
+    fn scope(&mut self, scope: &Scope) {
+        match scope {
+            Scope::Global => self.u8(0),
+            Scope::Current => self.u8(1),
+            Scope::Custom(register) => {
+                self.u8(2);
+                self.register(register);
+            }
+        }
+    }
+    fn static_ref(&mut self, static_ref: &StaticRef) {
+        self.string(&static_ref.refname);
+        self.static_data(&static_ref.reftype);
+    }
+    pub fn static_data(&mut self, data: &StaticData) {
+        match data {
+            StaticData::Bool(boolean) => {
+                self.u8(0);
+                self.u8(*boolean as u8);
+            }
+            StaticData::Integer(int) => {
+                self.u8(1);
+                self.buf.extend_from_slice(&int.to_le_bytes());
+            }
+            StaticData::String(string) => {
+                self.u8(2);
+                self.string(string);
+            }
+            StaticData::Identifier(ident) => {
+                self.u8(3);
+                self.string(ident);
+            }
+            StaticData::List(list) => {
+                self.u8(4);
+                self.len(list.len());
+                list.iter().for_each(|item| self.static_data(item));
+            }
+            StaticData::Void => self.u8(5),
+        }
+    }
+    /// Entries are written sorted by refname so equal tables give equal bytes
+    pub fn static_table(&mut self, table: &HashMap<String, StaticData>) {
+        let mut entries: Vec<_> = table.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        self.len(entries.len());
+        for (refname, data) in entries {
+            self.string(refname);
+            self.static_data(data);
+        }
+    }
+    fn program(&mut self, program: &[LinearInstruction]) {
+        self.len(program.len());
+        program.iter().for_each(|instr| self.instruction(instr));
+    }
+    pub fn instruction(&mut self, instr: &LinearInstruction) {
+        match instr {
+            LinearInstruction::AcceptToFormals {
+                static_formals_list,
+            } => {
+                self.u8(0);
+                self.static_ref(static_formals_list);
+            }
+            LinearInstruction::NewScopeAttachedToAndReplacingCurrent => self.u8(1),
+            LinearInstruction::PopScopeAndReplaceWithUpper => self.u8(2),
+            LinearInstruction::StaticRefToRegister { static_ref, to_reg } => {
+                self.u8(3);
+                self.static_ref(static_ref);
+                self.register(to_reg);
+            }
+            LinearInstruction::PushToStack { register } => {
+                self.u8(4);
+                self.register(register);
+            }
+            LinearInstruction::PopFromStack { register } => {
+                self.u8(5);
+                self.register(register);
+            }
+            LinearInstruction::LinkedListInit { output_reg } => {
+                self.u8(6);
+                self.register(output_reg);
+            }
+            LinearInstruction::LinkedListAdd {
+                linked_list_reg,
+                input_reg,
+                output_reg,
+            } => {
+                self.u8(7);
+                self.register(linked_list_reg);
+                self.register(input_reg);
+                self.register(output_reg);
+            }
+            LinearInstruction::Assign {
+                identifier,
+                from_reg,
+                scope,
+            } => {
+                self.u8(8);
+                self.static_ref(identifier);
+                self.register(from_reg);
+                self.scope(scope);
+            }
+            LinearInstruction::Call {
+                output_reg,
+                function_pointer,
+                arguments,
+            } => {
+                self.u8(9);
+                self.register(output_reg);
+                self.register(function_pointer);
+                self.register(arguments);
+            }
+            LinearInstruction::Lookup {
+                identifier,
+                to_reg,
+                scope,
+            } => {
+                self.u8(10);
+                self.static_ref(identifier);
+                self.register(to_reg);
+                self.scope(scope);
+            }
+            LinearInstruction::Cond {
+                cond_name,
+                condition,
+                branc_if_true,
+            } => {
+                self.u8(11);
+                self.string(cond_name);
+                self.register(condition);
+                self.program(&branc_if_true.program);
+            }
+            LinearInstruction::EndOfCond { cond_name } => {
+                self.u8(12);
+                self.string(cond_name);
+            }
+            LinearInstruction::Return { value } => {
+                self.u8(13);
+                self.register(value);
+            }
+            LinearInstruction::InitializeFunctionPointer {
+                function,
+                from_scope,
+                outpu_reg,
+            } => {
+                self.u8(14);
+                self.string(&function.actual_func);
+                self.static_ref(&function.formals_list);
+                self.scope(from_scope);
+                self.register(outpu_reg);
+            }
+            LinearInstruction::Move { from_reg, to_reg } => {
+                self.u8(15);
+                self.register(from_reg);
+                self.register(to_reg);
+            }
+            LinearInstruction::Phi {
+                cond_name,
+                inputs,
+                output_reg,
+            } => {
+                self.u8(16);
+                self.string(cond_name);
+                self.len(inputs.len());
+                for (clause, input) in inputs {
+                    match clause {
+                        Some(clause) => {
+                            self.u8(1);
+                            self.len(*clause);
+                        }
+                        None => self.u8(0),
+                    }
+                    self.register(input);
+                }
+                self.register(output_reg);
+            }
+            LinearInstruction::SpillToSlot { from_reg, slot } => {
+                self.u8(17);
+                self.register(from_reg);
+                self.len(*slot);
+            }
+            LinearInstruction::ReloadFromSlot { slot, to_reg } => {
+                self.u8(18);
+                self.len(*slot);
+                self.register(to_reg);
+            }
+            LinearInstruction::TailCall {
+                function_pointer,
+                arguments,
+            } => {
+                self.u8(20);
+                self.register(function_pointer);
+                self.register(arguments);
+            }
+            LinearInstruction::MakeClosure {
+                function,
+                captures,
+                output_reg,
+            } => {
+                self.u8(21);
+                self.string(&function.actual_func);
+                self.static_ref(&function.formals_list);
+                self.len(captures.len());
+                for capture in captures {
+                    self.register(capture);
+                }
+                self.register(output_reg);
+            }
+            LinearInstruction::LoadCapture { index, to_reg } => {
+                self.u8(22);
+                self.len(*index);
+                self.register(to_reg);
+            }
+            LinearInstruction::LoadLocal {
+                depth,
+                slot,
+                to_reg,
+            } => {
+                self.u8(23);
+                self.len(*depth);
+                self.len(*slot);
+                self.register(to_reg);
+            }
+            LinearInstruction::StoreLocal {
+                depth,
+                slot,
+                from_reg,
+            } => {
+                self.u8(24);
+                self.len(*depth);
+                self.len(*slot);
+                self.register(from_reg);
+            }
+            LinearInstruction::Label { name } => {
+                self.u8(25);
+                self.string(name);
+            }
+            LinearInstruction::Jump { label } => {
+                self.u8(26);
+                self.string(label);
+            }
+            LinearInstruction::JumpIfFalse { condition, label } => {
+                self.u8(27);
+                self.register(condition);
+                self.string(label);
+            }
+            LinearInstruction::PrimOp {
+                op,
+                arguments,
+                output_reg,
+            } => {
+                self.u8(19);
+                let index = Builtin::ALL.iter().position(|builtin| builtin == op);
+                self.u8(index.expect("every builtin is in ALL") as u8);
+                self.len(arguments.len());
+                for argument in arguments {
+                    self.register(argument);
+                }
+                self.register(output_reg);
+            }
+        }
+    }
+    pub fn block(&mut self, block: &LinearBlock) {
+        self.string(&block.ident);
+        self.program(&block.program);
+    }
+    pub fn module(&mut self, module: &IrModule) {
+        self.string(&module.entry);
+        self.len(module.metadata.register_count);
+        self.len(module.metadata.lambda_count);
+        self.len(module.metadata.static_count);
+        self.len(module.metadata.cond_count);
+        self.len(module.blocks.len());
+        module.iter_blocks().for_each(|block| self.block(block));
+        self.static_table(&module.static_data);
+        self.source_map(&module.source_map);
+    }
+    fn span(&mut self, span: &Option<Span>) {
+        match span {
+            None => self.u8(0),
+            Some(span) => {
+                self.u8(1);
+                self.len(span.start);
+                self.len(span.end);
+                self.len(span.line);
+                self.len(span.column);
+            }
+        }
+    }
+    fn instruction_spans(&mut self, spans: &[InstructionSpan]) {
+        self.len(spans.len());
+        for instr in spans {
+            self.span(&instr.span);
+            self.instruction_spans(&instr.branch);
+        }
+    }
+    fn source_map(&mut self, map: &SourceMap) {
+        let mut blocks: Vec<_> = map.blocks.iter().collect();
+        blocks.sort_by(|a, b| a.0.cmp(b.0));
+        self.len(blocks.len());
+        for (ident, spans) in blocks {
+            self.string(ident);
+            self.span(&spans.span);
+            self.instruction_spans(&spans.instructions);
+        }
+        let mut statics: Vec<_> = map.statics.iter().collect();
+        statics.sort_by(|a, b| a.0.cmp(b.0));
+        self.len(statics.len());
+        for (refname, span) in statics {
+            self.string(refname);
+            self.span(&Some(*span));
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Decoder<'b> {
+    bytes: &'b [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'b> Decoder<'b> {
+    /// Checks magic, version and that the payload is of the `expected` kind
+    pub fn new(bytes: &'b [u8], expected: PayloadKind) -> Result<Decoder<'b>, DecodeError> {
+        let mut decoder = Decoder {
+            bytes,
+            pos: 0,
+            depth: 0,
+        };
+        if decoder.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(DecodeError::BadMagic);
+        }
+        let version = u16::from_le_bytes(decoder.array()?);
+        if version != VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let kind = decoder.u8()?;
+        if PayloadKind::from_byte(kind) != Some(expected) {
+            return Err(DecodeError::WrongPayload {
+                expected,
+                found: kind,
+            });
+        }
+        Ok(decoder)
+    }
+    /// Errors if anything is left over
+    pub fn finish(self) -> Result<(), DecodeError> {
+        if self.pos == self.bytes.len() {
+            Ok(())
+        } else {
+            Err(DecodeError::TrailingBytes { offset: self.pos })
+        }
+    }
+    fn take(&mut self, len: usize) -> Result<&'b [u8], DecodeError> {
+        let available = self.bytes.len() - self.pos;
+        if available < len {
+            return Err(DecodeError::Truncated {
+                offset: self.pos,
+                needed: len - available,
+            });
+        }
+        let slice = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+    fn len(&mut self) -> Result<usize, DecodeError> {
+        Ok(u32::from_le_bytes(self.array()?) as usize)
+    }
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let len = self.len()?;
+        let offset = self.pos;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidUtf8 { offset })
+    }
+    fn register(&mut self) -> Result<Register, DecodeError> {
+        Ok(Register {
+            virtual_ident: self.string()?,
+        })
+    }
+    fn scope(&mut self) -> Result<Scope, DecodeError> {
+        let offset = self.pos;
+        match self.u8()? {
+            0 => Ok(Scope::Global),
+            1 => Ok(Scope::Current),
+            2 => Ok(Scope::Custom(self.register()?)),
+            tag => Err(DecodeError::UnknownTag {
+                offset,
+                what: "scope",
+                tag,
+            }),
+        }
+    }
+    fn static_ref(&mut self) -> Result<StaticRef, DecodeError> {
+        Ok(StaticRef {
+            refname: self.string()?,
+            reftype: self.static_data()?,
+        })
+    }
+    fn nested<T>(
+        &mut self,
+        inner: impl FnOnce(&mut Self) -> Result<T, DecodeError>,
+    ) -> Result<T, DecodeError> {
+        if self.depth >= MAX_NESTING {
+            return Err(DecodeError::TooDeeplyNested { offset: self.pos });
+        }
+        self.depth += 1;
+        let res = inner(self);
+        self.depth -= 1;
+        res
+    }
+    pub fn static_data(&mut self) -> Result<StaticData, DecodeError> {
+        let offset = self.pos;
+        match self.u8()? {
+            0 => {
+                let offset = self.pos;
+                match self.u8()? {
+                    0 => Ok(StaticData::Bool(false)),
+                    1 => Ok(StaticData::Bool(true)),
+                    tag => Err(DecodeError::UnknownTag {
+                        offset,
+                        what: "bool",
+                        tag,
+                    }),
+                }
+            }
+            1 => Ok(StaticData::Integer(i32::from_le_bytes(self.array()?))),
+            2 => Ok(StaticData::String(self.string()?)),
+            3 => Ok(StaticData::Identifier(self.string()?)),
+            4 => {
+                let len = self.len()?;
+                self.nested(|decoder| {
+                    (0..len)
+                        .map(|_| decoder.static_data())
+                        .collect::<Result<_, _>>()
+                        .map(StaticData::List)
+                })
+            }
+            5 => Ok(StaticData::Void),
+            tag => Err(DecodeError::UnknownTag {
+                offset,
+                what: "static data",
+                tag,
+            }),
+        }
+    }
+    pub fn static_table(&mut self) -> Result<HashMap<String, StaticData>, DecodeError> {
+        let len = self.len()?;
+        let mut table = HashMap::new();
+        for _ in 0..len {
+            let refname = self.string()?;
+            table.insert(refname, self.static_data()?);
+        }
+        Ok(table)
+    }
+    fn program(&mut self) -> Result<Vec<LinearInstruction>, DecodeError> {
+        let len = self.len()?;
+        // No with_capacity, the length is untrusted
+        let mut program = vec![];
+        for _ in 0..len {
+            program.push(self.instruction()?);
+        }
+        Ok(program)
+    }
+    pub fn instruction(&mut self) -> Result<LinearInstruction, DecodeError> {
+        let offset = self.pos;
+        let instr = match self.u8()? {
+            0 => LinearInstruction::AcceptToFormals {
+                static_formals_list: self.static_ref()?,
+            },
+            1 => LinearInstruction::NewScopeAttachedToAndReplacingCurrent,
+            2 => LinearInstruction::PopScopeAndReplaceWithUpper,
+            3 => LinearInstruction::StaticRefToRegister {
+                static_ref: self.static_ref()?,
+                to_reg: self.register()?,
+            },
+            4 => LinearInstruction::PushToStack {
+                register: self.register()?,
+            },
+            5 => LinearInstruction::PopFromStack {
+                register: self.register()?,
+            },
+            6 => LinearInstruction::LinkedListInit {
+                output_reg: self.register()?,
+            },
+            7 => LinearInstruction::LinkedListAdd {
+                linked_list_reg: self.register()?,
+                input_reg: self.register()?,
+                output_reg: self.register()?,
+            },
+            8 => LinearInstruction::Assign {
+                identifier: self.static_ref()?,
+                from_reg: self.register()?,
+                scope: self.scope()?,
+            },
+            9 => LinearInstruction::Call {
+                output_reg: self.register()?,
+                function_pointer: self.register()?,
+                arguments: self.register()?,
+            },
+            10 => LinearInstruction::Lookup {
+                identifier: self.static_ref()?,
+                to_reg: self.register()?,
+                scope: self.scope()?,
+            },
+            11 => LinearInstruction::Cond {
+                cond_name: self.string()?,
+                condition: self.register()?,
+                branc_if_true: Branch {
+                    program: self.nested(|decoder| decoder.program())?,
+                },
+            },
+            12 => LinearInstruction::EndOfCond {
+                cond_name: self.string()?,
+            },
+            13 => LinearInstruction::Return {
+                value: self.register()?,
+            },
+            14 => LinearInstruction::InitializeFunctionPointer {
+                function: FunctionPointer {
+                    actual_func: self.string()?,
+                    formals_list: self.static_ref()?,
+                },
+                from_scope: self.scope()?,
+                outpu_reg: self.register()?,
+            },
+            15 => LinearInstruction::Move {
+                from_reg: self.register()?,
+                to_reg: self.register()?,
+            },
+            16 => {
+                let cond_name = self.string()?;
+                let len = self.len()?;
+                let mut inputs = vec![];
+                for _ in 0..len {
+                    let offset = self.pos;
+                    let clause = match self.u8()? {
+                        0 => None,
+                        1 => Some(self.len()?),
+                        tag => {
+                            return Err(DecodeError::UnknownTag {
+                                offset,
+                                what: "phi input",
+                                tag,
+                            })
+                        }
+                    };
+                    inputs.push((clause, self.register()?));
+                }
+                LinearInstruction::Phi {
+                    cond_name,
+                    inputs,
+                    output_reg: self.register()?,
+                }
+            }
+            17 => LinearInstruction::SpillToSlot {
+                from_reg: self.register()?,
+                slot: self.len()?,
+            },
+            18 => LinearInstruction::ReloadFromSlot {
+                slot: self.len()?,
+                to_reg: self.register()?,
+            },
+            19 => {
+                let offset = self.pos;
+                let tag = self.u8()?;
+                let op = *Builtin::ALL
+                    .get(tag as usize)
+                    .ok_or(DecodeError::UnknownTag {
+                        offset,
+                        what: "builtin",
+                        tag,
+                    })?;
+                let len = self.len()?;
+                let mut arguments = vec![];
+                for _ in 0..len {
+                    arguments.push(self.register()?);
+                }
+                LinearInstruction::PrimOp {
+                    op,
+                    arguments,
+                    output_reg: self.register()?,
+                }
+            }
+            20 => LinearInstruction::TailCall {
+                function_pointer: self.register()?,
+                arguments: self.register()?,
+            },
+            21 => {
+                let function = FunctionPointer {
+                    actual_func: self.string()?,
+                    formals_list: self.static_ref()?,
+                };
+                let len = self.len()?;
+                let mut captures = vec![];
+                for _ in 0..len {
+                    captures.push(self.register()?);
+                }
+                LinearInstruction::MakeClosure {
+                    function,
+                    captures,
+                    output_reg: self.register()?,
+                }
+            }
+            22 => LinearInstruction::LoadCapture {
+                index: self.len()?,
+                to_reg: self.register()?,
+            },
+            23 => LinearInstruction::LoadLocal {
+                depth: self.len()?,
+                slot: self.len()?,
+                to_reg: self.register()?,
+            },
+            24 => LinearInstruction::StoreLocal {
+                depth: self.len()?,
+                slot: self.len()?,
+                from_reg: self.register()?,
+            },
+            25 => LinearInstruction::Label {
+                name: self.string()?,
+            },
+            26 => LinearInstruction::Jump {
+                label: self.string()?,
+            },
+            27 => LinearInstruction::JumpIfFalse {
+                condition: self.register()?,
+                label: self.string()?,
+            },
+            opcode => return Err(DecodeError::UnknownOpcode { offset, opcode }),
+        };
+        Ok(instr)
+    }
+    pub fn block(&mut self) -> Result<LinearBlock, DecodeError> {
+        Ok(LinearBlock {
+            ident: self.string()?,
+            program: self.program()?,
+        })
+    }
+    pub fn module(&mut self) -> Result<IrModule, DecodeError> {
+        let entry = self.string()?;
+        let metadata = ModuleMetadata {
+            register_count: self.len()?,
+            lambda_count: self.len()?,
+            static_count: self.len()?,
+            cond_count: self.len()?,
+        };
+        let block_count = self.len()?;
+        let mut blocks = HashMap::new();
+        for _ in 0..block_count {
+            let block = self.block()?;
+            blocks.insert(block.ident.clone(), block);
+        }
+        Ok(IrModule {
+            entry,
+            blocks,
+            static_data: self.static_table()?,
+            metadata,
+            source_map: self.source_map()?,
+        })
+    }
+    fn span(&mut self) -> Result<Option<Span>, DecodeError> {
+        let offset = self.pos;
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(Span {
+                start: self.len()?,
+                end: self.len()?,
+                line: self.len()?,
+                column: self.len()?,
+            })),
+            tag => Err(DecodeError::UnknownTag {
+                offset,
+                what: "span",
+                tag,
+            }),
+        }
+    }
+    fn instruction_spans(&mut self) -> Result<Vec<InstructionSpan>, DecodeError> {
+        let len = self.len()?;
+        let mut spans = vec![];
+        for _ in 0..len {
+            let span = self.span()?;
+            let branch = self.nested(|decoder| decoder.instruction_spans())?;
+            spans.push(InstructionSpan { span, branch });
+        }
+        Ok(spans)
+    }
+    fn source_map(&mut self) -> Result<SourceMap, DecodeError> {
+        let mut map = SourceMap::default();
+        for _ in 0..self.len()? {
+            let ident = self.string()?;
+            let spans = BlockSpans {
+                span: self.span()?,
+                instructions: self.instruction_spans()?,
+            };
+            map.blocks.insert(ident, spans);
+        }
+        for _ in 0..self.len()? {
+            let refname = self.string()?;
+            let offset = self.pos;
+            let span = self.span()?.ok_or(DecodeError::UnknownTag {
+                offset,
+                what: "span",
+                tag: 0,
+            })?;
+            map.statics.insert(refname, span);
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_block, decode_module, encode_block, encode_module, DecodeError};
+    use crate::{asm::parse_program, module::IrModule, LinearBlock};
+
+    fn every_variant() -> LinearBlock {
+        let program = parse_program(
+            r#"
+            accept _0{(x |odd sym|)}
+            scope.new
+            load static0{(1 -2 #t #void "s" (nested))} -> vreg0
+            push vreg0
+            pop vreg1
+            list.init -> vreg2
+            list.add vreg2 <- vreg1 -> vreg2
+            assign static1{"x"} <- vreg1 @global
+            lookup static2{x} -> vreg3 @[vreg4]
+            call vreg3 vreg2 -> vreg5
+            prim <= vreg5 vreg1 -> vreg6
+            closure _0 _0{(x)} @current -> vreg4
+            closure.flat _0 _0{(x)} [vreg4 vreg5] -> vreg7
+            capture 1 -> vreg8
+            local.store 0 1 <- vreg8
+            local.load 2 1 -> vreg9
+            jump.false vreg9 cond2.0
+            jump cond2.end
+            label cond2.0
+            label cond2.end
+            cond0: if vreg5 {
+                cond1: if vreg5 {
+                    ret vreg5
+                }
+                cond1: end
+                tailcall vreg3 vreg2
+            }
+            cond0: end
+            scope.pop
+            ret vreg5
+            "#,
+        )
+        .unwrap();
+        LinearBlock {
+            ident: "_0".into(),
+            program,
+        }
+    }
+
+    #[test]
+    fn every_variant_round_trips() {
+        let block = every_variant();
+        assert_eq!(decode_block(&encode_block(&block)), Ok(block.clone()));
+
+        let module = IrModule {
+            entry: "_0".into(),
+            blocks: [(block.ident.clone(), block)].into_iter().collect(),
+            static_data: [("static0".to_string(), crate::StaticData::Integer(7))]
+                .into_iter()
+                .collect(),
+            metadata: Default::default(),
+            source_map: Default::default(),
+        };
+        assert_eq!(decode_module(&encode_module(&module)), Ok(module));
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let bytes = encode_block(&every_variant());
+        assert!(matches!(
+            decode_block(&bytes[..bytes.len() - 3]),
+            Err(DecodeError::Truncated { .. })
+        ));
+        assert_eq!(decode_block(b"nope"), Err(DecodeError::BadMagic));
+
+        let mut bad_version = bytes.clone();
+        bad_version[4] = 99;
+        assert!(matches!(
+            decode_block(&bad_version),
+            Err(DecodeError::UnsupportedVersion(_))
+        ));
+
+        // Encodings from before the PrimOp, TailCall, closure, slot and jump opcodes
+        let mut old_version = bytes.clone();
+        old_version[4..6].copy_from_slice(&2u16.to_le_bytes());
+        assert_eq!(
+            decode_block(&old_version),
+            Err(DecodeError::UnsupportedVersion(2))
+        );
+
+        // Header (7) + ident "_0" (4 + 2) + instruction count (4) puts the first opcode at 17
+        let mut bad_opcode = bytes;
+        bad_opcode[17] = 200;
+        assert_eq!(
+            decode_block(&bad_opcode),
+            Err(DecodeError::UnknownOpcode {
+                offset: 17,
+                opcode: 200
+            })
+        );
+    }
+}
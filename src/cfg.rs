@@ -0,0 +1,448 @@
+//! Control flow graph view of a `LinearBlock`.
+//! Cond branches become their own basic blocks with explicit edges so analyses do not have to
+//! recurse through nested `Branch` programs. `Cfg::to_linear` turns the graph back into the
+//! nested form, code after a Return or TailCall that nothing can reach is dropped on the way.
+
+use std::{collections::HashMap, fmt};
+
+use crate::{Branch, LinearBlock, LinearInstruction, Register};
+
+pub type BlockId = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EdgeKind {
+    /// Condition held, into the clause's branch
+    BranchTaken,
+    /// Condition did not hold, on to the next clause
+    NextClause,
+    /// End of a taken branch, on to the EndOfCond of its group
+    Join,
+    /// Straight line continuation, e.g. the last clause falling into its EndOfCond
+    FallThrough,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Terminator {
+    Jump {
+        target: BlockId,
+        kind: EdgeKind,
+    },
+    /// A Cond instruction, `join` is the block starting at the EndOfCond of `cond_name`
+    Branch {
+        cond_name: String,
+        condition: Register,
+        taken: BlockId,
+        not_taken: BlockId,
+        join: BlockId,
+    },
+    Return(Register),
+    TailCall {
+        function_pointer: Register,
+        arguments: Register,
+    },
+    /// Fell off the end of the program, what main does
+    Exit,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub id: BlockId,
+    /// Set when the block starts at the EndOfCond of that cond group
+    pub join: Option<String>,
+    /// Never contains Cond or EndOfCond, those are the terminator and `join`
+    pub instructions: Vec<LinearInstruction>,
+    pub terminator: Terminator,
+    pub predecessors: Vec<BlockId>,
+}
+
+impl BasicBlock {
+    fn new(id: BlockId) -> BasicBlock {
+        BasicBlock {
+            id,
+            join: None,
+            instructions: vec![],
+            terminator: Terminator::Exit,
+            predecessors: vec![],
+        }
+    }
+    pub fn successors(&self) -> Vec<(BlockId, EdgeKind)> {
+        match &self.terminator {
+            Terminator::Jump { target, kind } => vec![(*target, *kind)],
+            Terminator::Branch {
+                taken, not_taken, ..
+            } => vec![
+                (*taken, EdgeKind::BranchTaken),
+                (*not_taken, EdgeKind::NextClause),
+            ],
+            Terminator::Return(_) | Terminator::TailCall { .. } | Terminator::Exit => vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CfgError {
+    CondWithoutEnd(String),
+    EndWithoutCond(String),
+}
+
+impl fmt::Display for CfgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CfgError::CondWithoutEnd(name) => write!(f, "`{}` has no EndOfCond", name),
+            CfgError::EndWithoutCond(name) => write!(f, "EndOfCond for `{}` without a Cond", name),
+        }
+    }
+}
+
+impl std::error::Error for CfgError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cfg {
+    /// Ident of the LinearBlock this came from
+    pub ident: String,
+    pub entry: BlockId,
+    /// Indexed by BlockId
+    pub blocks: Vec<BasicBlock>,
+}
+
+impl Cfg {
+    pub fn from_block(block: &LinearBlock) -> Result<Cfg, CfgError> {
+        let mut cfg = Cfg {
+            ident: block.ident.clone(),
+            entry: 0,
+            blocks: vec![BasicBlock::new(0)],
+        };
+        if let Some(open) = cfg.lower(&block.program, 0)? {
+            cfg.blocks[open].terminator = Terminator::Exit;
+        }
+        for id in 0..cfg.blocks.len() {
+            for (succ, _) in cfg.blocks[id].successors() {
+                cfg.blocks[succ].predecessors.push(id);
+            }
+        }
+        Ok(cfg)
+    }
+    pub fn block(&self, id: BlockId) -> &BasicBlock {
+        &self.blocks[id]
+    }
+    pub fn successors(&self, id: BlockId) -> Vec<(BlockId, EdgeKind)> {
+        self.blocks[id].successors()
+    }
+    pub fn predecessors(&self, id: BlockId) -> &[BlockId] {
+        &self.blocks[id].predecessors
+    }
+    /// Blocks ending in Return, TailCall or falling off the end
+    pub fn exits(&self) -> Vec<BlockId> {
+        self.blocks
+            .iter()
+            .filter(|block| block.successors().is_empty())
+            .map(|block| block.id)
+            .collect()
+    }
+    /// Reachable blocks, every block before its successors except along back edges (there are none yet)
+    pub fn reverse_postorder(&self) -> Vec<BlockId> {
+        let mut visited = vec![false; self.blocks.len()];
+        let mut postorder = vec![];
+        // Iterative dfs, the bool says whether the children were already pushed
+        let mut stack = vec![(self.entry, false)];
+        while let Some((id, expanded)) = stack.pop() {
+            if expanded {
+                postorder.push(id);
+                continue;
+            }
+            if visited[id] {
+                continue;
+            }
+            visited[id] = true;
+            stack.push((id, true));
+            for (succ, _) in self.successors(id).into_iter().rev() {
+                if !visited[succ] {
+                    stack.push((succ, false));
+                }
+            }
+        }
+        postorder.reverse();
+        postorder
+    }
+    fn new_block(&mut self) -> BlockId {
+        let id = self.blocks.len();
+        self.blocks.push(BasicBlock::new(id));
+        id
+    }
+    /// Appends `program` starting in `current`, returns the block left open at the end if any
+    fn lower(
+        &mut self,
+        program: &[LinearInstruction],
+        current: BlockId,
+    ) -> Result<Option<BlockId>, CfgError> {
+        let mut current = Some(current);
+        // Join block of every cond group opened at this level
+        let mut joins: HashMap<&str, BlockId> = HashMap::new();
+        for instr in program {
+            // Code after a Return still gets a block, it just has no predecessors
+            let open = match current {
+                Some(open) => open,
+                None => self.new_block(),
+            };
+            current = match instr {
+                LinearInstruction::Cond {
+                    cond_name,
+                    condition,
+                    branc_if_true,
+                } => {
+                    let join = match joins.get(cond_name.as_str()) {
+                        Some(join) => *join,
+                        None => {
+                            let join = self.new_block();
+                            self.blocks[join].join = Some(cond_name.clone());
+                            joins.insert(cond_name, join);
+                            join
+                        }
+                    };
+                    let taken = self.new_block();
+                    let not_taken = self.new_block();
+                    self.blocks[open].terminator = Terminator::Branch {
+                        cond_name: cond_name.clone(),
+                        condition: condition.clone(),
+                        taken,
+                        not_taken,
+                        join,
+                    };
+                    if let Some(branch_end) = self.lower(&branc_if_true.program, taken)? {
+                        self.blocks[branch_end].terminator = Terminator::Jump {
+                            target: join,
+                            kind: EdgeKind::Join,
+                        };
+                    }
+                    Some(not_taken)
+                }
+                LinearInstruction::EndOfCond { cond_name } => {
+                    let join = joins
+                        .remove(cond_name.as_str())
+                        .ok_or_else(|| CfgError::EndWithoutCond(cond_name.clone()))?;
+                    self.blocks[open].terminator = Terminator::Jump {
+                        target: join,
+                        kind: EdgeKind::FallThrough,
+                    };
+                    Some(join)
+                }
+                LinearInstruction::Return { value } => {
+                    self.blocks[open].terminator = Terminator::Return(value.clone());
+                    None
+                }
+                LinearInstruction::TailCall {
+                    function_pointer,
+                    arguments,
+                } => {
+                    self.blocks[open].terminator = Terminator::TailCall {
+                        function_pointer: function_pointer.clone(),
+                        arguments: arguments.clone(),
+                    };
+                    None
+                }
+                other => {
+                    self.blocks[open].instructions.push(other.clone());
+                    Some(open)
+                }
+            };
+        }
+        if let Some(name) = joins.keys().next() {
+            return Err(CfgError::CondWithoutEnd(name.to_string()));
+        }
+        Ok(current)
+    }
+    pub fn to_linear(&self) -> LinearBlock {
+        let mut program = vec![];
+        self.linearize(self.entry, None, &mut program);
+        LinearBlock {
+            ident: self.ident.clone(),
+            program,
+        }
+    }
+    /// Follows the graph from `start` until `stop` (the join of the branch being rebuilt)
+    fn linearize(&self, start: BlockId, stop: Option<BlockId>, out: &mut Vec<LinearInstruction>) {
+        let mut current = start;
+        // Joins of conds opened here that no path has fallen into yet, an else ending in a
+        // Return or TailCall never jumps to its join but the code after it still has to come
+        let mut unjoined: Vec<BlockId> = vec![];
+        loop {
+            let block = &self.blocks[current];
+            out.extend(block.instructions.iter().cloned());
+            match &block.terminator {
+                Terminator::Jump { target, .. } => {
+                    if Some(*target) == stop {
+                        return;
+                    }
+                    unjoined.retain(|join| join != target);
+                    current = *target;
+                    self.end_of_cond(current, out);
+                    continue;
+                }
+                Terminator::Branch {
+                    cond_name,
+                    condition,
+                    taken,
+                    not_taken,
+                    join,
+                } => {
+                    let mut branch = vec![];
+                    self.linearize(*taken, Some(*join), &mut branch);
+                    out.push(LinearInstruction::Cond {
+                        cond_name: cond_name.clone(),
+                        condition: condition.clone(),
+                        branc_if_true: Branch { program: branch },
+                    });
+                    if !unjoined.contains(join) {
+                        unjoined.push(*join);
+                    }
+                    current = *not_taken;
+                    continue;
+                }
+                Terminator::Return(value) => {
+                    out.push(LinearInstruction::Return {
+                        value: value.clone(),
+                    });
+                }
+                Terminator::TailCall {
+                    function_pointer,
+                    arguments,
+                } => {
+                    out.push(LinearInstruction::TailCall {
+                        function_pointer: function_pointer.clone(),
+                        arguments: arguments.clone(),
+                    });
+                }
+                Terminator::Exit => {}
+            }
+            match unjoined.pop() {
+                Some(join) => {
+                    current = join;
+                    self.end_of_cond(current, out);
+                }
+                None => return,
+            }
+        }
+    }
+    fn end_of_cond(&self, id: BlockId, out: &mut Vec<LinearInstruction>) {
+        if let Some(cond_name) = &self.blocks[id].join {
+            out.push(LinearInstruction::EndOfCond {
+                cond_name: cond_name.clone(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use little_parser::{Expression, Programm};
+
+    use super::{Cfg, EdgeKind, Terminator};
+    use crate::{
+        asm::parse_program,
+        test_ast::{boolean, call, ident, int, lambda},
+        value::Value,
+        verify::verify_module,
+        vm::Vm,
+        LinearBlock, Register, Translator,
+    };
+
+    #[test]
+    fn edges_of_a_cond() {
+        let block = LinearBlock {
+            ident: "test".into(),
+            program: parse_program(
+                "load static0{#t} -> vreg0
+                 cond0: if vreg0 {
+                     ret vreg0
+                 }
+                 cond0: if vreg0 {
+                     push vreg0
+                 }
+                 cond0: end
+                 pop vreg1",
+            )
+            .unwrap(),
+        };
+        let cfg = Cfg::from_block(&block).unwrap();
+
+        let Terminator::Branch {
+            taken,
+            not_taken,
+            join,
+            ..
+        } = cfg.block(cfg.entry).terminator.clone()
+        else {
+            panic!("entry should end in the first clause");
+        };
+        assert_eq!(
+            cfg.block(taken).terminator,
+            Terminator::Return(Register {
+                virtual_ident: "vreg0".into()
+            })
+        );
+        let Terminator::Branch {
+            taken: second_taken,
+            not_taken: second_not_taken,
+            ..
+        } = cfg.block(not_taken).terminator.clone()
+        else {
+            panic!("second clause");
+        };
+        assert_eq!(cfg.successors(second_taken), vec![(join, EdgeKind::Join)]);
+        assert_eq!(
+            cfg.successors(second_not_taken),
+            vec![(join, EdgeKind::FallThrough)]
+        );
+        let mut preds = cfg.predecessors(join).to_vec();
+        preds.sort();
+        assert_eq!(preds, vec![second_taken, second_not_taken]);
+        assert_eq!(cfg.block(join).join.as_deref(), Some("cond0"));
+        assert_eq!(cfg.reverse_postorder()[0], cfg.entry);
+        assert_eq!(cfg.to_linear(), block);
+    }
+
+    #[test]
+    fn translated_lambdas_round_trip() {
+        let program = Programm::Expression(vec![lambda(
+            &["n"],
+            vec![Expression::Cond(vec![
+                (ident("n"), int(1)),
+                (boolean(true), call(ident("f"), vec![ident("n")])),
+            ])],
+        )]);
+        let module = Translator::default().translate_module(program).unwrap();
+        for block in module.iter_blocks() {
+            assert_eq!(&Cfg::from_block(block).unwrap().to_linear(), block);
+        }
+
+        // The else is a tail call so nothing falls into the join
+        // (define g (lambda (n) n)) (define f (lambda (n) (cond ((= n 0) 1) (else (g n))))) (f 0)
+        let program = Programm::Expression(vec![
+            Expression::Define("g".into(), Rc::new(lambda(&["n"], vec![ident("n")]))),
+            Expression::Define(
+                "f".into(),
+                Rc::new(lambda(
+                    &["n"],
+                    vec![Expression::Cond(vec![
+                        (call(ident("="), vec![ident("n"), int(0)]), int(1)),
+                        (ident("else"), call(ident("g"), vec![ident("n")])),
+                    ])],
+                )),
+            ),
+            call(ident("f"), vec![int(0)]),
+        ]);
+        let mut module = Translator::default().translate_module(program).unwrap();
+        for block in module.blocks.values_mut() {
+            let linear = Cfg::from_block(block).unwrap().to_linear();
+            assert_eq!(&linear, block);
+            *block = linear;
+        }
+        assert_eq!(verify_module(&module), Ok(()));
+        assert_eq!(
+            Vm::new(&module.blocks).run("main").unwrap(),
+            Value::Integer(1)
+        );
+    }
+}
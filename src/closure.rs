@@ -0,0 +1,701 @@
+//! Closure conversion.
+//! A closure made by InitializeFunctionPointer keeps the whole scope chain it was made in and
+//! reads variables through it. `convert_closures` works out which variables each lambda needs
+//! from enclosing lambdas and lets, hands just those to a MakeClosure and turns their loads in
+//! the lambda into LoadCaptures. Names that resolve to the global scope stay lookups.
+//!
+//! Values are copied when the closure is made. A lambda needing a variable its parent only sets
+//! after making it, like an internal define calling a later one, keeps its scope chain.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{module::IrModule, LinearInstruction, Register, Scope, StaticData, StaticRef};
+
+/// Something a lambda reads from outside itself
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Var {
+    Named(String),
+    /// LoadLocal past the scopes of the block, `depth` counts from the scope the closure is made in
+    Local {
+        depth: usize,
+        slot: usize,
+    },
+}
+
+/// Where a closure of a lambda is made
+struct Site {
+    parent: String,
+    /// Textual position of the InitializeFunctionPointer in the parent
+    position: usize,
+    /// Names the parent has bound at that point, not counting the global scope
+    bound: HashSet<String>,
+    /// Ids of the parent scopes open at that point, innermost last, not counting the global scope
+    open: Vec<usize>,
+}
+
+impl Site {
+    /// `var` of a closure made here as seen by the parent, None if the parent binds it
+    fn outside(&self, var: &Var) -> Option<Var> {
+        match var {
+            Var::Named(name) if self.bound.contains(name) => None,
+            Var::Named(_) => Some(var.clone()),
+            Var::Local { depth, slot } => Some(Var::Local {
+                depth: depth.checked_sub(self.open.len())?,
+                slot: *slot,
+            }),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Scan {
+    /// Variables read before the block binds them
+    free: HashSet<Var>,
+    /// Textual positions of Assigns to scopes of the block, global ones do not count
+    assigns: HashMap<String, Vec<usize>>,
+    /// Textual positions of stores to each scope id and slot, formals included
+    stores: HashMap<(usize, usize), Vec<usize>>,
+    /// Names bound in a Cond branch, after the cond we do not know if they are
+    maybe_bound: HashSet<String>,
+    /// Slots stored to in a Cond branch
+    maybe_stored: HashSet<(usize, usize)>,
+    /// Uses custom scopes, looks up maybe bound names or was converted already
+    opaque: bool,
+    children: Vec<String>,
+}
+
+struct Analysis {
+    entry: String,
+    scans: HashMap<String, Scan>,
+    sites: HashMap<String, Vec<Site>>,
+    /// Lambdas made without their current scope, these are never converted
+    pinned: HashSet<String>,
+    /// A static to look each name up by
+    refs: HashMap<String, StaticRef>,
+    raw_free: HashMap<String, HashSet<Var>>,
+    convertible: HashMap<String, bool>,
+}
+
+/// Converts every lambda it can, returns how many
+pub fn convert_closures(module: &mut IrModule) -> usize {
+    let mut analysis = Analysis {
+        entry: module.entry.clone(),
+        scans: HashMap::new(),
+        sites: HashMap::new(),
+        pinned: HashSet::new(),
+        refs: HashMap::new(),
+        raw_free: HashMap::new(),
+        convertible: HashMap::new(),
+    };
+    for block in module.blocks.values() {
+        let mut walker = Walker {
+            analysis: &mut analysis,
+            scan: Scan::default(),
+            ident: &block.ident,
+            global_base: block.ident == module.entry,
+            position: 0,
+            next_scope: 1,
+            in_branch: false,
+        };
+        walker.walk(&block.program, &mut vec![(0, HashSet::new())]);
+        let scan = walker.scan;
+        analysis.scans.insert(block.ident.clone(), scan);
+    }
+
+    let mut idents: Vec<String> = module.blocks.keys().cloned().collect();
+    idents.sort();
+    let mut converted = vec![];
+    for ident in idents {
+        if analysis.is_convertible(&ident) {
+            let captures = analysis.captures(&ident);
+            converted.push((ident, captures));
+        }
+    }
+
+    let mut register_count = module.metadata.register_count;
+    for (ident, captures) in &converted {
+        let parent = &analysis.sites[ident][0].parent;
+        let Some(block) = module.blocks.get_mut(parent) else {
+            continue;
+        };
+        let program = std::mem::take(&mut block.program);
+        block.program = make_flat(
+            program,
+            ident,
+            captures,
+            &analysis.refs,
+            &mut register_count,
+        );
+    }
+    module.metadata.register_count = register_count;
+    for (ident, captures) in &converted {
+        if let Some(block) = module.blocks.get_mut(ident) {
+            load_captures(&mut block.program, &mut vec![HashSet::new()], captures);
+        }
+    }
+    converted.len()
+}
+
+/// Scope id and the names bound in it
+type ScanScope = (usize, HashSet<String>);
+
+struct Walker<'a> {
+    analysis: &'a mut Analysis,
+    scan: Scan,
+    ident: &'a str,
+    /// The outermost scope of the entry block is the global one
+    global_base: bool,
+    position: usize,
+    next_scope: usize,
+    in_branch: bool,
+}
+
+impl Walker<'_> {
+    /// The scopes of the block, without the global one
+    fn locals<'s>(&self, scopes: &'s [ScanScope]) -> &'s [ScanScope] {
+        &scopes[usize::from(self.global_base).min(scopes.len())..]
+    }
+    fn bound(&self, scopes: &[ScanScope]) -> HashSet<String> {
+        self.locals(scopes)
+            .iter()
+            .flat_map(|(_, names)| names)
+            .cloned()
+            .collect()
+    }
+    fn store(&mut self, scope: usize, slot: usize, position: usize) {
+        self.scan
+            .stores
+            .entry((scope, slot))
+            .or_default()
+            .push(position);
+        if self.in_branch {
+            self.scan.maybe_stored.insert((scope, slot));
+        }
+    }
+    fn walk(&mut self, program: &[LinearInstruction], scopes: &mut Vec<ScanScope>) {
+        for instr in program {
+            let position = self.position;
+            self.position += 1;
+            match instr {
+                LinearInstruction::AcceptToFormals {
+                    static_formals_list,
+                } => {
+                    if let StaticData::List(formals) = &static_formals_list.reftype {
+                        let (id, top) = scopes.last_mut().expect("there is always a scope");
+                        let id = *id;
+                        top.extend(
+                            formals
+                                .iter()
+                                .filter_map(StaticData::as_name)
+                                .map(str::to_string),
+                        );
+                        for slot in 0..formals.len() {
+                            self.store(id, slot, position);
+                        }
+                    }
+                }
+                LinearInstruction::NewScopeAttachedToAndReplacingCurrent => {
+                    scopes.push((self.next_scope, HashSet::new()));
+                    self.next_scope += 1;
+                }
+                LinearInstruction::PopScopeAndReplaceWithUpper => {
+                    if scopes.len() > 1 {
+                        scopes.pop();
+                    } else {
+                        self.scan.opaque = true;
+                    }
+                }
+                LinearInstruction::Assign {
+                    identifier, scope, ..
+                } => match (scope, identifier.reftype.as_name()) {
+                    (Scope::Current, Some(ident)) => {
+                        if !(self.global_base && scopes.len() == 1) {
+                            self.scan
+                                .assigns
+                                .entry(ident.to_string())
+                                .or_default()
+                                .push(position);
+                        }
+                        let (_, top) = scopes.last_mut().expect("there is always a scope");
+                        top.insert(ident.to_string());
+                    }
+                    (Scope::Global, _) => {}
+                    _ => self.scan.opaque = true,
+                },
+                LinearInstruction::Lookup {
+                    identifier, scope, ..
+                } => match (scope, identifier.reftype.as_name()) {
+                    (Scope::Current, Some(ident)) => {
+                        self.analysis
+                            .refs
+                            .entry(ident.to_string())
+                            .or_insert_with(|| identifier.clone());
+                        if self.bound(scopes).contains(ident) {
+                            continue;
+                        }
+                        if self.scan.maybe_bound.contains(ident) {
+                            self.scan.opaque = true;
+                        }
+                        self.scan.free.insert(Var::Named(ident.to_string()));
+                    }
+                    (Scope::Global, _) => {}
+                    _ => self.scan.opaque = true,
+                },
+                LinearInstruction::LoadLocal { depth, slot, .. } => {
+                    let open = self.locals(scopes).len();
+                    if *depth >= open {
+                        self.scan.free.insert(Var::Local {
+                            depth: depth - open,
+                            slot: *slot,
+                        });
+                    }
+                }
+                LinearInstruction::StoreLocal { depth, slot, .. } => {
+                    let locals = self.locals(scopes);
+                    match locals.len().checked_sub(depth + 1) {
+                        Some(index) => {
+                            let id = locals[index].0;
+                            self.store(id, *slot, position);
+                        }
+                        // Writes into a scope it does not own
+                        None => self.scan.opaque = true,
+                    }
+                }
+                LinearInstruction::InitializeFunctionPointer {
+                    function,
+                    from_scope,
+                    ..
+                } => {
+                    if *from_scope != Scope::Current {
+                        self.analysis.pinned.insert(function.actual_func.clone());
+                    }
+                    let site = Site {
+                        parent: self.ident.to_string(),
+                        position,
+                        bound: self.bound(scopes),
+                        open: self.locals(scopes).iter().map(|(id, _)| *id).collect(),
+                    };
+                    self.analysis
+                        .sites
+                        .entry(function.actual_func.clone())
+                        .or_default()
+                        .push(site);
+                    self.scan.children.push(function.actual_func.clone());
+                }
+                // Converted already, do not touch it again
+                LinearInstruction::MakeClosure { function, .. } => {
+                    self.analysis.pinned.insert(function.actual_func.clone());
+                }
+                LinearInstruction::LoadCapture { .. } => self.scan.opaque = true,
+                LinearInstruction::Cond { branc_if_true, .. } => {
+                    let mut branch = scopes.clone();
+                    let in_branch = std::mem::replace(&mut self.in_branch, true);
+                    self.walk(&branc_if_true.program, &mut branch);
+                    self.in_branch = in_branch;
+                    for ((_, before), (_, after)) in scopes.iter().zip(&branch) {
+                        self.scan
+                            .maybe_bound
+                            .extend(after.difference(before).cloned());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Analysis {
+    fn site(&self, ident: &str) -> Option<&Site> {
+        match self.sites.get(ident).map(Vec::as_slice) {
+            Some([site]) => Some(site),
+            _ => None,
+        }
+    }
+    /// Variables the block or closures made in it need from outside the block
+    fn raw_free(&mut self, ident: &str) -> HashSet<Var> {
+        if let Some(free) = self.raw_free.get(ident) {
+            return free.clone();
+        }
+        // Guards against cycles, a block only ever makes closures of blocks nested in it
+        self.raw_free.insert(ident.to_string(), HashSet::new());
+        let Some(scan) = self.scans.get(ident) else {
+            return HashSet::new();
+        };
+        let mut free = scan.free.clone();
+        for child in scan.children.clone() {
+            let child_free = self.raw_free(&child);
+            match self.site(&child) {
+                Some(site) => free.extend(child_free.iter().filter_map(|var| site.outside(var))),
+                None => free.extend(child_free),
+            }
+        }
+        self.raw_free.insert(ident.to_string(), free.clone());
+        free
+    }
+    /// Whether `var` read by a closure made at `site` is bound by the parent or an enclosing
+    /// block, also if that only happens later
+    fn resolves(&self, site: &Site, var: &Var) -> bool {
+        let Some(outside) = site.outside(var) else {
+            return true;
+        };
+        if let Var::Named(name) = &outside {
+            if self.scans[&site.parent].assigns.contains_key(name) {
+                return true;
+            }
+        }
+        if site.parent == self.entry {
+            return false;
+        }
+        match self.site(&site.parent) {
+            Some(up) => self.resolves(up, &outside),
+            None => false,
+        }
+    }
+    /// Captured variables in LoadCapture index order
+    fn captures(&mut self, ident: &str) -> Vec<Var> {
+        let mut captures: Vec<Var> = self
+            .raw_free(ident)
+            .into_iter()
+            .filter(|var| {
+                self.site(ident)
+                    .is_some_and(|site| self.resolves(site, var))
+            })
+            .collect();
+        captures.sort();
+        captures
+    }
+    fn is_convertible(&mut self, ident: &str) -> bool {
+        if let Some(convertible) = self.convertible.get(ident) {
+            return *convertible;
+        }
+        self.convertible.insert(ident.to_string(), false);
+        let convertible = self.check(ident);
+        self.convertible.insert(ident.to_string(), convertible);
+        convertible
+    }
+    fn check(&mut self, ident: &str) -> bool {
+        if ident == self.entry || self.pinned.contains(ident) {
+            return false;
+        }
+        let Some(site) = self.site(ident) else {
+            return false;
+        };
+        let parent = site.parent.clone();
+        let (Some(scan), Some(parent_scan)) = (self.scans.get(ident), self.scans.get(&parent))
+        else {
+            return false;
+        };
+        if scan.opaque || parent_scan.opaque {
+            return false;
+        }
+        let captures = self.captures(ident);
+        let site = self.site(ident).expect("checked above");
+        let parent_scan = &self.scans[&parent];
+        // Bound only after the closure is made or bound again afterwards
+        let changes_later = captures.iter().any(|var| match var {
+            Var::Named(name) => {
+                parent_scan.maybe_bound.contains(name)
+                    || parent_scan.assigns.get(name).is_some_and(|assigns| {
+                        !site.bound.contains(name)
+                            || assigns.iter().any(|assign| *assign > site.position)
+                    })
+            }
+            Var::Local { depth, slot } => {
+                let Some(index) = site.open.len().checked_sub(depth + 1) else {
+                    return false;
+                };
+                let key = (site.open[index], *slot);
+                parent_scan.maybe_stored.contains(&key)
+                    || parent_scan
+                        .stores
+                        .get(&key)
+                        .is_none_or(|stores| stores.iter().any(|store| *store > site.position))
+            }
+        });
+        if changes_later {
+            return false;
+        }
+        // Children keeping their scope chain must not need anything only our captures have,
+        // and cannot reach past us at all since our scope no longer leads anywhere
+        for child in self.scans[ident].children.clone() {
+            if self.is_convertible(&child) {
+                continue;
+            }
+            let mut needed = self.raw_free(&child);
+            if let Some(site) = self.site(&child) {
+                needed = needed.iter().filter_map(|var| site.outside(var)).collect();
+            }
+            if needed
+                .iter()
+                .any(|var| matches!(var, Var::Local { .. }) || captures.contains(var))
+            {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Replaces the InitializeFunctionPointer of `ident` with loads of its captures and a
+/// MakeClosure
+fn make_flat(
+    program: Vec<LinearInstruction>,
+    ident: &str,
+    captures: &[Var],
+    refs: &HashMap<String, StaticRef>,
+    register_count: &mut usize,
+) -> Vec<LinearInstruction> {
+    let mut out = vec![];
+    for instr in program {
+        match instr {
+            LinearInstruction::InitializeFunctionPointer {
+                function,
+                outpu_reg,
+                ..
+            } if function.actual_func == ident => {
+                let mut registers = vec![];
+                for var in captures {
+                    let register = Register {
+                        virtual_ident: format!("vreg{}", register_count),
+                    };
+                    *register_count += 1;
+                    out.push(match var {
+                        Var::Named(name) => LinearInstruction::Lookup {
+                            identifier: refs[name].clone(),
+                            to_reg: register.clone(),
+                            scope: Scope::Current,
+                        },
+                        // Depths count from the scope the closure is made in, which is this one
+                        Var::Local { depth, slot } => LinearInstruction::LoadLocal {
+                            depth: *depth,
+                            slot: *slot,
+                            to_reg: register.clone(),
+                        },
+                    });
+                    registers.push(register);
+                }
+                out.push(LinearInstruction::MakeClosure {
+                    function,
+                    captures: registers,
+                    output_reg: outpu_reg,
+                });
+            }
+            LinearInstruction::Cond {
+                cond_name,
+                condition,
+                mut branc_if_true,
+            } => {
+                branc_if_true.program =
+                    make_flat(branc_if_true.program, ident, captures, refs, register_count);
+                out.push(LinearInstruction::Cond {
+                    cond_name,
+                    condition,
+                    branc_if_true,
+                });
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// Turns reads of captured variables the lambda has not bound itself into LoadCaptures
+fn load_captures(
+    program: &mut [LinearInstruction],
+    scopes: &mut Vec<HashSet<String>>,
+    captures: &[Var],
+) {
+    for instr in program {
+        let (var, to_reg) = match instr {
+            LinearInstruction::AcceptToFormals {
+                static_formals_list,
+            } => {
+                if let StaticData::List(formals) = &static_formals_list.reftype {
+                    let top = scopes.last_mut().expect("there is always a scope");
+                    top.extend(
+                        formals
+                            .iter()
+                            .filter_map(StaticData::as_name)
+                            .map(str::to_string),
+                    );
+                }
+                continue;
+            }
+            LinearInstruction::NewScopeAttachedToAndReplacingCurrent => {
+                scopes.push(HashSet::new());
+                continue;
+            }
+            LinearInstruction::PopScopeAndReplaceWithUpper => {
+                scopes.pop();
+                continue;
+            }
+            LinearInstruction::Assign { identifier, .. } => {
+                if let (Some(top), Some(ident)) = (scopes.last_mut(), identifier.reftype.as_name())
+                {
+                    top.insert(ident.to_string());
+                }
+                continue;
+            }
+            LinearInstruction::Lookup {
+                identifier,
+                to_reg,
+                scope: Scope::Current,
+            } => {
+                let Some(ident) = identifier.reftype.as_name() else {
+                    continue;
+                };
+                if scopes.iter().any(|scope| scope.contains(ident)) {
+                    continue;
+                }
+                (Var::Named(ident.to_string()), to_reg.clone())
+            }
+            LinearInstruction::LoadLocal {
+                depth,
+                slot,
+                to_reg,
+            } => match depth.checked_sub(scopes.len()) {
+                Some(depth) => (Var::Local { depth, slot: *slot }, to_reg.clone()),
+                None => continue,
+            },
+            LinearInstruction::Cond { branc_if_true, .. } => {
+                load_captures(&mut branc_if_true.program, &mut scopes.clone(), captures);
+                continue;
+            }
+            _ => continue,
+        };
+        if let Some(index) = captures.iter().position(|capture| *capture == var) {
+            *instr = LinearInstruction::LoadCapture { index, to_reg };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use little_parser::{Expression, Programm};
+
+    use super::convert_closures;
+    use crate::{
+        module::IrModule,
+        test_ast::{call, ident, int, lambda},
+        value::Value,
+        verify::verify_module,
+        vm::Vm,
+        LinearInstruction, Translator,
+    };
+
+    fn translate(exprs: Vec<Expression>) -> IrModule {
+        Translator::default()
+            .translate_module(Programm::Expression(exprs))
+            .unwrap()
+    }
+    fn captures(module: &IrModule) -> Vec<(String, usize)> {
+        fn walk(program: &[LinearInstruction], out: &mut Vec<(String, usize)>) {
+            for instr in program {
+                match instr {
+                    LinearInstruction::MakeClosure {
+                        function, captures, ..
+                    } => out.push((function.actual_func.clone(), captures.len())),
+                    LinearInstruction::Cond { branc_if_true, .. } => {
+                        walk(&branc_if_true.program, out)
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let mut out = vec![];
+        for block in module.iter_blocks() {
+            walk(&block.program, &mut out);
+        }
+        out.sort();
+        out
+    }
+
+    #[test]
+    fn captures_only_what_is_used() {
+        // (define k 10)
+        // (list ((let ((x 1) (y 2)) (lambda (z) (+ (+ x z) k))) 5)
+        //       (((lambda (a) (lambda (b) (lambda (c) (+ a (+ b c))))) 1) 2) 3))
+        let adder = Expression::Let(
+            vec![("x".into(), int(1)), ("y".into(), int(2))],
+            vec![lambda(
+                &["z"],
+                vec![call(
+                    ident("+"),
+                    vec![call(ident("+"), vec![ident("x"), ident("z")]), ident("k")],
+                )],
+            )],
+        );
+        let curried = lambda(
+            &["a"],
+            vec![lambda(
+                &["b"],
+                vec![lambda(
+                    &["c"],
+                    vec![call(
+                        ident("+"),
+                        vec![ident("a"), call(ident("+"), vec![ident("b"), ident("c")])],
+                    )],
+                )],
+            )],
+        );
+        let mut module = translate(vec![
+            Expression::Define("k".into(), Rc::new(int(10))),
+            call(
+                ident("list"),
+                vec![
+                    call(adder, vec![int(5)]),
+                    call(
+                        call(call(curried, vec![int(1)]), vec![int(2)]),
+                        vec![int(3)],
+                    ),
+                ],
+            ),
+        ]);
+        assert_eq!(convert_closures(&mut module), 4);
+        // k is global and y unused, the innermost lambda needs a and b
+        assert_eq!(
+            captures(&module),
+            [
+                ("_0".to_string(), 1),
+                ("_1".to_string(), 0),
+                ("_2".to_string(), 1),
+                ("_3".to_string(), 2)
+            ]
+        );
+        assert!(format!("{}", module.block("_0").unwrap()).contains("capture 0"));
+        assert_eq!(verify_module(&module), Ok(()));
+        assert_eq!(
+            Vm::new(&module.blocks).run("main").unwrap().to_string(),
+            "(16 6)"
+        );
+    }
+
+    #[test]
+    fn keeps_scopes_of_later_bindings() {
+        // ((lambda () (define f (lambda () (g))) (define g (lambda () 7)) (f)))
+        let outer = lambda(
+            &[],
+            vec![
+                Expression::Define(
+                    "f".into(),
+                    Rc::new(lambda(&[], vec![call(ident("g"), vec![])])),
+                ),
+                Expression::Define("g".into(), Rc::new(lambda(&[], vec![int(7)]))),
+                call(ident("f"), vec![]),
+            ],
+        );
+        let mut module = translate(vec![call(outer, vec![])]);
+        // f needs g before it is defined, it keeps the scope of the outer lambda
+        assert_eq!(convert_closures(&mut module), 2);
+        assert_eq!(
+            captures(&module),
+            [("_0".to_string(), 0), ("_2".to_string(), 0)]
+        );
+        assert_eq!(
+            Vm::new(&module.blocks).run("main").unwrap(),
+            Value::Integer(7)
+        );
+    }
+}
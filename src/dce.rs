@@ -0,0 +1,344 @@
+//! Dead code elimination.
+//! `eliminate_dead_code` drops instructions without side effects whose results nobody reads,
+//! `prune_unreachable` drops lambdas and statics the entry block can no longer get to.
+//! Run `stack::stack_to_registers` first, values that go through the stack always look used.
+//!
+//! An instruction that may fail at runtime stays even if its result is dead, otherwise a
+//! program that errors would start to succeed. What registers are known to hold decides
+//! whether a PrimOp or LinkedListAdd can fail.
+
+use std::collections::HashSet;
+
+use crate::{
+    known::Facts,
+    liveness::{liveness, Liveness},
+    module::IrModule,
+    value::{Builtin, Value},
+    LinearBlock, LinearInstruction, Scope,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DceStats {
+    pub instructions_removed: usize,
+    pub lambdas_removed: usize,
+    pub statics_removed: usize,
+}
+
+/// Whether `instr` may fail when run with what is known before it. Reading a register that
+/// was never written is left to `verify`.
+fn can_fail(facts: &Facts, instr: &LinearInstruction) -> bool {
+    match instr {
+        LinearInstruction::StaticRefToRegister { .. }
+        | LinearInstruction::LinkedListInit { .. }
+        | LinearInstruction::Move { .. }
+        | LinearInstruction::InitializeFunctionPointer { .. }
+        | LinearInstruction::MakeClosure { .. } => false,
+        // Builtins are bound globally so the lookup finds at least those
+        LinearInstruction::Lookup {
+            identifier,
+            scope: Scope::Current | Scope::Global,
+            ..
+        } => identifier
+            .reftype
+            .as_name()
+            .and_then(Builtin::from_name)
+            .is_none(),
+        LinearInstruction::LinkedListAdd {
+            linked_list_reg, ..
+        } => !facts.is_list(linked_list_reg),
+        LinearInstruction::PrimOp { op, arguments, .. } => {
+            let arguments: Option<Vec<Value>> = arguments
+                .iter()
+                .map(|argument| facts.data(argument).map(Value::from_static_data))
+                .collect();
+            match arguments {
+                Some(arguments) => op.apply(arguments).is_err(),
+                None => true,
+            }
+        }
+        // Unbound names, unset locals, missing captures
+        _ => true,
+    }
+}
+
+/// Only reads registers and writes its outputs, so it can go if the outputs are dead and
+/// `can_fail` says it cannot fail
+fn is_pure(instr: &LinearInstruction) -> bool {
+    matches!(
+        instr,
+        LinearInstruction::StaticRefToRegister { .. }
+            | LinearInstruction::Lookup { .. }
+            | LinearInstruction::LinkedListInit { .. }
+            | LinearInstruction::LinkedListAdd { .. }
+            | LinearInstruction::PrimOp { .. }
+            | LinearInstruction::Move { .. }
+            | LinearInstruction::InitializeFunctionPointer { .. }
+            | LinearInstruction::MakeClosure { .. }
+            | LinearInstruction::LoadCapture { .. }
+            | LinearInstruction::LoadLocal { .. }
+    )
+}
+
+/// Removes dead pure instructions until there are none left, returns how many went
+pub fn eliminate_dead_code(block: &mut LinearBlock) -> usize {
+    let mut removed = 0;
+    loop {
+        let live = liveness(block);
+        match sweep(&mut block.program, &[], &live, &mut Facts::default()) {
+            0 => return removed,
+            swept => removed += swept,
+        }
+    }
+}
+
+fn sweep(
+    program: &mut Vec<LinearInstruction>,
+    prefix: &[usize],
+    live: &Liveness,
+    facts: &mut Facts,
+) -> usize {
+    let mut removed = 0;
+    let old = std::mem::take(program);
+    for (index, mut instr) in old.into_iter().enumerate() {
+        let mut path = prefix.to_vec();
+        path.push(index);
+        if let LinearInstruction::Cond { branc_if_true, .. } = &mut instr {
+            removed += facts.branch(&mut branc_if_true.program, |branch, facts| {
+                sweep(branch, &path, live, facts)
+            });
+        }
+        let dead = is_pure(&instr)
+            && !can_fail(facts, &instr)
+            && live.live_out(&path).is_some_and(|live_out| {
+                instr
+                    .defs()
+                    .iter()
+                    .all(|register| !live_out.contains(*register))
+            });
+        facts.learn(&instr);
+        if dead {
+            removed += 1;
+        } else {
+            program.push(instr);
+        }
+    }
+    removed
+}
+
+/// Drops blocks no closure reachable from the entry block points to and statics no reachable
+/// block refers to, together with their source map entries
+pub fn prune_unreachable(module: &mut IrModule) -> DceStats {
+    let mut reachable = HashSet::from([module.entry.clone()]);
+    let mut statics = HashSet::new();
+    let mut todo = vec![module.entry.clone()];
+    while let Some(ident) = todo.pop() {
+        let Some(block) = module.blocks.get(&ident) else {
+            continue;
+        };
+        let mut lambdas = vec![];
+        collect_refs(&block.program, &mut lambdas, &mut statics);
+        for lambda in lambdas {
+            if reachable.insert(lambda.clone()) {
+                todo.push(lambda);
+            }
+        }
+    }
+
+    let blocks_before = module.blocks.len();
+    let statics_before = module.static_data.len();
+    module.blocks.retain(|ident, _| reachable.contains(ident));
+    module
+        .static_data
+        .retain(|refname, _| statics.contains(refname));
+    module
+        .source_map
+        .blocks
+        .retain(|ident, _| reachable.contains(ident));
+    module
+        .source_map
+        .statics
+        .retain(|refname, _| statics.contains(refname));
+    DceStats {
+        instructions_removed: 0,
+        lambdas_removed: blocks_before - module.blocks.len(),
+        statics_removed: statics_before - module.static_data.len(),
+    }
+}
+
+/// Both passes over every block of the module
+pub fn eliminate_dead_code_module(module: &mut IrModule) -> DceStats {
+    let instructions_removed = module.blocks.values_mut().map(eliminate_dead_code).sum();
+    DceStats {
+        instructions_removed,
+        ..prune_unreachable(module)
+    }
+}
+
+fn collect_refs(
+    program: &[LinearInstruction],
+    lambdas: &mut Vec<String>,
+    statics: &mut HashSet<String>,
+) {
+    for instr in program {
+        let static_ref = match instr {
+            LinearInstruction::AcceptToFormals {
+                static_formals_list,
+            } => static_formals_list,
+            LinearInstruction::StaticRefToRegister { static_ref, .. } => static_ref,
+            LinearInstruction::Assign { identifier, .. }
+            | LinearInstruction::Lookup { identifier, .. } => identifier,
+            LinearInstruction::InitializeFunctionPointer { function, .. }
+            | LinearInstruction::MakeClosure { function, .. } => {
+                lambdas.push(function.actual_func.clone());
+                &function.formals_list
+            }
+            LinearInstruction::Cond { branc_if_true, .. } => {
+                collect_refs(&branc_if_true.program, lambdas, statics);
+                continue;
+            }
+            _ => continue,
+        };
+        statics.insert(static_ref.refname.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use little_parser::{Expression, Programm};
+
+    use super::{eliminate_dead_code, eliminate_dead_code_module};
+    use crate::{
+        asm::parse_program,
+        stack::stack_to_registers_module,
+        test_ast::{ident, int, lambda},
+        value::Value,
+        verify::verify_module,
+        vm::Vm,
+        LinearBlock, Translator,
+    };
+
+    #[test]
+    fn removes_unread_results() {
+        let mut block = LinearBlock {
+            ident: "test".into(),
+            program: parse_program(
+                "load static0{1} -> vreg0
+                 lookup static1{x} -> vreg1 @current
+                 move vreg1 -> vreg2
+                 load static2{#t} -> vreg3
+                 cond0: if vreg3 {
+                     list.init -> vreg4
+                     push vreg3
+                 }
+                 cond0: end
+                 call vreg0 vreg0 -> vreg5
+                 push vreg0",
+            )
+            .unwrap(),
+        };
+        // The call result is unused too but calling has effects, x may be unbound
+        assert_eq!(eliminate_dead_code(&mut block), 2);
+        assert_eq!(
+            block.program,
+            parse_program(
+                "load static0{1} -> vreg0
+                 lookup static1{x} -> vreg1 @current
+                 load static2{#t} -> vreg3
+                 cond0: if vreg3 {
+                     push vreg3
+                 }
+                 cond0: end
+                 call vreg0 vreg0 -> vreg5
+                 push vreg0"
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn keeps_what_can_fail() {
+        let mut block = LinearBlock {
+            ident: "test".into(),
+            program: parse_program(
+                "load static0{1} -> vreg0
+                 load static1{0} -> vreg1
+                 prim + vreg0 vreg1 -> vreg2
+                 prim / vreg0 vreg1 -> vreg3
+                 prim + vreg0 vreg4 -> vreg5
+                 local.load 0 0 -> vreg6
+                 lookup static2{+} -> vreg7 @current
+                 list.init -> vreg8
+                 list.add vreg8 <- vreg6 -> vreg9
+                 list.add vreg1 <- vreg0 -> vreg10
+                 push vreg0",
+            )
+            .unwrap(),
+        };
+        let run = |block: &LinearBlock| Vm::new(&HashMap::new()).run_block(block);
+        assert!(run(&block).is_err());
+
+        // Division by zero, an unknown argument, an unset local and adding to a number stay
+        assert_eq!(eliminate_dead_code(&mut block), 4);
+        assert_eq!(
+            block.program,
+            parse_program(
+                "load static0{1} -> vreg0
+                 load static1{0} -> vreg1
+                 prim / vreg0 vreg1 -> vreg3
+                 prim + vreg0 vreg4 -> vreg5
+                 local.load 0 0 -> vreg6
+                 list.add vreg1 <- vreg0 -> vreg10
+                 push vreg0"
+            )
+            .unwrap()
+        );
+        assert!(run(&block).is_err());
+
+        // Only the path falling into the label made vreg2 a list
+        let mut flat = LinearBlock {
+            ident: "test".into(),
+            program: parse_program(
+                "load static0{1} -> vreg2
+                 load static1{#f} -> vreg1
+                 jump.false vreg1 end
+                 list.init -> vreg2
+                 label end
+                 list.add vreg2 <- vreg1 -> vreg3
+                 push vreg1",
+            )
+            .unwrap(),
+        };
+        assert_eq!(eliminate_dead_code(&mut flat), 0);
+        assert!(run(&flat).is_err());
+    }
+
+    #[test]
+    fn drops_lambdas_nothing_makes() {
+        // (let ((unused (lambda (x) x))) (lambda (y) y) 1)
+        let program = Programm::Expression(vec![Expression::Let(
+            vec![("unused".into(), lambda(&["x"], vec![ident("x")]))],
+            vec![lambda(&["y"], vec![ident("y")]), int(1)],
+        )]);
+        let mut module = Translator::default().translate_module(program).unwrap();
+        let statics_before = module.static_data.len();
+        stack_to_registers_module(&mut module);
+
+        let stats = eliminate_dead_code_module(&mut module);
+        // The let binding is assigned into a scope so its lambda stays
+        assert!(module.blocks.contains_key("_0"));
+        assert!(!module.blocks.contains_key("_1"));
+        assert_eq!(stats.lambdas_removed, 1);
+        assert!(!module.static_data.contains_key("_1"));
+        assert_eq!(
+            module.static_data.len(),
+            statics_before - stats.statics_removed
+        );
+        assert_eq!(verify_module(&module), Ok(()));
+        assert_eq!(
+            Vm::new(&module.blocks).run("main").unwrap(),
+            Value::Integer(1)
+        );
+    }
+}
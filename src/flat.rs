@@ -0,0 +1,243 @@
+//! Flat control flow for code generators.
+//! `flatten` turns every cond group into conditional jumps over its branches so a block is one
+//! straight list of instructions. Labels are the cond name plus the clause index, or `end` for
+//! where the EndOfCond was:
+//!
+//! ```text
+//! jump.false vreg0 cond3.0
+//! push vreg1
+//! jump cond3.end
+//! label cond3.0
+//! push vreg2
+//! label cond3.end
+//! ```
+//!
+//! `verify`, `liveness` and `dce` follow the jumps, every other pass only knows the nested
+//! form that `unflatten` gives back, so flatten last. Phis stay after the end label but nothing
+//! can tell which path got there, run `ssa::from_ssa` first.
+
+use std::{collections::HashMap, fmt};
+
+use crate::{module::IrModule, Branch, LinearBlock, LinearInstruction};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnflattenError {
+    /// Label or jump that does not fit the shape `flatten` makes, as asm
+    Unexpected(String),
+    /// Branch skipped by a jump to this label never jumps to the end of its cond
+    MissingJump(String),
+    /// Jump to this label from a clause without the label after its branch
+    MissingLabel(String),
+}
+
+impl fmt::Display for UnflattenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnflattenError::Unexpected(instr) => {
+                write!(f, "`{}` is not part of a flattened cond", instr)
+            }
+            UnflattenError::MissingJump(label) => {
+                write!(f, "branch skipped to `{}` never jumps to its end", label)
+            }
+            UnflattenError::MissingLabel(label) => {
+                write!(f, "no label `{}` after the branch", label)
+            }
+        }
+    }
+}
+
+impl std::error::Error for UnflattenError {}
+
+pub fn flatten(block: &mut LinearBlock) {
+    let program = std::mem::take(&mut block.program);
+    block.program = flatten_program(program);
+}
+
+pub fn flatten_module(module: &mut IrModule) {
+    module.blocks.values_mut().for_each(flatten);
+}
+
+pub fn unflatten(block: &mut LinearBlock) -> Result<(), UnflattenError> {
+    let program = std::mem::take(&mut block.program);
+    block.program = nest(&mut program.into_iter(), None)?;
+    Ok(())
+}
+
+pub fn unflatten_module(module: &mut IrModule) -> Result<(), UnflattenError> {
+    module.blocks.values_mut().try_for_each(unflatten)
+}
+
+fn label(cond_name: &str, suffix: impl fmt::Display) -> String {
+    format!("{}.{}", cond_name, suffix)
+}
+
+fn flatten_program(program: Vec<LinearInstruction>) -> Vec<LinearInstruction> {
+    let mut out = vec![];
+    let mut clauses: HashMap<String, usize> = HashMap::new();
+    for instr in program {
+        match instr {
+            LinearInstruction::Cond {
+                cond_name,
+                condition,
+                branc_if_true,
+            } => {
+                let clause = clauses.entry(cond_name.clone()).or_insert(0);
+                let skip = label(&cond_name, *clause);
+                *clause += 1;
+                out.push(LinearInstruction::JumpIfFalse {
+                    condition,
+                    label: skip.clone(),
+                });
+                out.extend(flatten_program(branc_if_true.program));
+                // Even after a Return, it marks where the branch stops for unflatten
+                out.push(LinearInstruction::Jump {
+                    label: label(&cond_name, "end"),
+                });
+                out.push(LinearInstruction::Label { name: skip });
+            }
+            LinearInstruction::EndOfCond { cond_name } => {
+                clauses.remove(&cond_name);
+                out.push(LinearInstruction::Label {
+                    name: label(&cond_name, "end"),
+                });
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// Nested program up to the jump to `end`, or up to the end of `instrs` if None
+fn nest(
+    instrs: &mut std::vec::IntoIter<LinearInstruction>,
+    end: Option<&str>,
+) -> Result<Vec<LinearInstruction>, UnflattenError> {
+    let mut out = vec![];
+    loop {
+        let Some(instr) = instrs.next() else {
+            return match end {
+                Some(end) => Err(UnflattenError::MissingJump(end.to_string())),
+                None => Ok(out),
+            };
+        };
+        match instr {
+            LinearInstruction::JumpIfFalse { condition, label } => {
+                let Some((cond_name, _)) = label.rsplit_once('.') else {
+                    let instr = LinearInstruction::JumpIfFalse { condition, label };
+                    return Err(UnflattenError::Unexpected(instr.to_string()));
+                };
+                let cond_name = cond_name.to_string();
+                let program = nest(instrs, Some(&self::label(&cond_name, "end")))?;
+                match instrs.next() {
+                    Some(LinearInstruction::Label { name }) if name == label => {}
+                    _ => return Err(UnflattenError::MissingLabel(label)),
+                }
+                out.push(LinearInstruction::Cond {
+                    cond_name,
+                    condition,
+                    branc_if_true: Branch { program },
+                });
+            }
+            LinearInstruction::Jump { label } if Some(label.as_str()) == end => return Ok(out),
+            LinearInstruction::Label { name } if name.ends_with(".end") => {
+                let cond_name = name.trim_end_matches(".end").to_string();
+                out.push(LinearInstruction::EndOfCond { cond_name });
+            }
+            instr @ (LinearInstruction::Jump { .. } | LinearInstruction::Label { .. }) => {
+                return Err(UnflattenError::Unexpected(instr.to_string()))
+            }
+            other => out.push(other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use little_parser::{Expression, Programm};
+
+    use super::{flatten_module, unflatten, unflatten_module, UnflattenError};
+    use crate::{
+        asm::parse_program,
+        test_ast::{boolean, call, ident, int, lambda},
+        vm::Vm,
+        LinearBlock, LinearInstruction, Translator,
+    };
+
+    #[test]
+    fn round_trips_and_runs_flat() {
+        // (define sign (lambda (x) (cond ((< x 0) -1) ((= x 0) (cond (#f 5))) (else 1))))
+        // (list (sign -4) (sign 0) (sign 9))
+        let sign = lambda(
+            &["x"],
+            vec![Expression::Cond(vec![
+                (call(ident("<"), vec![ident("x"), int(0)]), int(-1)),
+                (
+                    call(ident("="), vec![ident("x"), int(0)]),
+                    Expression::Cond(vec![(boolean(false), int(5))]),
+                ),
+                (ident("else"), int(1)),
+            ])],
+        );
+        let program = Programm::Expression(vec![
+            Expression::Define("sign".into(), Rc::new(sign)),
+            call(
+                ident("list"),
+                vec![
+                    call(ident("sign"), vec![int(-4)]),
+                    call(ident("sign"), vec![int(0)]),
+                    call(ident("sign"), vec![int(9)]),
+                ],
+            ),
+        ]);
+        let nested = Translator::default().translate_module(program).unwrap();
+        let mut module = nested.clone();
+
+        flatten_module(&mut module);
+        let flat = &module.block("_0").unwrap().program;
+        assert!(!flat.iter().any(|instr| matches!(
+            instr,
+            LinearInstruction::Cond { .. } | LinearInstruction::EndOfCond { .. }
+        )));
+        assert!(flat.contains(&LinearInstruction::Label {
+            name: "cond0.end".into()
+        }));
+        assert_eq!(
+            Vm::new(&module.blocks).run("main").unwrap().to_string(),
+            "(-1 #<void> 1)"
+        );
+
+        unflatten_module(&mut module).unwrap();
+        assert_eq!(module.blocks, nested.blocks);
+    }
+
+    #[test]
+    fn rejects_jumps_it_did_not_make() {
+        let unflatten = |src: &str| {
+            unflatten(&mut LinearBlock {
+                ident: "test".into(),
+                program: parse_program(src).unwrap(),
+            })
+        };
+        assert_eq!(
+            unflatten("jump elsewhere"),
+            Err(UnflattenError::Unexpected("jump elsewhere".into()))
+        );
+        assert_eq!(
+            unflatten(
+                "jump.false vreg0 cond0.0
+                 push vreg0"
+            ),
+            Err(UnflattenError::MissingJump("cond0.end".into()))
+        );
+        assert_eq!(
+            unflatten(
+                "jump.false vreg0 cond0.0
+                 jump cond0.end
+                 label cond0.1"
+            ),
+            Err(UnflattenError::MissingLabel("cond0.0".into()))
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! Constant folding.
+//! Calls to builtins whose arguments are all statics get evaluated at compile time and replaced
+//! by loading the result. The lookup and argument list are left behind for `dce` to remove.
+//! Run `stack::stack_to_registers` first, values are only followed through registers.
+//!
+//! A builtin name assigned or taken as a formal anywhere in the module is never folded, we do
+//! not try to tell which lookups it shadows. Let bindings and internal defines are read with
+//! LoadLocal and never look like builtins to begin with.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    known::{Facts, Known},
+    module::IrModule,
+    value::{Builtin, Value},
+    LinearInstruction, Scope, StaticData, StaticRef,
+};
+
+struct Folder<'m> {
+    shadowed: HashSet<String>,
+    /// Existing statics by value so results can reuse them
+    interned: HashMap<StaticData, String>,
+    static_data: &'m mut HashMap<String, StaticData>,
+    static_count: &'m mut usize,
+}
+
+/// Folds every block of the module, returns how many calls were replaced
+pub fn fold_constants(module: &mut IrModule) -> usize {
+    let mut shadowed = HashSet::new();
+    for block in module.blocks.values() {
+        collect_bound(&block.program, &mut shadowed);
+    }
+    let mut interned = HashMap::new();
+    for (refname, data) in module.iter_static_data() {
+        interned
+            .entry(data.clone())
+            .or_insert_with(|| refname.clone());
+    }
+    let mut folder = Folder {
+        shadowed,
+        interned,
+        static_data: &mut module.static_data,
+        static_count: &mut module.metadata.static_count,
+    };
+    // Sorted so new statics get the same names every run
+    let mut blocks: Vec<_> = module.blocks.values_mut().collect();
+    blocks.sort_by(|a, b| a.ident.cmp(&b.ident));
+    blocks
+        .into_iter()
+        .map(|block| folder.fold(&mut block.program, &mut Facts::default()))
+        .sum()
+}
+
+impl Folder<'_> {
+    fn fold(&mut self, program: &mut [LinearInstruction], facts: &mut Facts) -> usize {
+        let mut folded = 0;
+        for instr in program.iter_mut() {
+            if let LinearInstruction::Cond { branc_if_true, .. } = instr {
+                folded += facts.branch(&mut branc_if_true.program, |branch, facts| {
+                    self.fold(branch, facts)
+                });
+                continue;
+            }
+            if let LinearInstruction::Call {
+                output_reg,
+                function_pointer,
+                arguments,
+            } = instr
+            {
+                if let Some(data) = evaluate(facts.get(function_pointer), facts.get(arguments)) {
+                    *instr = LinearInstruction::StaticRefToRegister {
+                        static_ref: self.static_ref(data),
+                        to_reg: output_reg.clone(),
+                    };
+                    folded += 1;
+                }
+            }
+            if let LinearInstruction::PrimOp {
+                op,
+                arguments,
+                output_reg,
+            } = instr
+            {
+                let arguments: Option<Vec<StaticData>> = arguments
+                    .iter()
+                    .map(|argument| facts.data(argument).cloned())
+                    .collect();
+                let function = Known::Builtin(*op);
+                let arguments = arguments.map(|arguments| Known::Data(StaticData::List(arguments)));
+                if let Some(data) = evaluate(Some(&function), arguments.as_ref()) {
+                    *instr = LinearInstruction::StaticRefToRegister {
+                        static_ref: self.static_ref(data),
+                        to_reg: output_reg.clone(),
+                    };
+                    folded += 1;
+                }
+            }
+
+            facts.learn(instr);
+            if let LinearInstruction::Lookup {
+                identifier,
+                to_reg,
+                scope: Scope::Current | Scope::Global,
+            } = instr
+            {
+                let builtin = identifier
+                    .reftype
+                    .as_name()
+                    .filter(|name| !self.shadowed.contains(*name))
+                    .and_then(Builtin::from_name);
+                if let Some(builtin) = builtin {
+                    facts.insert(to_reg.clone(), Known::Builtin(builtin));
+                }
+            }
+        }
+        folded
+    }
+    fn static_ref(&mut self, data: StaticData) -> StaticRef {
+        let refname = match self.interned.get(&data) {
+            Some(refname) => refname.clone(),
+            None => {
+                let refname = format!("static{}", self.static_count);
+                *self.static_count += 1;
+                self.static_data.insert(refname.clone(), data.clone());
+                self.interned.insert(data.clone(), refname.clone());
+                refname
+            }
+        };
+        StaticRef {
+            refname,
+            reftype: data,
+        }
+    }
+}
+
+/// Result of calling `function` with `arguments`, None if either is unknown or the call fails
+fn evaluate(function: Option<&Known>, arguments: Option<&Known>) -> Option<StaticData> {
+    let (Some(Known::Builtin(builtin)), Some(Known::Data(StaticData::List(arguments)))) =
+        (function, arguments)
+    else {
+        return None;
+    };
+    let arguments = arguments.iter().map(Value::from_static_data).collect();
+    // Failing calls stay so the error still happens at runtime
+    builtin.apply(arguments).ok()?.to_static_data()
+}
+
+/// Names assigned or accepted as formals anywhere in `program`
+fn collect_bound(program: &[LinearInstruction], bound: &mut HashSet<String>) {
+    for instr in program {
+        match instr {
+            LinearInstruction::Assign { identifier, .. } => {
+                bound.extend(identifier.reftype.as_name().map(str::to_string))
+            }
+            LinearInstruction::AcceptToFormals {
+                static_formals_list,
+            } => {
+                if let StaticData::List(formals) = &static_formals_list.reftype {
+                    bound.extend(
+                        formals
+                            .iter()
+                            .filter_map(StaticData::as_name)
+                            .map(str::to_string),
+                    );
+                }
+            }
+            LinearInstruction::Cond { branc_if_true, .. } => {
+                collect_bound(&branc_if_true.program, bound)
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use little_parser::{Expression, Programm};
+
+    use super::fold_constants;
+    use crate::{
+        dce::eliminate_dead_code_module,
+        stack::stack_to_registers_module,
+        test_ast::{call, ident, int},
+        value::Value,
+        verify::verify_module,
+        vm::Vm,
+        LinearInstruction, StaticData, Translator,
+    };
+
+    #[test]
+    fn nested_calls_become_one_load() {
+        // (+ 1 (* 2 3))
+        let program = Programm::Expression(vec![call(
+            ident("+"),
+            vec![int(1), call(ident("*"), vec![int(2), int(3)])],
+        )]);
+        let mut module = Translator::default().translate_module(program).unwrap();
+        stack_to_registers_module(&mut module);
+        assert_eq!(fold_constants(&mut module), 2);
+        eliminate_dead_code_module(&mut module);
+
+        let main = &module.entry_block().program;
+        assert_eq!(main.len(), 2, "{:?}", main);
+        assert!(matches!(
+            &main[0],
+            LinearInstruction::StaticRefToRegister { static_ref, .. }
+                if static_ref.reftype == StaticData::Integer(7)
+        ));
+        assert_eq!(verify_module(&module), Ok(()));
+        assert_eq!(
+            Vm::new(&module.blocks).run("main").unwrap(),
+            Value::Integer(7)
+        );
+    }
+
+    #[test]
+    fn leaves_shadowed_and_failing_calls() {
+        let fold = |exprs: Vec<Expression>| {
+            let mut module = Translator::default()
+                .translate_module(Programm::Expression(exprs))
+                .unwrap();
+            stack_to_registers_module(&mut module);
+            let folded = fold_constants(&mut module);
+            (folded, Vm::new(&module.blocks).run("main"))
+        };
+        // (let ((+ -)) (+ 5 1)) (* 2 2)
+        let (folded, res) = fold(vec![
+            Expression::Let(
+                vec![("+".into(), ident("-"))],
+                vec![call(ident("+"), vec![int(5), int(1)])],
+            ),
+            call(ident("*"), vec![int(2), int(2)]),
+        ]);
+        assert_eq!(folded, 1);
+        assert_eq!(res.unwrap(), Value::Integer(4));
+        // (/ 1 0)
+        let (folded, res) = fold(vec![call(ident("/"), vec![int(1), int(0)])]);
+        assert_eq!(folded, 0);
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,148 @@
+//! What registers are known to hold, for `fold` and `dce`.
+//! Facts flow forward through a program. A Cond branch starts from a copy of them and
+//! afterwards whatever it wrote is forgotten, a Label forgets everything since jumps arrive
+//! there with whatever their own path knew.
+
+use std::collections::HashMap;
+
+use crate::{value::Builtin, LinearInstruction, Register, StaticData};
+
+/// What a register is known to hold at some point
+#[derive(Debug, Clone)]
+pub(crate) enum Known {
+    Data(StaticData),
+    Builtin(Builtin),
+    /// A list with contents we do not know
+    List,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Facts(HashMap<Register, Known>);
+
+impl Facts {
+    pub fn get(&self, register: &Register) -> Option<&Known> {
+        self.0.get(register)
+    }
+    pub fn data(&self, register: &Register) -> Option<&StaticData> {
+        match self.get(register) {
+            Some(Known::Data(data)) => Some(data),
+            _ => None,
+        }
+    }
+    pub fn is_list(&self, register: &Register) -> bool {
+        matches!(
+            self.get(register),
+            Some(Known::List | Known::Data(StaticData::List(_)))
+        )
+    }
+    /// For what only the pass knows, call after `learn` for the same instruction
+    pub fn insert(&mut self, register: Register, known: Known) {
+        self.0.insert(register, known);
+    }
+    /// Updates what is known after running `instr`, the branch of a Cond goes through `branch`
+    pub fn learn(&mut self, instr: &LinearInstruction) {
+        let known = match instr {
+            LinearInstruction::Label { .. } => {
+                self.0.clear();
+                return;
+            }
+            LinearInstruction::StaticRefToRegister { static_ref, .. } => {
+                Some(Known::Data(static_ref.reftype.clone()))
+            }
+            LinearInstruction::LinkedListInit { .. } => Some(Known::Data(StaticData::List(vec![]))),
+            LinearInstruction::LinkedListAdd {
+                linked_list_reg,
+                input_reg,
+                ..
+            } => match (self.data(linked_list_reg), self.data(input_reg)) {
+                (Some(StaticData::List(items)), Some(input)) => {
+                    let mut items = items.clone();
+                    items.push(input.clone());
+                    Some(Known::Data(StaticData::List(items)))
+                }
+                _ => self.is_list(linked_list_reg).then_some(Known::List),
+            },
+            LinearInstruction::Move { from_reg, .. } => self.get(from_reg).cloned(),
+            _ => None,
+        };
+        for register in instr.defs() {
+            self.0.remove(register);
+        }
+        if let (Some(known), [register]) = (known, instr.defs().as_slice()) {
+            self.0.insert((*register).clone(), known);
+        }
+    }
+    /// Runs `pass` over the branch of a Cond with a copy of the facts, then forgets whatever
+    /// the branch wrote since that depends on whether it ran
+    pub fn branch<T>(
+        &mut self,
+        branch: &mut Vec<LinearInstruction>,
+        pass: impl FnOnce(&mut Vec<LinearInstruction>, &mut Facts) -> T,
+    ) -> T {
+        let result = pass(branch, &mut self.clone());
+        self.forget(branch);
+        result
+    }
+    fn forget(&mut self, program: &[LinearInstruction]) {
+        for instr in program {
+            for register in instr.defs() {
+                self.0.remove(register);
+            }
+            if let LinearInstruction::Cond { branc_if_true, .. } = instr {
+                self.forget(&branc_if_true.program);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Facts;
+    use crate::{asm::parse_program, LinearInstruction, Register, StaticData};
+
+    #[test]
+    fn branches_and_labels_forget() {
+        let mut program = parse_program(
+            "load static0{1} -> vreg0
+             load static0{1} -> vreg1
+             cond0: if vreg0 {
+                 load static1{2} -> vreg1
+             }
+             cond0: end
+             label end",
+        )
+        .unwrap();
+        let register = |name: &str| Register {
+            virtual_ident: name.into(),
+        };
+        let mut facts = Facts::default();
+        let mut seen = vec![];
+        for instr in program.iter_mut() {
+            if let LinearInstruction::Cond { branc_if_true, .. } = instr {
+                let inside = facts.branch(&mut branc_if_true.program, |branch, facts| {
+                    branch.iter().for_each(|instr| facts.learn(instr));
+                    facts.data(&register("vreg1")).cloned()
+                });
+                assert_eq!(inside, Some(StaticData::Integer(2)));
+            } else {
+                facts.learn(instr);
+            }
+            seen.push((
+                facts.data(&register("vreg0")).cloned(),
+                facts.data(&register("vreg1")).cloned(),
+            ));
+        }
+        let one = Some(StaticData::Integer(1));
+        assert_eq!(
+            seen,
+            vec![
+                (one.clone(), None),
+                (one.clone(), one.clone()),
+                // The branch may or may not have overwritten vreg1
+                (one.clone(), None),
+                (one, None),
+                (None, None),
+            ]
+        );
+    }
+}
@@ -1,6 +1,34 @@
-use std::{collections::HashMap, vec};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    rc::Rc,
+};
+
+pub mod asm;
+pub mod binary;
+pub mod cfg;
+pub mod closure;
+pub mod dce;
+pub mod flat;
+pub mod fold;
+mod known;
+pub mod liveness;
+pub mod module;
+pub mod regalloc;
+pub mod span;
+pub mod ssa;
+pub mod stack;
+#[cfg(test)]
+mod test_ast;
+pub mod value;
+pub mod verify;
+pub mod vm;
+pub mod x86_64;
 
 use little_parser::{Expression, Programm};
+use module::{IrModule, ModuleMetadata};
+use span::{read_span_trees, BlockSpans, SourceMap, Span, SpanTree, SpannedProgram};
+use value::Builtin;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum LinearInstruction {
@@ -30,6 +58,7 @@ pub enum LinearInstruction {
     LinkedListAdd {
         linked_list_reg: Register,
         input_reg: Register,
+        output_reg: Register,
     },
     Assign {
         identifier: StaticRef,
@@ -48,6 +77,9 @@ pub enum LinearInstruction {
         to_reg: Register,
         scope: Scope,
     },
+    /// Runs the branch if `condition` is true and then jumps to the EndOfCond of `cond_name`,
+    /// skipping the clauses left. What comes between the last clause and the EndOfCond only runs
+    /// when no clause was taken.
     Cond {
         cond_name: String,
         /// Pointer
@@ -65,6 +97,72 @@ pub enum LinearInstruction {
         from_scope: Scope,
         outpu_reg: Register,
     },
+    /// Copies a register, left behind where a push/pop pair got removed
+    Move {
+        from_reg: Register,
+        to_reg: Register,
+    },
+    /// SSA merge right after the EndOfCond of `cond_name`. Each input is tagged with the clause
+    /// whose branch it comes from, None for falling through every clause.
+    Phi {
+        cond_name: String,
+        inputs: Vec<(Option<usize>, Register)>,
+        output_reg: Register,
+    },
+    /// Frame slots the register allocator keeps values in that did not get a register
+    SpillToSlot {
+        from_reg: Register,
+        slot: usize,
+    },
+    ReloadFromSlot {
+        slot: usize,
+        to_reg: Register,
+    },
+    /// Call whose result is returned right away, the callee takes over the frame of the caller
+    TailCall {
+        function_pointer: Register,
+        arguments: Register,
+    },
+    /// Closure carrying only the values it needs instead of the scope it was made in,
+    /// its body reads them back with LoadCapture
+    MakeClosure {
+        function: FunctionPointer,
+        captures: Vec<Register>,
+        output_reg: Register,
+    },
+    LoadCapture {
+        index: usize,
+        to_reg: Register,
+    },
+    /// Builtin applied straight to registers, calls to builtin names nothing rebinds become these
+    PrimOp {
+        op: Builtin,
+        arguments: Vec<Register>,
+        output_reg: Register,
+    },
+    /// Slot `slot` of the scope `depth` parents up from the current one, let bindings and
+    /// formals are resolved to these at compile time
+    LoadLocal {
+        depth: usize,
+        slot: usize,
+        to_reg: Register,
+    },
+    StoreLocal {
+        depth: usize,
+        slot: usize,
+        from_reg: Register,
+    },
+    /// Flat control flow `flat::flatten` turns Conds into, labels only need to be unique per block
+    Label {
+        name: String,
+    },
+    Jump {
+        label: String,
+    },
+    JumpIfFalse {
+        condition: Register,
+        label: String,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -73,7 +171,7 @@ pub struct LinearBlock {
     pub program: Vec<LinearInstruction>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Register {
     pub virtual_ident: String,
 }
@@ -116,8 +214,39 @@ pub struct Translator {
     static_data_counter: usize,
     cond_name_counter: usize,
     pub static_data: HashMap<String, StaticData>,
+    /// Refname of every static made so far, equal data shares one entry
+    interned: HashMap<StaticData, String>,
+    /// Statics asked for, including the ones served from `interned`
+    static_requests: usize,
+    /// Names the program binds somewhere, calls to builtins with these names stay calls
+    rebound: HashSet<String>,
     pub lambda_map: HashMap<String, LinearBlock>,
+    /// Where each block, instruction and static came from, only filled when given source
+    pub source_map: SourceMap,
+    /// Span of the expression currently being lowered, statics made now belong to it
+    current_span: Option<Span>,
+    /// Set right before lowering an expression whose value the enclosing lambda returns
+    tail_position: bool,
+    /// Set right before lowering a define at the head of a lambda or let body
+    head_define: bool,
+    /// Compile time copy of the scopes between the current one and the global scope, innermost
+    /// last. Each lists the names of its slots in order.
+    locals: Vec<Vec<String>>,
+}
+/// Static table sizes, `requested` is what it would be without interning
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StaticStats {
+    pub requested: usize,
+    pub stored: usize,
+}
+
+impl StaticStats {
+    /// Entries interning saved
+    pub fn saved(&self) -> usize {
+        self.requested - self.stored
+    }
 }
+
 impl Translator {
     pub fn default() -> Translator {
         Translator {
@@ -127,30 +256,161 @@ impl Translator {
             cond_name_counter: 0,
             lambda_map: HashMap::new(),
             static_data: HashMap::new(),
+            interned: HashMap::new(),
+            static_requests: 0,
+            rebound: HashSet::new(),
+            source_map: SourceMap::default(),
+            current_span: None,
+            tail_position: false,
+            head_define: false,
+            locals: vec![],
         }
     }
     // Prob just a series of applying expr_to_instructions
-    pub fn ast_to_intermediate_representation(&mut self, ast: Programm) -> LinearBlock {
-        let mut main = LinearBlock {
-            ident: "main".into(),
-            program: vec![],
+    pub fn ast_to_intermediate_representation(
+        &mut self,
+        ast: Programm,
+    ) -> Result<LinearBlock, TranslateError> {
+        self.translate_main(ast, &[])
+    }
+    /// Same as ast_to_intermediate_representation but hands back everything the program needs
+    pub fn translate_module(&mut self, ast: Programm) -> Result<IrModule, TranslateError> {
+        let main = self.ast_to_intermediate_representation(ast)?;
+        Ok(self.make_module(main))
+    }
+    /// Like translate_module but also records spans into `source`, the text `ast` was parsed from
+    pub fn translate_module_with_source(
+        &mut self,
+        ast: Programm,
+        source: &str,
+    ) -> Result<IrModule, TranslateError> {
+        let main = self.translate_main(ast, &read_span_trees(source))?;
+        Ok(self.make_module(main))
+    }
+    fn translate_main(
+        &mut self,
+        ast: Programm,
+        trees: &[SpanTree],
+    ) -> Result<LinearBlock, TranslateError> {
+        let span = match (trees.first(), trees.last()) {
+            (Some(first), Some(last)) => Some(Span {
+                end: last.span.end,
+                ..first.span
+            }),
+            _ => None,
         };
+        let mut main = SpannedProgram::new(span);
+        // Left over if an earlier translation failed halfway
+        self.locals.clear();
         match ast {
             Programm::Expression(inner) => {
-                for expr in inner {
-                    main.program
-                        .extend_from_slice(&self.expr_to_instructions(expr));
+                // Programs wrapped in one outer list have their expressions one level down,
+                // if neither fits we rather have no spans than wrong ones
+                let trees = if trees.len() == inner.len() {
+                    trees
+                } else if trees.len() == 1 && trees[0].children.len() == inner.len() {
+                    &trees[0].children[..]
+                } else {
+                    &[]
+                };
+                for expr in &inner {
+                    collect_bound_names(expr, &mut self.rebound);
+                }
+                for (i, expr) in inner.into_iter().enumerate() {
+                    main.append(self.lower(expr, trees.get(i))?);
                 }
             }
         }
+        self.source_map.blocks.insert(
+            "main".into(),
+            BlockSpans {
+                span,
+                instructions: main.spans,
+            },
+        );
+        let main = LinearBlock {
+            ident: "main".into(),
+            program: main.program,
+        };
         self.lambda_map.insert("main".into(), main.clone());
 
-        main
+        Ok(main)
+    }
+    fn make_module(&self, main: LinearBlock) -> IrModule {
+        IrModule {
+            entry: main.ident,
+            blocks: self.lambda_map.clone(),
+            static_data: self.static_data.clone(),
+            source_map: self.source_map.clone(),
+            metadata: ModuleMetadata {
+                register_count: self.register_counter,
+                lambda_count: self.anon_lambda_counter,
+                static_count: self.static_data_counter,
+                cond_count: self.cond_name_counter,
+            },
+        }
     }
     /// Design Note!:
     /// Final Data is always pushed onto the stack :)
-    pub fn expr_to_instructions(&mut self, expr: Expression) -> Vec<LinearInstruction> {
-        let mut instr_buf = vec![];
+    pub fn expr_to_instructions(
+        &mut self,
+        expr: Expression,
+    ) -> Result<Vec<LinearInstruction>, TranslateError> {
+        collect_bound_names(&expr, &mut self.rebound);
+        self.locals.clear();
+        Ok(self.lower(expr, None)?.program)
+    }
+    /// expr_to_instructions that also keeps track of spans, `tree` is the source of `expr` if known
+    fn lower(
+        &mut self,
+        expr: Expression,
+        tree: Option<&SpanTree>,
+    ) -> Result<SpannedProgram, TranslateError> {
+        let span = tree.map(|tree| tree.span);
+        let outer_span = std::mem::replace(&mut self.current_span, span);
+        let res = self.lower_expression(expr, tree);
+        self.current_span = outer_span;
+        res
+    }
+    /// Lowers a lambda or let body, the defines at its head already have their slots
+    fn lower_body(
+        &mut self,
+        body: Vec<Expression>,
+        tree: Option<&SpanTree>,
+        tail: bool,
+    ) -> Result<Vec<SpannedProgram>, TranslateError> {
+        let head = head_defines(&body).count();
+        let len = body.len();
+        let mut lowered = vec![];
+        for (i, expr) in body.into_iter().enumerate() {
+            let expr_tree = span::child(tree, 2 + i);
+            self.head_define = i < head;
+            lowered.push(if tail && i + 1 == len {
+                self.lower_tail(expr, expr_tree)?
+            } else {
+                self.lower(expr, expr_tree)?
+            });
+        }
+        Ok(lowered)
+    }
+    /// lower for an expression in tail position, calls in it do not come back here
+    fn lower_tail(
+        &mut self,
+        expr: Expression,
+        tree: Option<&SpanTree>,
+    ) -> Result<SpannedProgram, TranslateError> {
+        self.tail_position = true;
+        self.lower(expr, tree)
+    }
+    fn lower_expression(
+        &mut self,
+        expr: Expression,
+        tree: Option<&SpanTree>,
+    ) -> Result<SpannedProgram, TranslateError> {
+        // Only this expression is in tail position, not the ones inside it
+        let tail = std::mem::take(&mut self.tail_position);
+        let head_define = std::mem::take(&mut self.head_define);
+        let mut instr_buf = SpannedProgram::new(self.current_span);
         match expr {
             Expression::Quote(quoted) => {
                 // Quoted should be static
@@ -169,16 +429,7 @@ impl Translator {
                     }
                 }
 
-                let static_ref_name = self.make_static_name();
-                self.static_data.insert(
-                    static_ref_name.clone(),
-                    atomtype_to_static_data(quoted.clone()),
-                );
-
-                let quoted_ref = StaticRef {
-                    refname: static_ref_name,
-                    reftype: atomtype_to_static_data(quoted),
-                };
+                let quoted_ref = self.intern_static(atomtype_to_static_data(quoted));
 
                 // Somehow take the quoted data to a register? => new Command
                 let reg = self.make_reg_name();
@@ -196,6 +447,22 @@ impl Translator {
                 // how do we accept the args into the formals?
                 // Make accept formals Instruction taking StaticRef and then a reg?
                 // We accept formals and push them to scope internally
+                let mut seen_formals = HashSet::new();
+                if let Some(formal) = formals
+                    .iter()
+                    .find(|formal| !seen_formals.insert(formal.to_string()))
+                {
+                    return Err(TranslateError::DuplicateFormal {
+                        formal: formal.to_string(),
+                        expr: Expression::Lambda(formals.clone(), body),
+                    });
+                }
+                if body.is_empty() {
+                    // Nothing would be on the stack to return
+                    return Err(TranslateError::EmptyBody {
+                        expr: Expression::Lambda(formals, body),
+                    });
+                }
                 let formals_vec = StaticData::List(
                     formals
                         .iter()
@@ -206,6 +473,7 @@ impl Translator {
                 let anon_lambda_name = self.make_anon_lambda_name();
                 self.static_data
                     .insert(anon_lambda_name.clone(), formals_vec.clone());
+                self.record_static_span(&anon_lambda_name);
 
                 let formals_vec_ref = StaticRef {
                     refname: anon_lambda_name.clone(),
@@ -213,33 +481,57 @@ impl Translator {
                 };
 
                 // Init the lambda block with the coresponding name and add content later
-                let mut lambda_block = LinearBlock {
-                    ident: anon_lambda_name.clone(),
-                    program: vec![],
-                };
+                let mut lambda_block = SpannedProgram::new(self.current_span);
 
-                lambda_block
-                    .program
-                    .push(LinearInstruction::AcceptToFormals {
-                        static_formals_list: formals_vec_ref.clone(),
-                    });
-
-                // Make body
-                let mut labmda_body = vec![];
-                body.iter().for_each(|f| {
-                    labmda_body.extend_from_slice(&self.expr_to_instructions(f.clone()))
+                lambda_block.push(LinearInstruction::AcceptToFormals {
+                    static_formals_list: formals_vec_ref.clone(),
                 });
 
-                let return_reg = self.make_reg_name();
-                labmda_body.push(LinearInstruction::PopFromStack {
-                    register: return_reg.clone(),
-                });
-                labmda_body.push(LinearInstruction::Return { value: return_reg });
+                // Formals take the first slots of the call scope, internal defines the next ones
+                self.locals
+                    .push(formals.iter().map(|formal| formal.to_string()).collect());
+                self.reserve_defines(&body);
+                // Make body, (lambda (formals) body...)
+                let lowered = self.lower_body(body, tree, true)?;
+                let tail_call = lowered
+                    .last()
+                    .is_some_and(SpannedProgram::ends_in_tail_call);
+                let last = lowered.len().saturating_sub(1);
+                // Only the last value is returned, the ones before it come off the stack
+                let discard_reg = (last > 0).then(|| self.make_reg_name());
+                for (i, lowered) in lowered.into_iter().enumerate() {
+                    lambda_block.append(lowered);
+                    if let Some(discard_reg) = discard_reg.as_ref().filter(|_| i < last) {
+                        lambda_block.push(LinearInstruction::PopFromStack {
+                            register: discard_reg.clone(),
+                        });
+                    }
+                }
+                self.locals.pop();
 
-                lambda_block.program.extend_from_slice(&labmda_body);
+                // A tail call already left the frame
+                if !tail_call {
+                    let return_reg = self.make_reg_name();
+                    lambda_block.push(LinearInstruction::PopFromStack {
+                        register: return_reg.clone(),
+                    });
+                    lambda_block.push(LinearInstruction::Return { value: return_reg });
+                }
 
-                self.lambda_map
-                    .insert(anon_lambda_name.clone(), lambda_block);
+                self.source_map.blocks.insert(
+                    anon_lambda_name.clone(),
+                    BlockSpans {
+                        span: self.current_span,
+                        instructions: lambda_block.spans,
+                    },
+                );
+                self.lambda_map.insert(
+                    anon_lambda_name.clone(),
+                    LinearBlock {
+                        ident: anon_lambda_name.clone(),
+                        program: lambda_block.program,
+                    },
+                );
 
                 // Final thing return initialized fuction pointer
                 let reg = self.make_reg_name();
@@ -261,50 +553,102 @@ impl Translator {
                 // Can internally just call and? or better just impl check here?
                 // Shoul add an instruction for checking booleans somehow?
                 // Can be done in cond instruction taking reg to check.
+                if cases
+                    .iter()
+                    .position(|(test, _)| is_else(test))
+                    .is_some_and(|i| i + 1 != cases.len())
+                {
+                    return Err(TranslateError::ElseNotLast {
+                        expr: Expression::Cond(cases),
+                    });
+                }
                 let name = self.make_cond_name();
-                for case in cases {
-                    instr_buf.extend_from_slice(&self.expr_to_instructions(case.0));
+                let has_else = cases.last().is_some_and(|(test, _)| is_else(test));
+                let cases_tested = cases.len() - usize::from(has_else);
+                for (i, case) in cases.into_iter().enumerate() {
+                    // (cond (test branch)...)
+                    let clause = span::child(tree, 1 + i);
+                    if is_else(&case.0) {
+                        // Only reached when no clause before it was taken
+                        instr_buf.append(if tail {
+                            self.lower_tail(case.1, span::child(clause, 1))?
+                        } else {
+                            self.lower(case.1, span::child(clause, 1))?
+                        });
+                        break;
+                    }
+                    instr_buf.append(self.lower(case.0, span::child(clause, 0))?);
                     let reg_to_check = self.make_reg_name();
                     instr_buf.push(LinearInstruction::PopFromStack {
                         register: reg_to_check.clone(),
                     });
 
-                    instr_buf.push(LinearInstruction::Cond {
-                        condition: reg_to_check,
-                        branc_if_true: Branch {
-                            program: self.expr_to_instructions(case.1),
+                    let branch = if tail {
+                        self.lower_tail(case.1, span::child(clause, 1))?
+                    } else {
+                        self.lower(case.1, span::child(clause, 1))?
+                    };
+                    instr_buf.push_with_branch(
+                        LinearInstruction::Cond {
+                            condition: reg_to_check,
+                            branc_if_true: Branch {
+                                program: branch.program,
+                            },
+                            cond_name: name.clone(),
                         },
-                        cond_name: name.clone(),
+                        branch.spans,
+                    );
+                }
+                if !has_else {
+                    // Nothing matched, push something anyway so every path leaves one value
+                    let void = self.intern_static(StaticData::Void);
+                    let reg = self.make_reg_name();
+                    instr_buf.push(LinearInstruction::StaticRefToRegister {
+                        static_ref: void,
+                        to_reg: reg.clone(),
                     });
+                    instr_buf.push(LinearInstruction::PushToStack { register: reg });
+                }
+                // Without a tested clause there is nothing to jump here from
+                if cases_tested > 0 {
+                    instr_buf.push(LinearInstruction::EndOfCond { cond_name: name });
                 }
-                instr_buf.push(LinearInstruction::EndOfCond {
-                    cond_name: name,
-                });
             }
             Expression::Define(global_ident, body) => {
-                // Assign to global Scope whater is the body
-                let body_instr = &self.expr_to_instructions(
-                    std::rc::Rc::<little_parser::Expression>::try_unwrap(body).unwrap(),
-                );
-                instr_buf.extend_from_slice(body_instr);
-
-                let static_ref = StaticRef {
-                    refname: self.make_static_name(),
-                    reftype: StaticData::String(global_ident),
-                };
-                self.static_data
-                    .insert(static_ref.refname.clone(), static_ref.reftype.clone());
+                // Outside of any lambda or let it is a global, at the head of a body a local
+                // letrec style, anywhere else it would be unclear which scope it goes into
+                if !self.locals.is_empty() && !head_define {
+                    return Err(TranslateError::MisplacedDefine {
+                        expr: Expression::Define(global_ident, body),
+                    });
+                }
+                // Body may still be shared if the ast was cloned, then we need our own copy
+                // (define name body)
+                let body_instr = self.lower(
+                    Rc::try_unwrap(body).unwrap_or_else(|shared| (*shared).clone()),
+                    span::child(tree, 2),
+                )?;
+                instr_buf.append(body_instr);
 
                 let reg_with_data_assigned = self.make_reg_name();
                 instr_buf.push(LinearInstruction::PopFromStack {
                     register: reg_with_data_assigned.clone(),
                 });
 
-                instr_buf.push(LinearInstruction::Assign {
-                    identifier: static_ref,
-                    from_reg: reg_with_data_assigned.clone(),
-                    scope: Scope::Current,
-                });
+                if self.locals.is_empty() {
+                    let static_ref = self.intern_static(StaticData::String(global_ident));
+                    instr_buf.push(LinearInstruction::Assign {
+                        identifier: static_ref,
+                        from_reg: reg_with_data_assigned.clone(),
+                        scope: Scope::Global,
+                    });
+                } else {
+                    instr_buf.push(LinearInstruction::StoreLocal {
+                        depth: 0,
+                        slot: self.local_slot(&global_ident),
+                        from_reg: reg_with_data_assigned.clone(),
+                    });
+                }
                 // What do we push onto the stack?! what if we define in a function? we destroy the stack balance??
                 // Just return the defines result xD
                 instr_buf.push(LinearInstruction::PushToStack {
@@ -314,161 +658,140 @@ impl Translator {
             Expression::Let(bindings, body) => {
                 // Assign bindings in order and then execute body untill last which is returned in a way
 
+                let mut seen_bindings = HashSet::new();
+                if let Some(binding) = bindings
+                    .iter()
+                    .find(|binding| !seen_bindings.insert(binding.0.clone()))
+                {
+                    return Err(TranslateError::DuplicateBinding {
+                        binding: binding.0.clone(),
+                        expr: Expression::Let(bindings.clone(), body),
+                    });
+                }
+                if body.is_empty() {
+                    return Err(TranslateError::EmptyBody {
+                        expr: Expression::Let(bindings, body),
+                    });
+                }
+
                 // Need to build new scope we push into!:
                 instr_buf.push(LinearInstruction::NewScopeAttachedToAndReplacingCurrent);
+                self.locals.push(vec![]);
 
-                for binding in bindings {
-                    instr_buf.extend_from_slice(&self.expr_to_instructions(binding.1));
+                // (let ((name value)...) body...)
+                for (i, binding) in bindings.into_iter().enumerate() {
+                    let binding_tree = span::child(span::child(tree, 1), i);
+                    instr_buf.append(self.lower(binding.1, span::child(binding_tree, 1))?);
                     let data_reg = self.make_reg_name();
                     instr_buf.push(LinearInstruction::PopFromStack {
                         register: data_reg.clone(),
                     });
 
-                    let static_ref = StaticRef {
-                        refname: self.make_static_name(),
-                        reftype: StaticData::String(binding.0.clone()),
-                    };
-                    self.static_data
-                        .insert(static_ref.refname.clone(), static_ref.reftype.clone());
-
-                    instr_buf.push(LinearInstruction::Assign {
-                        identifier: static_ref,
+                    // Only bound once its value is there, earlier bindings are visible to later ones
+                    instr_buf.push(LinearInstruction::StoreLocal {
+                        depth: 0,
+                        slot: self.local_slot(&binding.0),
                         from_reg: data_reg,
-                        scope: Scope::Current,
                     });
                 }
+                self.reserve_defines(&body);
                 let body_res_reg = self.make_reg_name();
-                for body_expr in body.iter().enumerate() {
-                    instr_buf.extend_from_slice(&self.expr_to_instructions(body_expr.1.clone()));
-                    instr_buf.push(LinearInstruction::PopFromStack {
-                        register: body_res_reg.clone(),
-                    });
+                let lowered = self.lower_body(body, tree, tail)?;
+                // After a tail call the frame and its scopes are gone, there is nothing to clean
+                let tail_call = lowered
+                    .last()
+                    .is_some_and(SpannedProgram::ends_in_tail_call);
+                let last = lowered.len() - 1;
+                for (i, lowered) in lowered.into_iter().enumerate() {
+                    instr_buf.append(lowered);
+                    if !(tail_call && i == last) {
+                        instr_buf.push(LinearInstruction::PopFromStack {
+                            register: body_res_reg.clone(),
+                        });
+                    }
                 }
-                instr_buf.push(LinearInstruction::PushToStack {
-                    register: body_res_reg,
-                });
-                // Finally clean new Scope
-                instr_buf.push(LinearInstruction::PopScopeAndReplaceWithUpper)
-            }
-            Expression::LambdaCall(mut to_call, arguments) => {
-                let to_call = std::rc::Rc::make_mut(&mut to_call).clone();
-                // Call to_call (if ident -> lookup in scope,if lambda -> Direct)
-                if let Expression::Identifier(ident) = to_call {
-                    let shared_reg = self.make_reg_name();
-                    let static_name = self.make_static_name();
-                    self.static_data
-                        .insert(static_name.clone(), StaticData::Identifier(ident.clone()));
-                    // Lookup
-                    instr_buf.push(LinearInstruction::Lookup {
-                        identifier: StaticRef {
-                            refname: static_name,
-                            reftype: StaticData::Identifier(ident),
-                        },
-                        to_reg: shared_reg.clone(),
-                        scope: Scope::Current,
-                    });
-                    instr_buf.push(LinearInstruction::PushToStack {
-                        register: shared_reg,
-                    });
-                    let args_list = self.make_reg_name();
-                    instr_buf.push(LinearInstruction::LinkedListInit {
-                        output_reg: args_list.clone(),
-                    });
+                if !tail_call {
                     instr_buf.push(LinearInstruction::PushToStack {
-                        register: args_list,
+                        register: body_res_reg,
                     });
-
-                    // For Arguments we can add them to the scope we push into?
-                    // How difficult is it to clone?
-                    // Do we know the functions aliases?
-
-                    // Converting to a linked list and a function pointer ontop of the stack
-                    instr_buf.extend_from_slice(&self.body_to_instruction_list_with_list_to_pop_from_stack_first_in_stack_is_linked_list(arguments));
-
-                    let args_list = self.make_reg_name();
-                    instr_buf.push(LinearInstruction::PopFromStack {
-                        register: args_list.clone(),
-                    });
-                    let function_pointer = self.make_reg_name();
-                    instr_buf.push(LinearInstruction::PopFromStack {
-                        register: function_pointer.clone(),
+                    // Finally clean new Scope
+                    instr_buf.push(LinearInstruction::PopScopeAndReplaceWithUpper);
+                }
+                self.locals.pop();
+            }
+            Expression::LambdaCall(to_call, arguments) => {
+                // Literals and quoted data never evaluate to a function
+                if matches!(*to_call, Expression::Atom(_) | Expression::Quote(_)) {
+                    return Err(TranslateError::NotCallable {
+                        callee: (*to_call).clone(),
+                        call: Expression::LambdaCall(to_call, arguments),
                     });
+                }
+                if let Some(op) = self.primitive(&to_call, arguments.len()) {
+                    for (i, arg) in arguments.into_iter().enumerate() {
+                        instr_buf.append(self.lower(arg, span::child(tree, 1 + i))?);
+                    }
+                    let mut operands: Vec<Register> = vec![];
+                    for _ in 0..op.arity().unwrap_or(0) {
+                        let reg = self.make_reg_name();
+                        instr_buf.push(LinearInstruction::PopFromStack {
+                            register: reg.clone(),
+                        });
+                        operands.insert(0, reg);
+                    }
                     let output_reg = self.make_reg_name();
-                    // Then call
-                    instr_buf.push(LinearInstruction::Call {
+                    instr_buf.push(LinearInstruction::PrimOp {
+                        op,
+                        arguments: operands,
                         output_reg: output_reg.clone(),
-                        function_pointer,
-                        arguments: args_list,
                     });
                     instr_buf.push(LinearInstruction::PushToStack {
                         register: output_reg,
                     });
-                } else if let Expression::Lambda(formals, body) = to_call {
-                    // Build InitializedPointer First
-                    instr_buf.extend_from_slice(
-                        &self.expr_to_instructions(Expression::Lambda(formals, body)),
-                    );
-        
-                    let args_list = self.make_reg_name();
-                    instr_buf.push(LinearInstruction::LinkedListInit {
-                        output_reg: args_list.clone(),
-                    });
-                    instr_buf.push(LinearInstruction::PushToStack {
-                        register: args_list,
-                    });
-
-
-                    instr_buf.extend_from_slice(&self.body_to_instruction_list_with_list_to_pop_from_stack_first_in_stack_is_linked_list(arguments));
+                    return Ok(instr_buf);
+                }
+                // Anything else might give us a function (identifier, lambda, call, cond, let, define)
+                // so evaluate it onto the stack like any other expression and call what we get
+                let to_call = Rc::try_unwrap(to_call).unwrap_or_else(|shared| (*shared).clone());
+                instr_buf.append(self.lower(to_call, span::child(tree, 0))?);
 
-                    // Call func with the arg list and func pointer pushed onto the stack earlier
-                    let args_list = self.make_reg_name();
-                    instr_buf.push(LinearInstruction::PopFromStack {
-                        register: args_list.clone(),
-                    });
-                    let function_pointer = self.make_reg_name();
-                    instr_buf.push(LinearInstruction::PopFromStack {
-                        register: function_pointer.clone(),
-                    });
-                    let output_reg = self.make_reg_name();
-                    // Then call
-                    instr_buf.push(LinearInstruction::Call {
-                        output_reg: output_reg.clone(),
-                        function_pointer,
-                        arguments: args_list,
-                    });
-                    instr_buf.push(LinearInstruction::PushToStack {
-                        register: output_reg,
-                    });
-                } else if let Expression::LambdaCall(formals, body) = to_call {
-                    instr_buf.extend_from_slice(
-                        &self.expr_to_instructions(Expression::LambdaCall(formals, body)),
-                    );
+                // Converting to a linked list and a function pointer ontop of the stack
+                let args_list = self.make_reg_name();
+                instr_buf.push(LinearInstruction::LinkedListInit {
+                    output_reg: args_list.clone(),
+                });
+                instr_buf.push(LinearInstruction::PushToStack {
+                    register: args_list,
+                });
 
-                    instr_buf.extend_from_slice(&self.body_to_instruction_list_with_list_to_pop_from_stack_first_in_stack_is_linked_list(arguments));
+                instr_buf.append(self.body_to_instruction_list_with_list_to_pop_from_stack_first_in_stack_is_linked_list(arguments, tree)?);
 
-                    // Call func with the arg list and func pointer pushed onto the stack earlier
-                    let args_list = self.make_reg_name();
-                    instr_buf.push(LinearInstruction::PopFromStack {
-                        register: args_list.clone(),
-                    });
-                    let function_pointer = self.make_reg_name();
-                    instr_buf.push(LinearInstruction::PopFromStack {
-                        register: function_pointer.clone(),
-                    });
-                    let output_reg = self.make_reg_name();
-                    // Then call
-                    instr_buf.push(LinearInstruction::Call {
-                        output_reg: output_reg.clone(),
+                // Call func with the arg list and func pointer pushed onto the stack earlier
+                let args_list = self.make_reg_name();
+                instr_buf.push(LinearInstruction::PopFromStack {
+                    register: args_list.clone(),
+                });
+                let function_pointer = self.make_reg_name();
+                instr_buf.push(LinearInstruction::PopFromStack {
+                    register: function_pointer.clone(),
+                });
+                if tail {
+                    // Nothing is pushed, the callee returns for us
+                    instr_buf.push(LinearInstruction::TailCall {
                         function_pointer,
                         arguments: args_list,
                     });
-                    instr_buf.push(LinearInstruction::PushToStack {
-                        register: output_reg,
-                    });
-                } else {
-                    // Should be correct?!
-                    unreachable!();
+                    return Ok(instr_buf);
                 }
+                let output_reg = self.make_reg_name();
+                instr_buf.push(LinearInstruction::Call {
+                    output_reg: output_reg.clone(),
+                    function_pointer,
+                    arguments: args_list,
+                });
+                instr_buf.push(LinearInstruction::PushToStack {
+                    register: output_reg,
+                });
             }
             Expression::Atom(atom) => {
                 // Idk is this even possible
@@ -486,16 +809,7 @@ impl Translator {
                     }
                 }
 
-                let static_ref_name = self.make_static_name();
-                self.static_data.insert(
-                    static_ref_name.clone(),
-                    atomtype_to_static_data(atom.clone()),
-                );
-
-                let atom_ref = StaticRef {
-                    refname: static_ref_name,
-                    reftype: atomtype_to_static_data(atom),
-                };
+                let atom_ref = self.intern_static(atomtype_to_static_data(atom));
 
                 // Somehow take the atom data to a register? => new Command
                 let reg = self.make_reg_name();
@@ -508,24 +822,66 @@ impl Translator {
             Expression::Identifier(ident) => {
                 // Is this possible - prob yes // probably lookup element
                 let shared_reg = self.make_reg_name();
-                let static_name = self.make_static_name();
-                self.static_data
-                    .insert(static_name.clone(), StaticData::Identifier(ident.clone()));
-                // Lookup
-                instr_buf.push(LinearInstruction::Lookup {
-                    identifier: StaticRef {
-                        refname: static_name,
-                        reftype: StaticData::Identifier(ident),
-                    },
-                    to_reg: shared_reg.clone(),
-                    scope: Scope::Current,
-                });
+                match self.resolve_local(&ident) {
+                    Some((depth, slot)) => instr_buf.push(LinearInstruction::LoadLocal {
+                        depth,
+                        slot,
+                        to_reg: shared_reg.clone(),
+                    }),
+                    // Not bound by anything around us so it can only be a global
+                    None => instr_buf.push(LinearInstruction::Lookup {
+                        identifier: self.intern_static(StaticData::Identifier(ident)),
+                        to_reg: shared_reg.clone(),
+                        scope: Scope::Global,
+                    }),
+                }
                 instr_buf.push(LinearInstruction::PushToStack {
                     register: shared_reg,
                 });
             }
         }
-        instr_buf
+        Ok(instr_buf)
+    }
+    /// Builtin a call to `callee` with `args` arguments can use a PrimOp for
+    fn primitive(&self, callee: &Expression, args: usize) -> Option<Builtin> {
+        match callee {
+            Expression::Identifier(name) if !self.rebound.contains(name) => {
+                Builtin::from_name(name).filter(|op| op.arity() == Some(args))
+            }
+            _ => None,
+        }
+    }
+    /// Depth and slot of the innermost local named `name`, None if it is free
+    fn resolve_local(&self, name: &str) -> Option<(usize, usize)> {
+        self.locals
+            .iter()
+            .rev()
+            .enumerate()
+            .find_map(|(depth, scope)| {
+                let slot = scope.iter().position(|local| local == name)?;
+                Some((depth, slot))
+            })
+    }
+    /// Slot of `name` in the current scope, given the next free one if it has none yet
+    fn local_slot(&mut self, name: &str) -> usize {
+        let scope = self
+            .locals
+            .last_mut()
+            .expect("only called inside a lambda or let");
+        match scope.iter().position(|local| local == name) {
+            Some(slot) => slot,
+            None => {
+                scope.push(name.to_string());
+                scope.len() - 1
+            }
+        }
+    }
+    /// Gives the defines at the head of `body` their slots up front, like a letrec every value
+    /// can refer to all of them. Reading one before its define ran is an error at runtime.
+    fn reserve_defines(&mut self, body: &[Expression]) {
+        for name in head_defines(body) {
+            self.local_slot(name);
+        }
     }
     fn make_reg_name(&mut self) -> Register {
         let temp = Register {
@@ -542,20 +898,52 @@ impl Translator {
     fn make_static_name(&mut self) -> String {
         let temp = "static".to_owned() + &self.static_data_counter.to_string();
         self.static_data_counter += 1;
+        self.record_static_span(&temp);
         temp
     }
+    /// Reuses the entry of an equal static if there is one, lambda formals are not interned
+    fn intern_static(&mut self, data: StaticData) -> StaticRef {
+        self.static_requests += 1;
+        let refname = match self.interned.get(&data) {
+            Some(refname) => refname.clone(),
+            None => {
+                let refname = self.make_static_name();
+                self.static_data.insert(refname.clone(), data.clone());
+                self.interned.insert(data.clone(), refname.clone());
+                refname
+            }
+        };
+        StaticRef {
+            refname,
+            reftype: data,
+        }
+    }
+    /// Size of the static table with and without interning
+    pub fn static_stats(&self) -> StaticStats {
+        StaticStats {
+            requested: self.static_requests + self.anon_lambda_counter,
+            stored: self.static_data.len(),
+        }
+    }
+    fn record_static_span(&mut self, refname: &str) {
+        if let Some(span) = self.current_span {
+            self.source_map.statics.insert(refname.to_string(), span);
+        }
+    }
     fn make_cond_name(&mut self) -> String {
-        let temp = "cond".to_owned() + &self.static_data_counter.to_string();
+        let temp = "cond".to_owned() + &self.cond_name_counter.to_string();
         self.cond_name_counter += 1;
         temp
     }
     fn body_to_instruction_list_with_list_to_pop_from_stack_first_in_stack_is_linked_list(
         &mut self,
         body: Vec<Expression>,
-    ) -> Vec<LinearInstruction> {
-        let mut instr_buf = vec![];
-        for arg in body {
-            instr_buf.extend_from_slice(&self.expr_to_instructions(arg));
+        call_tree: Option<&SpanTree>,
+    ) -> Result<SpannedProgram, TranslateError> {
+        let mut instr_buf = SpannedProgram::new(self.current_span);
+        // (callee args...)
+        for (i, arg) in body.into_iter().enumerate() {
+            instr_buf.append(self.lower(arg, span::child(call_tree, 1 + i))?);
 
             let to_add_reg = self.make_reg_name();
             instr_buf.push(LinearInstruction::PopFromStack {
@@ -568,17 +956,112 @@ impl Translator {
             instr_buf.push(LinearInstruction::LinkedListAdd {
                 linked_list_reg: args_list.clone(),
                 input_reg: to_add_reg.clone(),
+                output_reg: args_list.clone(),
             });
             instr_buf.push(LinearInstruction::PushToStack {
                 register: args_list.clone(),
             });
         }
-        instr_buf
+        Ok(instr_buf)
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Every name `expr` defines, binds with let or takes as a formal, wherever it is
+fn collect_bound_names(expr: &Expression, names: &mut HashSet<String>) {
+    match expr {
+        Expression::Define(name, body) => {
+            names.insert(name.clone());
+            collect_bound_names(body, names);
+        }
+        Expression::Let(bindings, body) => {
+            for (name, value) in bindings {
+                names.insert(name.clone());
+                collect_bound_names(value, names);
+            }
+            body.iter()
+                .for_each(|expr| collect_bound_names(expr, names));
+        }
+        Expression::Lambda(formals, body) => {
+            names.extend(formals.iter().map(|formal| formal.to_string()));
+            body.iter()
+                .for_each(|expr| collect_bound_names(expr, names));
+        }
+        Expression::LambdaCall(callee, arguments) => {
+            collect_bound_names(callee, names);
+            arguments
+                .iter()
+                .for_each(|expr| collect_bound_names(expr, names));
+        }
+        Expression::Cond(cases) => {
+            for (test, branch) in cases {
+                collect_bound_names(test, names);
+                collect_bound_names(branch, names);
+            }
+        }
+        Expression::Quote(_) | Expression::Atom(_) | Expression::Identifier(_) => {}
+    }
+}
+
+/// `else` as the test of a cond clause, it is not looked up
+fn is_else(test: &Expression) -> bool {
+    matches!(test, Expression::Identifier(name) if name == "else")
+}
+
+/// Names defined by the defines a body starts with
+fn head_defines(body: &[Expression]) -> impl Iterator<Item = &String> {
+    body.iter().map_while(|expr| match expr {
+        Expression::Define(name, _) => Some(name),
+        _ => None,
+    })
+}
+
+/// Programs the Translator cannot lower, carrying the offending expression
+#[derive(Debug, Clone)]
+pub enum TranslateError {
+    /// Callee of a LambdaCall that can never evaluate to a function, like a literal or quoted data
+    NotCallable {
+        callee: Expression,
+        call: Expression,
+    },
+    /// Lambda or Let without body expressions, there would be nothing to return
+    EmptyBody { expr: Expression },
+    DuplicateFormal { formal: String, expr: Expression },
+    DuplicateBinding { binding: String, expr: Expression },
+    /// Define inside a lambda or let that is not at the head of its body
+    MisplacedDefine { expr: Expression },
+    /// Cond with clauses after its else clause, they could never be taken
+    ElseNotLast { expr: Expression },
+}
+
+impl fmt::Display for TranslateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TranslateError::NotCallable { callee, call } => {
+                write!(f, "{:?} is not callable in {:?}", callee, call)
+            }
+            TranslateError::EmptyBody { expr } => write!(f, "empty body in {:?}", expr),
+            TranslateError::DuplicateFormal { formal, expr } => {
+                write!(f, "formal `{}` appears twice in {:?}", formal, expr)
+            }
+            TranslateError::DuplicateBinding { binding, expr } => {
+                write!(f, "`{}` is bound twice in {:?}", binding, expr)
+            }
+            TranslateError::MisplacedDefine { expr } => {
+                write!(f, "{:?} is not at the head of a body", expr)
+            }
+            TranslateError::ElseNotLast { expr } => {
+                write!(f, "else is not the last clause in {:?}", expr)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TranslateError {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum StaticData {
+    /// What a cond gives when no clause matches
+    Void,
     Bool(bool),
     Integer(i32),
     String(String),
@@ -586,11 +1069,31 @@ pub enum StaticData {
     List(Vec<StaticData>),
 }
 
+impl StaticData {
+    /// Global defines store their names as String, lookups as Identifier so accept both
+    pub fn as_name(&self) -> Option<&str> {
+        match self {
+            StaticData::Identifier(name) | StaticData::String(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use little_parser::Parser;
 
-    use crate::{LinearBlock, LinearInstruction, Register, StaticData, StaticRef, Translator};
+    use std::rc::Rc;
+
+    use little_parser::{AtomTypes, Expression, Programm};
+
+    use crate::{
+        test_ast::{boolean, call, ident, int, lambda},
+        verify::verify_module,
+        vm::Vm,
+        LinearBlock, LinearInstruction, Register, Scope, StaticData, StaticRef, TranslateError,
+        Translator,
+    };
 
     #[test]
     fn it_works_init_1() {
@@ -599,7 +1102,7 @@ mod tests {
 
         let mut translator = Translator::default();
 
-        let incomplete_res = translator.ast_to_intermediate_representation(ast);
+        let incomplete_res = translator.ast_to_intermediate_representation(ast).unwrap();
 
         println!("translator: {:#?}", translator);
 
@@ -627,4 +1130,364 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn bad_programs_are_errors() {
+        let quoted_callee = call(Expression::Quote(AtomTypes::Integer(5)), vec![]);
+        let res = Translator::default()
+            .ast_to_intermediate_representation(Programm::Expression(vec![quoted_callee]));
+        assert!(matches!(res, Err(TranslateError::NotCallable { .. })));
+
+        let duplicate_formals = lambda(&["x", "x"], vec![ident("x")]);
+        let res = Translator::default()
+            .ast_to_intermediate_representation(Programm::Expression(vec![duplicate_formals]));
+        assert!(
+            matches!(res, Err(TranslateError::DuplicateFormal { ref formal, .. }) if formal == "x")
+        );
+
+        let empty_let = Expression::Let(vec![], vec![]);
+        let res = Translator::default()
+            .ast_to_intermediate_representation(Programm::Expression(vec![empty_let]));
+        assert!(matches!(res, Err(TranslateError::EmptyBody { .. })));
+    }
+
+    #[test]
+    fn shared_define_body_and_let_callee() {
+        // The ast is cloned so the Rc in Define is shared and cannot be unwrapped
+        let body = Rc::new(int(2));
+        let define = Expression::Define("two".into(), body.clone());
+        // ((let () (lambda (x) x)) two)
+        let applied = call(
+            Expression::Let(vec![], vec![lambda(&["x"], vec![ident("x")])]),
+            vec![ident("two")],
+        );
+        let module = Translator::default()
+            .translate_module(Programm::Expression(vec![define, applied]))
+            .unwrap();
+        assert_eq!(
+            Vm::new(&module.blocks).run("main").unwrap().to_string(),
+            "2"
+        );
+    }
+
+    #[test]
+    fn repeated_statics_are_interned() {
+        // (list 1 1) (list 1 1) "list", list has no PrimOp so it is looked up
+        let list = call(ident("list"), vec![int(1), int(1)]);
+        let string = Expression::Atom(AtomTypes::String("list".into()));
+        let mut translator = Translator::default();
+        let module = translator
+            .translate_module(Programm::Expression(vec![list.clone(), list, string]))
+            .unwrap();
+        // `list` as identifier and as string, and 1
+        assert_eq!(module.static_data.len(), 3);
+        let stats = translator.static_stats();
+        assert_eq!(stats.requested, 7);
+        assert_eq!(stats.stored, 3);
+        assert_eq!(stats.saved(), 4);
+        assert_eq!(
+            Vm::new(&module.blocks).run("main").unwrap().to_string(),
+            "\"list\""
+        );
+    }
+
+    #[test]
+    fn builtin_calls_become_prim_ops() {
+        let count = |program: &[LinearInstruction]| {
+            let prims = program
+                .iter()
+                .filter(|instr| matches!(instr, LinearInstruction::PrimOp { .. }))
+                .count();
+            let calls = program
+                .iter()
+                .filter(|instr| matches!(instr, LinearInstruction::Call { .. }))
+                .count();
+            (prims, calls)
+        };
+
+        // (cons (- 7 2) (car (list 1)))
+        let exprs = vec![call(
+            ident("cons"),
+            vec![
+                call(ident("-"), vec![int(7), int(2)]),
+                call(ident("car"), vec![call(ident("list"), vec![int(1)])]),
+            ],
+        )];
+        let module = Translator::default()
+            .translate_module(Programm::Expression(exprs))
+            .unwrap();
+        // list takes any number of arguments and stays a call
+        assert_eq!(count(&module.entry_block().program), (3, 1));
+        assert_eq!(
+            Vm::new(&module.blocks).run("main").unwrap().to_string(),
+            "(5 . 1)"
+        );
+
+        // (define - +) (- 7 2)
+        let exprs = vec![
+            Expression::Define("-".into(), Rc::new(ident("+"))),
+            call(ident("-"), vec![int(7), int(2)]),
+        ];
+        let module = Translator::default()
+            .translate_module(Programm::Expression(exprs))
+            .unwrap();
+        assert_eq!(count(&module.entry_block().program), (0, 1));
+        assert_eq!(
+            Vm::new(&module.blocks).run("main").unwrap().to_string(),
+            "9"
+        );
+    }
+
+    #[test]
+    fn locals_are_addressed_lexically() {
+        // (define g 10)
+        // ((lambda (x) (let ((y x) (x 3)) (list x y g))) 1)
+        let body = Expression::Let(
+            vec![("y".into(), ident("x")), ("x".into(), int(3))],
+            vec![call(
+                ident("list"),
+                vec![ident("x"), ident("y"), ident("g")],
+            )],
+        );
+        let exprs = vec![
+            Expression::Define("g".into(), Rc::new(int(10))),
+            call(lambda(&["x"], vec![body]), vec![int(1)]),
+        ];
+        let module = Translator::default()
+            .translate_module(Programm::Expression(exprs))
+            .unwrap();
+
+        let mut loads = vec![];
+        let mut lookups = vec![];
+        for instr in &module.block("_0").unwrap().program {
+            match instr {
+                LinearInstruction::LoadLocal { depth, slot, .. } => loads.push((*depth, *slot)),
+                LinearInstruction::Lookup {
+                    identifier, scope, ..
+                } => lookups.push((identifier.reftype.clone(), scope.clone())),
+                _ => {}
+            }
+        }
+        // The first x is the formal one level up, the second the let binding shadowing it
+        assert_eq!(loads, [(1, 0), (0, 1), (0, 0)]);
+        assert_eq!(
+            lookups,
+            [
+                (StaticData::Identifier("list".into()), Scope::Global),
+                (StaticData::Identifier("g".into()), Scope::Global)
+            ]
+        );
+        assert_eq!(
+            Vm::new(&module.blocks).run("main").unwrap().to_string(),
+            "(3 1 10)"
+        );
+    }
+
+    #[test]
+    fn defines_are_global_at_top_and_letrec_in_bodies() {
+        // (lambda (n) (cond ((= n 0) base) (#t (other (- n 1)))))
+        let step = |base: bool, other: &str| {
+            lambda(
+                &["n"],
+                vec![Expression::Cond(vec![
+                    (call(ident("="), vec![ident("n"), int(0)]), boolean(base)),
+                    (
+                        boolean(true),
+                        call(ident(other), vec![call(ident("-"), vec![ident("n"), int(1)])]),
+                    ),
+                ])],
+            )
+        };
+        // (define (parity n) (define even? ...) (define odd? ...) (even? n))
+        // (parity 7)
+        let parity = lambda(
+            &["n"],
+            vec![
+                Expression::Define("even?".into(), Rc::new(step(true, "odd?"))),
+                Expression::Define("odd?".into(), Rc::new(step(false, "even?"))),
+                call(ident("even?"), vec![ident("n")]),
+            ],
+        );
+        let exprs = vec![
+            Expression::Define("parity".into(), Rc::new(parity)),
+            call(ident("parity"), vec![int(7)]),
+        ];
+        let module = Translator::default()
+            .translate_module(Programm::Expression(exprs.clone()))
+            .unwrap();
+        let assigns = |ident: &str| -> Vec<Scope> {
+            let program = &module.block(ident).unwrap().program;
+            program
+                .iter()
+                .filter_map(|instr| match instr {
+                    LinearInstruction::Assign { scope, .. } => Some(scope.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(assigns("main"), [Scope::Global]);
+        // Both internal defines see each other and stay local to the call
+        assert_eq!(assigns("_0"), []);
+        assert_eq!(verify_module(&module), Ok(()));
+        assert_eq!(run_value(exprs.clone()), "#f");
+        let mut leaks = exprs;
+        leaks.push(ident("odd?"));
+        let module = Translator::default()
+            .translate_module(Programm::Expression(leaks))
+            .unwrap();
+        assert!(Vm::new(&module.blocks).run("main").is_err());
+
+        // Values before the last one in a body are dropped
+        // ((lambda () (define x 1) x)) ((lambda () 1 2))
+        for (body, value) in [
+            (
+                vec![Expression::Define("x".into(), Rc::new(int(1))), ident("x")],
+                "1",
+            ),
+            (vec![int(1), int(2)], "2"),
+        ] {
+            let exprs = vec![call(lambda(&[], body), vec![])];
+            let module = Translator::default()
+                .translate_module(Programm::Expression(exprs.clone()))
+                .unwrap();
+            assert_eq!(verify_module(&module), Ok(()));
+            assert_eq!(run_value(exprs), value);
+        }
+
+        // ((lambda () 1 (define x 2)))
+        let late = lambda(
+            &[],
+            vec![int(1), Expression::Define("x".into(), Rc::new(int(2)))],
+        );
+        let res = Translator::default()
+            .ast_to_intermediate_representation(Programm::Expression(vec![call(late, vec![])]));
+        assert!(matches!(res, Err(TranslateError::MisplacedDefine { .. })));
+    }
+
+    #[test]
+    fn cond_leaves_one_value_on_every_path() {
+        // ((lambda (x) (cond ((< x 0) 0) (else x))) 5)
+        let clamp = lambda(
+            &["x"],
+            vec![Expression::Cond(vec![
+                (call(ident("<"), vec![ident("x"), int(0)]), int(0)),
+                (ident("else"), ident("x")),
+            ])],
+        );
+        let exprs = vec![call(clamp, vec![int(5)])];
+        let module = Translator::default()
+            .translate_module(Programm::Expression(exprs.clone()))
+            .unwrap();
+        assert_eq!(verify_module(&module), Ok(()));
+        assert_eq!(run_value(exprs), "5");
+
+        // (list (cond (#f 1)) (cond) 3)
+        let exprs = vec![call(
+            ident("list"),
+            vec![
+                Expression::Cond(vec![(boolean(false), int(1))]),
+                Expression::Cond(vec![]),
+                int(3),
+            ],
+        )];
+        let module = Translator::default()
+            .translate_module(Programm::Expression(exprs.clone()))
+            .unwrap();
+        assert_eq!(verify_module(&module), Ok(()));
+        assert_eq!(run_value(exprs), "(#<void> #<void> 3)");
+
+        // (cond (else 1) (#t 2))
+        let res =
+            Translator::default().ast_to_intermediate_representation(Programm::Expression(vec![
+                Expression::Cond(vec![(ident("else"), int(1)), (boolean(true), int(2))]),
+            ]));
+        assert!(matches!(res, Err(TranslateError::ElseNotLast { .. })));
+    }
+
+    #[test]
+    fn nothing_follows_a_tail_call() {
+        // (define g (lambda (x) x)) ((lambda (n) (let ((m n)) (g m))) 5)
+        let exprs = vec![
+            Expression::Define("g".into(), Rc::new(lambda(&["x"], vec![ident("x")]))),
+            call(
+                lambda(
+                    &["n"],
+                    vec![Expression::Let(
+                        vec![("m".into(), ident("n"))],
+                        vec![call(ident("g"), vec![ident("m")])],
+                    )],
+                ),
+                vec![int(5)],
+            ),
+        ];
+        let module = Translator::default()
+            .translate_module(Programm::Expression(exprs.clone()))
+            .unwrap();
+        let tail_calls = |block: &LinearBlock| {
+            block
+                .program
+                .iter()
+                .position(|instr| matches!(instr, LinearInstruction::TailCall { .. }))
+        };
+        let lambda = module
+            .iter_lambdas()
+            .find(|block| tail_calls(block).is_some())
+            .unwrap();
+        assert_eq!(
+            tail_calls(lambda),
+            Some(lambda.program.len() - 1),
+            "{}",
+            lambda
+        );
+        assert_eq!(verify_module(&module), Ok(()));
+        assert_eq!(run_value(exprs), "5");
+    }
+
+    fn run_value(exprs: Vec<Expression>) -> String {
+        let module = Translator::default()
+            .translate_module(Programm::Expression(exprs))
+            .unwrap();
+        Vm::new(&module.blocks).run("main").unwrap().to_string()
+    }
+
+    #[test]
+    fn every_callee_shape() {
+        // (+ 1 2)
+        assert_eq!(run_value(vec![call(ident("+"), vec![int(1), int(2)])]), "3");
+        // ((lambda (x) x) 4)
+        assert_eq!(
+            run_value(vec![call(lambda(&["x"], vec![ident("x")]), vec![int(4)])]),
+            "4"
+        );
+        // (((lambda (x) (lambda (y) (- x y))) 10) 3)
+        let curried = lambda(
+            &["x"],
+            vec![lambda(
+                &["y"],
+                vec![call(ident("-"), vec![ident("x"), ident("y")])],
+            )],
+        );
+        assert_eq!(
+            run_value(vec![call(call(curried, vec![int(10)]), vec![int(3)])]),
+            "7"
+        );
+        // ((cond (#f -) (#t *)) 2 5)
+        let choose = Expression::Cond(vec![
+            (boolean(false), ident("-")),
+            (boolean(true), ident("*")),
+        ]);
+        assert_eq!(run_value(vec![call(choose, vec![int(2), int(5)])]), "10");
+        // ((let ((n 1)) (lambda (x) (+ x n))) 6)
+        let adder = Expression::Let(
+            vec![("n".into(), int(1))],
+            vec![lambda(
+                &["x"],
+                vec![call(ident("+"), vec![ident("x"), ident("n")])],
+            )],
+        );
+        assert_eq!(run_value(vec![call(adder, vec![int(6)])]), "7");
+        // ((define id (lambda (x) x)) 8)
+        let define = Expression::Define("id".into(), Rc::new(lambda(&["x"], vec![ident("x")])));
+        assert_eq!(run_value(vec![call(define, vec![int(8)])]), "8");
+    }
 }
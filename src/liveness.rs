@@ -0,0 +1,364 @@
+//! Def-use information and liveness.
+//! `uses` and `defs` say which registers an instruction reads and writes so passes do not need
+//! their own match over every variant. A Cond only reads its condition here, its branch is a
+//! program of its own. `liveness` computes live-in and live-out sets for every instruction of
+//! a block, addressed by the same paths the verifier reports.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{LinearBlock, LinearInstruction, Register, Scope};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Read,
+    Write,
+}
+
+/// One table for both the shared and the mutable accessors, binding modes do the rest
+macro_rules! operands {
+    ($instr:expr) => {
+        match $instr {
+            LinearInstruction::AcceptToFormals { .. }
+            | LinearInstruction::NewScopeAttachedToAndReplacingCurrent
+            | LinearInstruction::PopScopeAndReplaceWithUpper
+            | LinearInstruction::EndOfCond { .. }
+            | LinearInstruction::Label { .. }
+            | LinearInstruction::Jump { .. } => vec![],
+            LinearInstruction::PushToStack { register }
+            | LinearInstruction::Cond {
+                condition: register,
+                ..
+            }
+            | LinearInstruction::Return { value: register }
+            | LinearInstruction::SpillToSlot {
+                from_reg: register, ..
+            }
+            | LinearInstruction::StoreLocal {
+                from_reg: register, ..
+            }
+            | LinearInstruction::JumpIfFalse {
+                condition: register,
+                ..
+            } => vec![(register, Access::Read)],
+            LinearInstruction::StaticRefToRegister { to_reg, .. }
+            | LinearInstruction::PopFromStack { register: to_reg }
+            | LinearInstruction::LinkedListInit { output_reg: to_reg }
+            | LinearInstruction::LoadCapture { to_reg, .. }
+            | LinearInstruction::LoadLocal { to_reg, .. }
+            | LinearInstruction::ReloadFromSlot { to_reg, .. } => vec![(to_reg, Access::Write)],
+            LinearInstruction::LinkedListAdd {
+                linked_list_reg,
+                input_reg,
+                output_reg,
+            } => vec![
+                (linked_list_reg, Access::Read),
+                (input_reg, Access::Read),
+                (output_reg, Access::Write),
+            ],
+            LinearInstruction::Assign {
+                from_reg, scope, ..
+            } => {
+                let mut operands = vec![(from_reg, Access::Read)];
+                if let Scope::Custom(register) = scope {
+                    operands.push((register, Access::Read));
+                }
+                operands
+            }
+            LinearInstruction::Call {
+                output_reg,
+                function_pointer,
+                arguments,
+            } => vec![
+                (function_pointer, Access::Read),
+                (arguments, Access::Read),
+                (output_reg, Access::Write),
+            ],
+            LinearInstruction::Lookup { to_reg, scope, .. }
+            | LinearInstruction::InitializeFunctionPointer {
+                outpu_reg: to_reg,
+                from_scope: scope,
+                ..
+            } => {
+                let mut operands = vec![];
+                if let Scope::Custom(register) = scope {
+                    operands.push((register, Access::Read));
+                }
+                operands.push((to_reg, Access::Write));
+                operands
+            }
+            LinearInstruction::Move { from_reg, to_reg } => {
+                vec![(from_reg, Access::Read), (to_reg, Access::Write)]
+            }
+            LinearInstruction::TailCall {
+                function_pointer,
+                arguments,
+            } => vec![(function_pointer, Access::Read), (arguments, Access::Read)],
+            LinearInstruction::Phi {
+                inputs, output_reg, ..
+            } => IntoIterator::into_iter(inputs)
+                .map(|(_, input)| (input, Access::Read))
+                .chain(std::iter::once((output_reg, Access::Write)))
+                .collect(),
+            LinearInstruction::PrimOp {
+                arguments,
+                output_reg,
+                ..
+            }
+            | LinearInstruction::MakeClosure {
+                captures: arguments,
+                output_reg,
+                ..
+            } => IntoIterator::into_iter(arguments)
+                .map(|argument| (argument, Access::Read))
+                .chain(std::iter::once((output_reg, Access::Write)))
+                .collect(),
+        }
+    };
+}
+
+impl LinearInstruction {
+    /// Registers read
+    pub fn uses(&self) -> Vec<&Register> {
+        let operands: Vec<(&Register, Access)> = operands!(self);
+        operands
+            .into_iter()
+            .filter(|(_, access)| *access != Access::Write)
+            .map(|(register, _)| register)
+            .collect()
+    }
+    /// Registers written
+    pub fn defs(&self) -> Vec<&Register> {
+        let operands: Vec<(&Register, Access)> = operands!(self);
+        operands
+            .into_iter()
+            .filter(|(_, access)| *access != Access::Read)
+            .map(|(register, _)| register)
+            .collect()
+    }
+    pub fn uses_mut(&mut self) -> Vec<&mut Register> {
+        let operands: Vec<(&mut Register, Access)> = operands!(self);
+        operands
+            .into_iter()
+            .filter(|(_, access)| *access != Access::Write)
+            .map(|(register, _)| register)
+            .collect()
+    }
+    pub fn defs_mut(&mut self) -> Vec<&mut Register> {
+        let operands: Vec<(&mut Register, Access)> = operands!(self);
+        operands
+            .into_iter()
+            .filter(|(_, access)| *access != Access::Read)
+            .map(|(register, _)| register)
+            .collect()
+    }
+    /// Every register field once, uses first, for renaming
+    pub fn registers_mut(&mut self) -> Vec<&mut Register> {
+        let operands: Vec<(&mut Register, Access)> = operands!(self);
+        operands.into_iter().map(|(register, _)| register).collect()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LiveSets {
+    pub live_in: HashSet<Register>,
+    pub live_out: HashSet<Register>,
+}
+
+/// Live registers around every instruction of a block.
+/// Phis count as reading all their inputs at the join.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Liveness {
+    sets: HashMap<Vec<usize>, LiveSets>,
+}
+
+impl Liveness {
+    /// `path` is the index into the block followed by indices into nested Cond branches
+    pub fn at(&self, path: &[usize]) -> Option<&LiveSets> {
+        self.sets.get(path)
+    }
+    pub fn live_in(&self, path: &[usize]) -> Option<&HashSet<Register>> {
+        Some(&self.at(path)?.live_in)
+    }
+    pub fn live_out(&self, path: &[usize]) -> Option<&HashSet<Register>> {
+        Some(&self.at(path)?.live_out)
+    }
+}
+
+pub fn liveness(block: &LinearBlock) -> Liveness {
+    // Live sets at each label of a flattened block, a jump back to a label needs another pass
+    let mut labels = HashMap::new();
+    loop {
+        let mut liveness = Liveness::default();
+        let before = labels.clone();
+        analyze(
+            &block.program,
+            &[],
+            HashSet::new(),
+            &mut labels,
+            &mut liveness,
+        );
+        if labels == before {
+            return liveness;
+        }
+    }
+}
+
+/// Walks `program` backwards from `exit`, the live set after its last instruction.
+/// Returns the live set before its first one. Without jumps back one pass is enough.
+fn analyze(
+    program: &[LinearInstruction],
+    prefix: &[usize],
+    exit: HashSet<Register>,
+    labels: &mut HashMap<String, HashSet<Register>>,
+    liveness: &mut Liveness,
+) -> HashSet<Register> {
+    let mut live = exit;
+    // What is live right after each EndOfCond, where taken branches continue
+    let mut after_join: HashMap<&str, HashSet<Register>> = HashMap::new();
+    for (index, instr) in program.iter().enumerate().rev() {
+        let mut path = prefix.to_vec();
+        path.push(index);
+        let live_out = match instr {
+            LinearInstruction::Return { .. } | LinearInstruction::TailCall { .. } => HashSet::new(),
+            LinearInstruction::EndOfCond { cond_name } => {
+                after_join.insert(cond_name, live.clone());
+                live
+            }
+            LinearInstruction::Cond {
+                cond_name,
+                branc_if_true,
+                ..
+            } => {
+                let join = after_join
+                    .get(cond_name.as_str())
+                    .cloned()
+                    .unwrap_or_default();
+                let taken = analyze(&branc_if_true.program, &path, join, labels, liveness);
+                live.union(&taken).cloned().collect()
+            }
+            LinearInstruction::Label { name } => {
+                labels.insert(name.clone(), live.clone());
+                live
+            }
+            LinearInstruction::Jump { label } => labels.get(label).cloned().unwrap_or_default(),
+            LinearInstruction::JumpIfFalse { label, .. } => {
+                let taken = labels.get(label).cloned().unwrap_or_default();
+                live.union(&taken).cloned().collect()
+            }
+            _ => live,
+        };
+        let mut live_in = live_out.clone();
+        for register in instr.defs() {
+            live_in.remove(register);
+        }
+        live_in.extend(instr.uses().into_iter().cloned());
+        live = live_in.clone();
+        liveness.sets.insert(path, LiveSets { live_in, live_out });
+    }
+    live
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::liveness;
+    use crate::{asm::parse_program, LinearBlock, LinearInstruction, Register};
+
+    fn set(names: &[&str]) -> HashSet<Register> {
+        names
+            .iter()
+            .map(|name| Register {
+                virtual_ident: name.to_string(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn uses_and_defs() {
+        let program = parse_program(
+            "list.add vreg0 <- vreg1 -> vreg6
+             call vreg2 vreg0 -> vreg3
+             lookup static0{x} -> vreg4 @[vreg5]",
+        )
+        .unwrap();
+        let names = |registers: Vec<&Register>| -> Vec<String> {
+            registers
+                .into_iter()
+                .map(|register| register.virtual_ident.clone())
+                .collect()
+        };
+        assert_eq!(names(program[0].uses()), ["vreg0", "vreg1"]);
+        assert_eq!(names(program[0].defs()), ["vreg6"]);
+        assert_eq!(names(program[1].uses()), ["vreg2", "vreg0"]);
+        assert_eq!(names(program[1].defs()), ["vreg3"]);
+        assert_eq!(names(program[2].uses()), ["vreg5"]);
+        assert_eq!(names(program[2].defs()), ["vreg4"]);
+    }
+
+    #[test]
+    fn live_through_conds() {
+        let block = LinearBlock {
+            ident: "test".into(),
+            program: parse_program(
+                "load static0{1} -> vreg0
+                 load static1{#t} -> vreg1
+                 cond0: if vreg1 {
+                     ret vreg1
+                 }
+                 cond0: if vreg1 {
+                     load static2{2} -> vreg0
+                 }
+                 load static3{3} -> vreg2
+                 cond0: end
+                 push vreg0",
+            )
+            .unwrap(),
+        };
+        let live = liveness(&block);
+        assert_eq!(live.live_out(&[0]), Some(&set(&["vreg0"])));
+        // vreg0 is overwritten in the second clause but still needed if it is not taken
+        assert_eq!(live.live_in(&[2]), Some(&set(&["vreg0", "vreg1"])));
+        assert_eq!(live.live_out(&[2, 0]), Some(&set(&[])));
+        assert_eq!(live.live_in(&[3, 0]), Some(&set(&[])));
+        assert_eq!(live.live_out(&[3, 0]), Some(&set(&["vreg0"])));
+        // vreg2 is never read
+        assert_eq!(live.live_out(&[4]), Some(&set(&["vreg0"])));
+        assert!(matches!(
+            block.program[5],
+            LinearInstruction::EndOfCond { .. }
+        ));
+        assert_eq!(live.live_in(&[6]), Some(&set(&["vreg0"])));
+    }
+
+    #[test]
+    fn follows_jumps() {
+        let block = |src: &str| LinearBlock {
+            ident: "test".into(),
+            program: parse_program(src).unwrap(),
+        };
+        let skip = block(
+            "load static0{1} -> vreg0
+             load static1{#f} -> vreg1
+             jump.false vreg1 skip
+             load static2{2} -> vreg0
+             label skip
+             push vreg0",
+        );
+        let live = liveness(&skip);
+        // The jump goes around the second write
+        assert_eq!(live.live_out(&[0]), Some(&set(&["vreg0"])));
+        assert_eq!(live.live_out(&[2]), Some(&set(&["vreg0"])));
+        assert_eq!(live.live_in(&[3]), Some(&set(&[])));
+
+        let back = block(
+            "label top
+             push vreg0
+             load static0{1} -> vreg0
+             jump top",
+        );
+        let live = liveness(&back);
+        assert_eq!(live.live_out(&[2]), Some(&set(&["vreg0"])));
+        assert_eq!(live.live_in(&[0]), Some(&set(&["vreg0"])));
+    }
+}
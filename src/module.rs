@@ -0,0 +1,108 @@
+//! Whole program output of the Translator.
+//! Bundles main, every lambda block and the static table so later stages do not need to keep
+//! the Translator around.
+
+use std::collections::HashMap;
+
+use crate::{span::SourceMap, LinearBlock, StaticData};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IrModule {
+    /// Name of the block execution starts in, `main` for translated programs
+    pub entry: String,
+    /// Entry block and every lambda, keyed by their ident
+    pub blocks: HashMap<String, LinearBlock>,
+    pub static_data: HashMap<String, StaticData>,
+    pub metadata: ModuleMetadata,
+    /// Empty unless translated with source
+    pub source_map: SourceMap,
+}
+
+/// Counter state of the Translator that produced the module.
+/// Passes that need fresh names can start counting from here.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ModuleMetadata {
+    pub register_count: usize,
+    pub lambda_count: usize,
+    pub static_count: usize,
+    pub cond_count: usize,
+}
+
+impl IrModule {
+    pub fn entry_block(&self) -> &LinearBlock {
+        &self.blocks[&self.entry]
+    }
+    pub fn block(&self, ident: &str) -> Option<&LinearBlock> {
+        self.blocks.get(ident)
+    }
+    pub fn block_mut(&mut self, ident: &str) -> Option<&mut LinearBlock> {
+        self.blocks.get_mut(ident)
+    }
+    /// Entry block first, then the rest sorted by ident so output is deterministic
+    pub fn iter_blocks(&self) -> impl Iterator<Item = &LinearBlock> {
+        let mut lambdas: Vec<&LinearBlock> = self
+            .blocks
+            .values()
+            .filter(|block| block.ident != self.entry)
+            .collect();
+        lambdas.sort_by(|a, b| a.ident.cmp(&b.ident));
+        self.blocks.get(&self.entry).into_iter().chain(lambdas)
+    }
+    /// Every block except the entry block
+    pub fn iter_lambdas(&self) -> impl Iterator<Item = &LinearBlock> {
+        self.iter_blocks().skip(1)
+    }
+    pub fn lookup_static(&self, refname: &str) -> Option<&StaticData> {
+        self.static_data.get(refname)
+    }
+    /// Static table sorted by refname
+    pub fn iter_static_data(&self) -> impl Iterator<Item = (&String, &StaticData)> {
+        let mut entries: Vec<(&String, &StaticData)> = self.static_data.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        entries.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use little_parser::{AtomTypes, Expression, Programm};
+
+    use crate::{vm::Vm, StaticData, Translator};
+
+    #[test]
+    fn module_is_self_contained() {
+        let program = Programm::Expression(vec![
+            Expression::Define(
+                "id".into(),
+                Rc::new(Expression::Lambda(
+                    vec!["x".into()],
+                    vec![Expression::Identifier("x".into())],
+                )),
+            ),
+            Expression::LambdaCall(
+                Rc::new(Expression::Identifier("id".into())),
+                vec![Expression::Atom(AtomTypes::Integer(3))],
+            ),
+        ]);
+        let module = Translator::default().translate_module(program).unwrap();
+
+        assert_eq!(module.entry_block().ident, "main");
+        let idents: Vec<&str> = module.iter_blocks().map(|b| b.ident.as_str()).collect();
+        assert_eq!(idents, vec!["main", "_0"]);
+        assert_eq!(module.iter_lambdas().count(), 1);
+        assert_eq!(
+            module.lookup_static("_0"),
+            Some(&StaticData::List(vec![StaticData::Identifier("x".into())]))
+        );
+        assert_eq!(module.metadata.lambda_count, 1);
+        assert_eq!(
+            Vm::new(&module.blocks)
+                .run(&module.entry)
+                .unwrap()
+                .to_string(),
+            "3"
+        );
+    }
+}
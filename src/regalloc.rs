@@ -0,0 +1,531 @@
+//! Linear scan register allocation.
+//! Maps the unbounded virtual registers of a block onto `r0`..`rN` for a target with N + 1
+//! registers. Intervals run from the first to the last instruction mentioning a register in
+//! textual order, which is the order every run of a block sees them in since there are no
+//! loops. Registers that do not fit live in frame slots, the highest registers are then kept
+//! free for reloading and spilling around each instruction. That is two of them, or as many as
+//! the instruction with the most spilled registers needs, like a MakeClosure with lots of
+//! captures.
+//!
+//! Run `ssa::from_ssa` first, Phis are not allocated.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+};
+
+use crate::{LinearBlock, LinearInstruction, Register};
+
+/// Fewest registers reserved for spill code once anything is spilled
+const SCRATCH: usize = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LiveInterval {
+    pub register: String,
+    /// Positions in textual order, Cond branches counted before what follows the Cond
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Location {
+    Register(Register),
+    Slot(usize),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Allocation {
+    /// Where each virtual register ended up
+    pub locations: HashMap<String, Location>,
+    pub slots: usize,
+    pub spill_instructions: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AllocError {
+    /// Spilling needs the scratch registers plus one to allocate
+    TooFewRegisters {
+        available: usize,
+        needed: usize,
+    },
+    Phi {
+        cond_name: String,
+    },
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllocError::TooFewRegisters { available, needed } => write!(
+                f,
+                "{} registers are not enough, spilling needs {}",
+                available, needed
+            ),
+            AllocError::Phi { cond_name } => {
+                write!(
+                    f,
+                    "phi of `{}` left in the block, run from_ssa first",
+                    cond_name
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for AllocError {}
+
+/// Name of the physical register with `index`
+pub fn physical_register(index: usize) -> Register {
+    Register {
+        virtual_ident: format!("r{}", index),
+    }
+}
+
+/// Rewrites `block` to only use `registers` physical registers
+pub fn allocate_registers(
+    block: &mut LinearBlock,
+    registers: usize,
+) -> Result<Allocation, AllocError> {
+    if let Some(cond_name) = find_phi(&block.program) {
+        return Err(AllocError::Phi { cond_name });
+    }
+    let intervals = live_intervals(block);
+    let mut locations = linear_scan(&intervals, registers);
+    let mut reserved = 0;
+    // Fewer registers to allocate can spill more around one instruction, so until it settles
+    loop {
+        let needed = most_spilled(&block.program, &locations);
+        if needed <= reserved {
+            break;
+        }
+        reserved = needed.max(SCRATCH);
+        if registers < reserved + 1 {
+            return Err(AllocError::TooFewRegisters {
+                available: registers,
+                needed: reserved + 1,
+            });
+        }
+        locations = linear_scan(&intervals, registers - reserved);
+    }
+    let slots = locations
+        .values()
+        .filter(|location| matches!(location, Location::Slot(_)))
+        .count();
+    let scratch: Vec<Register> = (registers - reserved..registers)
+        .map(physical_register)
+        .collect();
+    let program = std::mem::take(&mut block.program);
+    let mut spill_instructions = 0;
+    block.program = rewrite(program, &locations, &scratch, &mut spill_instructions);
+    Ok(Allocation {
+        locations,
+        slots,
+        spill_instructions,
+    })
+}
+
+/// Intervals of every register in `block`, sorted by start
+pub fn live_intervals(block: &LinearBlock) -> Vec<LiveInterval> {
+    fn walk(
+        program: &[LinearInstruction],
+        position: &mut usize,
+        intervals: &mut HashMap<String, (usize, usize)>,
+    ) {
+        for instr in program {
+            let pos = *position;
+            *position += 1;
+            for register in instr.uses().into_iter().chain(instr.defs()) {
+                let interval = intervals
+                    .entry(register.virtual_ident.clone())
+                    .or_insert((pos, pos));
+                interval.1 = pos;
+            }
+            if let LinearInstruction::Cond { branc_if_true, .. } = instr {
+                walk(&branc_if_true.program, position, intervals);
+            }
+        }
+    }
+    let mut intervals = HashMap::new();
+    walk(&block.program, &mut 0, &mut intervals);
+    let mut intervals: Vec<LiveInterval> = intervals
+        .into_iter()
+        .map(|(register, (start, end))| LiveInterval {
+            register,
+            start,
+            end,
+        })
+        .collect();
+    intervals.sort_by(|a, b| (a.start, &a.register).cmp(&(b.start, &b.register)));
+    intervals
+}
+
+fn linear_scan(intervals: &[LiveInterval], registers: usize) -> HashMap<String, Location> {
+    let mut locations = HashMap::new();
+    let mut free: BTreeSet<usize> = (0..registers).collect();
+    // (end, register, physical index) of intervals currently holding a register
+    let mut active: Vec<(usize, &str, usize)> = vec![];
+    let mut slots = 0;
+    for interval in intervals {
+        active.retain(|(end, _, physical)| {
+            if *end < interval.start {
+                free.insert(*physical);
+                false
+            } else {
+                true
+            }
+        });
+        if let Some(physical) = free.pop_first() {
+            active.push((interval.end, &interval.register, physical));
+            locations.insert(
+                interval.register.clone(),
+                Location::Register(physical_register(physical)),
+            );
+            continue;
+        }
+        // Whoever lives longest goes to memory
+        let longest = active
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, (end, _, _))| *end)
+            .map(|(index, _)| index);
+        let spilled = match longest {
+            Some(index) if active[index].0 > interval.end => {
+                let (_, register, physical) = active.swap_remove(index);
+                active.push((interval.end, &interval.register, physical));
+                locations.insert(
+                    interval.register.clone(),
+                    Location::Register(physical_register(physical)),
+                );
+                register
+            }
+            _ => &interval.register,
+        };
+        locations.insert(spilled.to_string(), Location::Slot(slots));
+        slots += 1;
+    }
+    locations
+}
+
+fn rewrite(
+    program: Vec<LinearInstruction>,
+    locations: &HashMap<String, Location>,
+    scratch: &[Register],
+    spill_instructions: &mut usize,
+) -> Vec<LinearInstruction> {
+    let mut out = vec![];
+    for mut instr in program {
+        let mut reloads = vec![];
+        let mut spills = vec![];
+        // Spilled registers of this instruction and the scratch register standing in for them.
+        // Every spilled register gets its own scratch, one both used and defined keeps it for
+        // the reload and the spill, so `most_spilled` reserves one per distinct register
+        let mut standins: Vec<(String, Register)> = vec![];
+        let name = |register: &Register| register.virtual_ident.clone();
+        let uses: Vec<String> = instr.uses().into_iter().map(name).collect();
+        let defs: Vec<String> = instr.defs().into_iter().map(name).collect();
+        for register in uses.iter().chain(&defs) {
+            let Location::Slot(slot) = locations[register] else {
+                continue;
+            };
+            if standins.iter().any(|(spilled, _)| spilled == register) {
+                continue;
+            }
+            let standin = scratch[standins.len()].clone();
+            if uses.contains(register) {
+                reloads.push(LinearInstruction::ReloadFromSlot {
+                    slot,
+                    to_reg: standin.clone(),
+                });
+            }
+            if defs.contains(register) {
+                spills.push(LinearInstruction::SpillToSlot {
+                    from_reg: standin.clone(),
+                    slot,
+                });
+            }
+            standins.push((register.clone(), standin));
+        }
+        for register in instr.registers_mut() {
+            *register = match &locations[&register.virtual_ident] {
+                Location::Register(physical) => physical.clone(),
+                Location::Slot(_) => standins
+                    .iter()
+                    .find(|(spilled, _)| *spilled == register.virtual_ident)
+                    .map(|(_, standin)| standin.clone())
+                    .expect("every spilled register got a standin"),
+            };
+        }
+        if let LinearInstruction::Cond { branc_if_true, .. } = &mut instr {
+            let branch = std::mem::take(&mut branc_if_true.program);
+            branc_if_true.program = rewrite(branch, locations, scratch, spill_instructions);
+        }
+        *spill_instructions += reloads.len() + spills.len();
+        out.extend(reloads);
+        out.push(instr);
+        out.extend(spills);
+    }
+    out
+}
+
+/// Most spilled registers any one instruction mentions, each needs its own scratch register
+fn most_spilled(program: &[LinearInstruction], locations: &HashMap<String, Location>) -> usize {
+    program
+        .iter()
+        .map(|instr| {
+            let mut spilled: Vec<&str> = instr
+                .uses()
+                .into_iter()
+                .chain(instr.defs())
+                .map(|register| register.virtual_ident.as_str())
+                .filter(|register| matches!(locations[*register], Location::Slot(_)))
+                .collect();
+            spilled.sort();
+            spilled.dedup();
+            let branch = match instr {
+                LinearInstruction::Cond { branc_if_true, .. } => {
+                    most_spilled(&branc_if_true.program, locations)
+                }
+                _ => 0,
+            };
+            spilled.len().max(branch)
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+fn find_phi(program: &[LinearInstruction]) -> Option<String> {
+    program.iter().find_map(|instr| match instr {
+        LinearInstruction::Phi { cond_name, .. } => Some(cond_name.clone()),
+        LinearInstruction::Cond { branc_if_true, .. } => find_phi(&branc_if_true.program),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use little_parser::Programm;
+
+    use super::{allocate_registers, live_intervals, AllocError, Location};
+    use crate::{
+        asm::parse_program,
+        stack::stack_to_registers_module,
+        test_ast::{call, ident, int, lambda},
+        value::Value,
+        verify::verify_module,
+        vm::Vm,
+        LinearBlock, LinearInstruction, Translator,
+    };
+
+    fn registers_used(program: &[LinearInstruction]) -> Vec<String> {
+        let mut used = vec![];
+        for instr in program {
+            for register in instr.uses().into_iter().chain(instr.defs()) {
+                used.push(register.virtual_ident.clone());
+            }
+            if let LinearInstruction::Cond { branc_if_true, .. } = instr {
+                used.extend(registers_used(&branc_if_true.program));
+            }
+        }
+        used
+    }
+
+    #[test]
+    fn spills_under_pressure_and_still_runs() {
+        // ((lambda (a b) (- (* a b) (+ a b (* b b)))) 3 4)
+        let program = Programm::Expression(vec![call(
+            lambda(
+                &["a", "b"],
+                vec![call(
+                    ident("-"),
+                    vec![
+                        call(ident("*"), vec![ident("a"), ident("b")]),
+                        call(
+                            ident("+"),
+                            vec![
+                                ident("a"),
+                                ident("b"),
+                                call(ident("*"), vec![ident("b"), ident("b")]),
+                            ],
+                        ),
+                    ],
+                )],
+            ),
+            vec![int(3), int(4)],
+        )]);
+        let mut module = Translator::default().translate_module(program).unwrap();
+        stack_to_registers_module(&mut module);
+
+        for registers in [3, 4, 64] {
+            let mut allocated = module.clone();
+            let mut spilled = 0;
+            for block in allocated.blocks.values_mut() {
+                let allocation = allocate_registers(block, registers).unwrap();
+                spilled += allocation.slots;
+                assert!(registers_used(&block.program)
+                    .iter()
+                    .all(|register| { register[1..].parse::<usize>().unwrap() < registers }));
+            }
+            match registers {
+                3 => assert!(spilled > 0),
+                64 => assert_eq!(spilled, 0),
+                _ => {}
+            }
+            assert_eq!(verify_module(&allocated), Ok(()));
+            assert_eq!(
+                Vm::new(&allocated.blocks).run("main").unwrap(),
+                Value::Integer(-11)
+            );
+        }
+    }
+
+    #[test]
+    fn intervals_and_spill_code() {
+        let mut block = LinearBlock {
+            ident: "test".into(),
+            program: parse_program(
+                "load static0{1} -> vreg0
+                 load static1{2} -> vreg1
+                 load static2{3} -> vreg2
+                 list.init -> vreg3
+                 list.add vreg3 <- vreg2 -> vreg3
+                 list.add vreg3 <- vreg1 -> vreg3
+                 list.add vreg3 <- vreg0 -> vreg3
+                 push vreg3",
+            )
+            .unwrap(),
+        };
+        let intervals = live_intervals(&block);
+        assert_eq!(
+            intervals
+                .iter()
+                .map(|interval| (interval.register.as_str(), interval.start, interval.end))
+                .collect::<Vec<_>>(),
+            vec![
+                ("vreg0", 0, 6),
+                ("vreg1", 1, 5),
+                ("vreg2", 2, 4),
+                ("vreg3", 3, 7)
+            ]
+        );
+        assert_eq!(
+            allocate_registers(&mut block.clone(), 2),
+            Err(AllocError::TooFewRegisters {
+                available: 2,
+                needed: 3
+            })
+        );
+
+        let allocation = allocate_registers(&mut block, 3).unwrap();
+        // One register left after the scratch ones, it goes to the shortest interval
+        assert_eq!(allocation.slots, 3);
+        assert!(matches!(
+            allocation.locations["vreg2"],
+            Location::Register(_)
+        ));
+        assert_eq!(
+            block.program,
+            parse_program(
+                "load static0{1} -> r1
+                 spill r1 -> slot0
+                 load static1{2} -> r1
+                 spill r1 -> slot1
+                 load static2{3} -> r0
+                 list.init -> r1
+                 spill r1 -> slot2
+                 reload slot2 -> r1
+                 list.add r1 <- r0 -> r1
+                 spill r1 -> slot2
+                 reload slot2 -> r1
+                 reload slot1 -> r2
+                 list.add r1 <- r2 -> r1
+                 spill r1 -> slot2
+                 reload slot2 -> r1
+                 reload slot0 -> r2
+                 list.add r1 <- r2 -> r1
+                 spill r1 -> slot2
+                 reload slot2 -> r1
+                 push r1"
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn every_spilled_operand_gets_its_own_scratch() {
+        // The captures outlive everything else so they are what gets spilled
+        let mut block = LinearBlock {
+            ident: "test".into(),
+            program: parse_program(
+                "load static0{0} -> vreg0
+                 load static1{1} -> vreg1
+                 load static2{2} -> vreg2
+                 load static3{3} -> vreg3
+                 load static4{4} -> vreg4
+                 load static5{5} -> vreg5
+                 load static6{6} -> vreg7
+                 load static7{7} -> vreg8
+                 load static8{8} -> vreg9
+                 closure.flat _0 _0{()} [vreg0 vreg1 vreg2 vreg3 vreg4 vreg5] -> vreg6
+                 push vreg7
+                 push vreg8
+                 push vreg9
+                 push vreg6
+                 push vreg0
+                 push vreg1
+                 push vreg2
+                 push vreg3
+                 push vreg4
+                 push vreg5",
+            )
+            .unwrap(),
+        };
+        // Everything the block leaves on the stack, top first
+        let run = |block: &LinearBlock| {
+            let mut program = block.program.clone();
+            program.extend(parse_program("list.init -> all").unwrap());
+            for i in 0..10 {
+                let collect = format!("pop item{}\n list.add all <- item{} -> all", i, i);
+                program.extend(parse_program(&collect).unwrap());
+            }
+            program.extend(parse_program("push all").unwrap());
+            Vm::new(&HashMap::new())
+                .run_block(&LinearBlock {
+                    ident: "test".into(),
+                    program,
+                })
+                .unwrap()
+        };
+        let expected = run(&block);
+
+        assert_eq!(
+            allocate_registers(&mut block.clone(), 7),
+            Err(AllocError::TooFewRegisters {
+                available: 7,
+                needed: 8
+            })
+        );
+        let allocation = allocate_registers(&mut block, 9).unwrap();
+        let captures = block
+            .program
+            .iter()
+            .find_map(|instr| match instr {
+                LinearInstruction::MakeClosure { captures, .. } => Some(captures.clone()),
+                _ => None,
+            })
+            .unwrap();
+        let reloads = block
+            .program
+            .iter()
+            .take_while(|instr| !matches!(instr, LinearInstruction::MakeClosure { .. }))
+            .filter(|instr| matches!(instr, LinearInstruction::ReloadFromSlot { .. }))
+            .count();
+        assert!(allocation.slots > 2 && reloads > 2);
+        let mut distinct = captures.clone();
+        distinct.sort_by(|a, b| a.virtual_ident.cmp(&b.virtual_ident));
+        distinct.dedup();
+        assert_eq!(distinct.len(), captures.len());
+        assert_eq!(run(&block), expected);
+    }
+}
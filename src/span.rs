@@ -0,0 +1,303 @@
+//! Source locations for lowered code.
+//! `little_parser` expressions do not remember where they came from, so `read_span_trees`
+//! re-reads the source into bare s-expression spans which the Translator walks alongside the
+//! ast. What it finds ends up in a `SourceMap` keyed by block and instruction index.
+
+use std::{collections::HashMap, fmt, ops::Range};
+
+use crate::LinearInstruction;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    /// Byte offsets into the source
+    pub start: usize,
+    pub end: usize,
+    /// 1 based position of `start`, column counted in chars
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn range(&self) -> Range<usize> {
+        self.start..self.end
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+/// One datum of the source, lists keep the spans of their elements
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpanTree {
+    pub span: Span,
+    pub children: Vec<SpanTree>,
+}
+
+impl SpanTree {
+    pub fn child(&self, index: usize) -> Option<&SpanTree> {
+        self.children.get(index)
+    }
+}
+
+/// Reads every toplevel datum of `source`. Only the shape is kept, not what the atoms are.
+pub fn read_span_trees(source: &str) -> Vec<SpanTree> {
+    let mut reader = SpanReader {
+        source,
+        pos: 0,
+        line: 1,
+        column: 1,
+        depth: 0,
+    };
+    let mut trees = vec![];
+    while let Some(tree) = reader.datum() {
+        trees.push(tree);
+    }
+    trees
+}
+
+struct SpanReader<'s> {
+    source: &'s str,
+    pos: usize,
+    line: usize,
+    column: usize,
+    /// Lists we are currently inside of
+    depth: usize,
+}
+
+impl SpanReader<'_> {
+    fn peek(&self) -> Option<char> {
+        self.source[self.pos..].chars().next()
+    }
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+    fn skip_atmosphere(&mut self) {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => {
+                    self.bump();
+                }
+                Some(';') => while !matches!(self.bump(), Some('\n') | None) {},
+                _ => return,
+            }
+        }
+    }
+    /// None at the end of input or at a closing paren, which the caller consumes
+    fn datum(&mut self) -> Option<SpanTree> {
+        self.skip_atmosphere();
+        // Stray closing parens at toplevel are skipped
+        while self.depth == 0 && matches!(self.peek(), Some(')' | ']')) {
+            self.bump();
+            self.skip_atmosphere();
+        }
+        let (start, line, column) = (self.pos, self.line, self.column);
+        let children = match self.peek()? {
+            ')' | ']' => return None,
+            '(' | '[' => {
+                self.bump();
+                self.depth += 1;
+                let mut children = vec![];
+                while let Some(child) = self.datum() {
+                    children.push(child);
+                }
+                self.depth -= 1;
+                // Unclosed lists just end with the input
+                self.bump();
+                children
+            }
+            '\'' | '`' | ',' => {
+                self.bump();
+                self.datum().into_iter().collect()
+            }
+            '"' => {
+                self.bump();
+                while let Some(c) = self.bump() {
+                    match c {
+                        '\\' => {
+                            self.bump();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+                vec![]
+            }
+            _ => {
+                while self
+                    .peek()
+                    .is_some_and(|c| !c.is_whitespace() && !"()[]\";".contains(c))
+                {
+                    self.bump();
+                }
+                vec![]
+            }
+        };
+        Some(SpanTree {
+            span: Span {
+                start,
+                end: self.pos,
+                line,
+                column,
+            },
+            children,
+        })
+    }
+}
+
+/// Spans of one instruction and, for a Cond, of the instructions in its branch
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InstructionSpan {
+    pub span: Option<Span>,
+    pub branch: Vec<InstructionSpan>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockSpans {
+    /// Whole program for main, the lambda expression for lambda blocks
+    pub span: Option<Span>,
+    pub instructions: Vec<InstructionSpan>,
+}
+
+/// Side table answering "which source range produced instruction N of block X"
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    pub blocks: HashMap<String, BlockSpans>,
+    /// Expression each StaticRef was made for
+    pub statics: HashMap<String, Span>,
+}
+
+impl SourceMap {
+    pub fn block_span(&self, block: &str) -> Option<Span> {
+        self.blocks.get(block)?.span
+    }
+    pub fn instruction_span(&self, block: &str, index: usize) -> Option<Span> {
+        self.nested_instruction_span(block, &[index])
+    }
+    /// `path` is the index into the block followed by indices into nested Cond branches
+    pub fn nested_instruction_span(&self, block: &str, path: &[usize]) -> Option<Span> {
+        let (first, rest) = path.split_first()?;
+        let mut current = self.blocks.get(block)?.instructions.get(*first)?;
+        for index in rest {
+            current = current.branch.get(*index)?;
+        }
+        current.span
+    }
+    pub fn static_span(&self, refname: &str) -> Option<Span> {
+        self.statics.get(refname).copied()
+    }
+}
+
+/// Sub tree at `index`, for following the ast through a tree that may be missing
+pub(crate) fn child(tree: Option<&SpanTree>, index: usize) -> Option<&SpanTree> {
+    tree?.child(index)
+}
+
+/// Instructions of one lowered expression together with where each came from.
+/// Used by the Translator in place of a bare Vec so spans survive splicing.
+#[derive(Debug, Default)]
+pub(crate) struct SpannedProgram {
+    pub program: Vec<LinearInstruction>,
+    pub spans: Vec<InstructionSpan>,
+    /// What `push` attributes new instructions to
+    pub span: Option<Span>,
+}
+
+impl SpannedProgram {
+    pub fn new(span: Option<Span>) -> SpannedProgram {
+        SpannedProgram {
+            program: vec![],
+            spans: vec![],
+            span,
+        }
+    }
+    pub fn push(&mut self, instr: LinearInstruction) {
+        self.push_with_branch(instr, vec![]);
+    }
+    pub fn push_with_branch(&mut self, instr: LinearInstruction, branch: Vec<InstructionSpan>) {
+        self.program.push(instr);
+        self.spans.push(InstructionSpan {
+            span: self.span,
+            branch,
+        });
+    }
+    /// Splices in a sub expression, keeping its spans
+    pub fn append(&mut self, other: SpannedProgram) {
+        self.program.extend(other.program);
+        self.spans.extend(other.spans);
+    }
+    /// Whether the frame is left by a tail call at the end, so nothing after it runs
+    pub fn ends_in_tail_call(&self) -> bool {
+        matches!(
+            self.program.last(),
+            Some(LinearInstruction::TailCall { .. })
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use little_parser::{AtomTypes, Expression, Programm};
+
+    use super::read_span_trees;
+    use crate::{LinearInstruction, Translator};
+
+    #[test]
+    fn reads_nested_spans() {
+        let trees = read_span_trees("(f 1)\n ; note\n (g \"a b\" '(x))");
+        assert_eq!(trees.len(), 2);
+        assert_eq!(trees[0].span.range(), 0..5);
+        let g = &trees[1];
+        assert_eq!((g.span.line, g.span.column), (3, 2));
+        assert_eq!(g.children.len(), 3);
+        assert_eq!(g.children[1].span.range(), 18..23);
+        assert_eq!(g.children[2].span.range(), 24..28);
+    }
+
+    #[test]
+    fn instructions_know_their_expression() {
+        let source = "(define x 5)\n(f x)";
+        let program = Programm::Expression(vec![
+            Expression::Define("x".into(), Rc::new(Expression::Atom(AtomTypes::Integer(5)))),
+            Expression::LambdaCall(
+                Rc::new(Expression::Identifier("f".into())),
+                vec![Expression::Identifier("x".into())],
+            ),
+        ]);
+        let module = Translator::default()
+            .translate_module_with_source(program, source)
+            .unwrap();
+        let main = module.entry_block();
+        let map = &module.source_map;
+        let text = |index: usize| &source[map.instruction_span("main", index).unwrap().range()];
+
+        // The literal 5 first, then the assign belonging to the whole define
+        assert!(matches!(
+            main.program[0],
+            LinearInstruction::StaticRefToRegister { .. }
+        ));
+        assert_eq!(text(0), "5");
+        assert_eq!(text(3), "(define x 5)");
+        let lookup_x = main
+            .program
+            .iter()
+            .rposition(|instr| matches!(instr, LinearInstruction::Lookup { .. }))
+            .unwrap();
+        assert_eq!(text(lookup_x), "x");
+        assert_eq!(map.instruction_span("main", lookup_x).unwrap().line, 2);
+        assert_eq!(text(main.program.len() - 1), "(f x)");
+        assert_eq!(&source[map.block_span("main").unwrap().range()], source);
+    }
+}
@@ -0,0 +1,430 @@
+//! SSA form for linear blocks.
+//! `to_ssa` gives every register write its own name, the first write keeps the original one
+//! and later ones get `_1`, `_2`... appended. Names that differ between the paths into an
+//! EndOfCond are merged by Phis placed right after it. `from_ssa` turns the Phis back into
+//! Moves at the end of each incoming path.
+//!
+//! Values passed on the stack are not tracked, run `stack::stack_to_registers` first to get
+//! as many of them as possible into registers.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{LinearBlock, LinearInstruction, Register};
+
+/// Original register name to the name currently holding its value
+type Env = HashMap<String, Register>;
+
+pub fn to_ssa(block: &mut LinearBlock) {
+    let mut renamer = Renamer {
+        taken: HashSet::new(),
+        renamed: HashSet::new(),
+        counters: HashMap::new(),
+        last_read: HashMap::new(),
+        position: 0,
+    };
+    renamer.scan(&block.program);
+    renamer.position = 0;
+    let program = std::mem::take(&mut block.program);
+    block.program = renamer.program(program, &mut Env::new()).0;
+}
+
+pub fn from_ssa(block: &mut LinearBlock) {
+    lower_phis(&mut block.program);
+}
+
+struct Renamer {
+    /// Every name in the block, fresh names skip these
+    taken: HashSet<String>,
+    /// Registers whose first write already kept the original name
+    renamed: HashSet<String>,
+    counters: HashMap<String, usize>,
+    /// Position of the last instruction reading each register, Phis are only made if read later
+    last_read: HashMap<String, usize>,
+    position: usize,
+}
+
+impl Renamer {
+    /// Positions count instructions in textual order, branches before what follows their Cond
+    fn scan(&mut self, program: &[LinearInstruction]) {
+        for instr in program {
+            let position = self.position;
+            self.position += 1;
+            for register in instr.uses() {
+                self.taken.insert(register.virtual_ident.clone());
+                self.last_read
+                    .insert(register.virtual_ident.clone(), position);
+            }
+            for register in instr.defs() {
+                self.taken.insert(register.virtual_ident.clone());
+            }
+            if let LinearInstruction::Cond { branc_if_true, .. } = instr {
+                self.scan(&branc_if_true.program);
+            }
+        }
+    }
+    fn fresh(&mut self, name: &str) -> Register {
+        if self.renamed.insert(name.to_string()) {
+            return Register {
+                virtual_ident: name.to_string(),
+            };
+        }
+        let counter = self.counters.entry(name.to_string()).or_insert(0);
+        loop {
+            *counter += 1;
+            let candidate = format!("{}_{}", name, counter);
+            if self.taken.insert(candidate.clone()) {
+                return Register {
+                    virtual_ident: candidate,
+                };
+            }
+        }
+    }
+    /// Renames `program` starting from `env`, returns it and whether its end is reachable
+    fn program(
+        &mut self,
+        program: Vec<LinearInstruction>,
+        env: &mut Env,
+    ) -> (Vec<LinearInstruction>, bool) {
+        let mut out = vec![];
+        let mut reachable = true;
+        // Environments at the end of taken branches waiting for their EndOfCond
+        let mut pending: HashMap<String, Vec<(Option<usize>, Env)>> = HashMap::new();
+        let mut clauses: HashMap<String, usize> = HashMap::new();
+        for mut instr in program {
+            let position = self.position;
+            self.position += 1;
+            match instr {
+                LinearInstruction::Cond {
+                    cond_name,
+                    condition,
+                    mut branc_if_true,
+                } => {
+                    let clause = clauses.entry(cond_name.clone()).or_insert(0);
+                    let index = *clause;
+                    *clause += 1;
+                    let condition = env.get(&condition.virtual_ident).unwrap_or(&condition);
+                    let condition = condition.clone();
+                    let mut branch_env = env.clone();
+                    let (branch, falls_through) =
+                        self.program(branc_if_true.program, &mut branch_env);
+                    if falls_through {
+                        pending
+                            .entry(cond_name.clone())
+                            .or_default()
+                            .push((Some(index), branch_env));
+                    }
+                    branc_if_true.program = branch;
+                    out.push(LinearInstruction::Cond {
+                        cond_name,
+                        condition,
+                        branc_if_true,
+                    });
+                }
+                LinearInstruction::EndOfCond { cond_name } => {
+                    let mut incoming = pending.remove(&cond_name).unwrap_or_default();
+                    clauses.remove(&cond_name);
+                    if reachable {
+                        incoming.push((None, env.clone()));
+                    }
+                    reachable = !incoming.is_empty();
+                    out.push(LinearInstruction::EndOfCond {
+                        cond_name: cond_name.clone(),
+                    });
+                    *env = self.join(&cond_name, incoming, position, &mut out);
+                }
+                _ => {
+                    for register in instr.uses_mut() {
+                        if let Some(current) = env.get(&register.virtual_ident) {
+                            *register = current.clone();
+                        }
+                    }
+                    for register in instr.defs_mut() {
+                        let fresh = self.fresh(&register.virtual_ident);
+                        env.insert(register.virtual_ident.clone(), fresh.clone());
+                        *register = fresh;
+                    }
+                    if matches!(
+                        instr,
+                        LinearInstruction::Return { .. } | LinearInstruction::TailCall { .. }
+                    ) {
+                        reachable = false;
+                    }
+                    out.push(instr);
+                }
+            }
+        }
+        (out, reachable)
+    }
+    /// Environment after the EndOfCond at `position`, pushing the Phis it needs onto `out`
+    fn join(
+        &mut self,
+        cond_name: &str,
+        incoming: Vec<(Option<usize>, Env)>,
+        position: usize,
+        out: &mut Vec<LinearInstruction>,
+    ) -> Env {
+        let mut env = Env::new();
+        let Some((_, first)) = incoming.first() else {
+            return env;
+        };
+        let mut names: Vec<&String> = first.keys().collect();
+        names.sort();
+        for name in names {
+            // Registers missing on some path are not defined after the join either
+            let Some(inputs) = incoming
+                .iter()
+                .map(|(clause, env)| Some((*clause, env.get(name)?.clone())))
+                .collect::<Option<Vec<_>>>()
+            else {
+                continue;
+            };
+            if inputs.iter().all(|(_, input)| *input == inputs[0].1) {
+                env.insert(name.clone(), inputs[0].1.clone());
+            } else if self
+                .last_read
+                .get(name)
+                .is_some_and(|last| *last > position)
+            {
+                let output_reg = self.fresh(name);
+                env.insert(name.clone(), output_reg.clone());
+                out.push(LinearInstruction::Phi {
+                    cond_name: cond_name.to_string(),
+                    inputs,
+                    output_reg,
+                });
+            }
+        }
+        env
+    }
+}
+
+/// Replaces the Phis after each EndOfCond with a Move on every incoming path
+fn lower_phis(program: &mut Vec<LinearInstruction>) {
+    for instr in program.iter_mut() {
+        if let LinearInstruction::Cond { branc_if_true, .. } = instr {
+            lower_phis(&mut branc_if_true.program);
+        }
+    }
+    let mut index = 0;
+    while index < program.len() {
+        let LinearInstruction::EndOfCond { cond_name } = &program[index] else {
+            index += 1;
+            continue;
+        };
+        let cond_name = cond_name.clone();
+        let phis_end = program[index + 1..]
+            .iter()
+            .position(|instr| !matches!(instr, LinearInstruction::Phi { .. }))
+            .map_or(program.len(), |offset| index + 1 + offset);
+        let phis: Vec<LinearInstruction> = program.drain(index + 1..phis_end).collect();
+
+        // Clauses of this cond in order, found by walking back to the previous group of the name
+        let mut clauses: Vec<usize> = program[..index]
+            .iter()
+            .enumerate()
+            .rev()
+            .take_while(|(_, instr)| {
+                !matches!(instr, LinearInstruction::EndOfCond { cond_name: name } if *name == cond_name)
+            })
+            .filter(|(_, instr)| {
+                matches!(instr, LinearInstruction::Cond { cond_name: name, .. } if *name == cond_name)
+            })
+            .map(|(index, _)| index)
+            .collect();
+        clauses.reverse();
+
+        for phi in phis {
+            let LinearInstruction::Phi {
+                inputs, output_reg, ..
+            } = phi
+            else {
+                unreachable!("only Phis were drained");
+            };
+            for (clause, input) in inputs {
+                let copy = LinearInstruction::Move {
+                    from_reg: input,
+                    to_reg: output_reg.clone(),
+                };
+                match clause.and_then(|clause| clauses.get(clause)) {
+                    Some(cond) => {
+                        if let LinearInstruction::Cond { branc_if_true, .. } = &mut program[*cond] {
+                            branc_if_true.program.push(copy);
+                        }
+                    }
+                    // Right before the EndOfCond only runs when no clause was taken
+                    None => {
+                        program.insert(index, copy);
+                        index += 1;
+                    }
+                }
+            }
+        }
+        index += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use little_parser::{Expression, Programm};
+
+    use super::{from_ssa, to_ssa};
+    use crate::{
+        asm::parse_program,
+        stack::stack_to_registers,
+        test_ast::{call, ident, int, lambda},
+        value::Value,
+        verify::{verify, verify_module},
+        vm::Vm,
+        LinearBlock, LinearInstruction, Translator,
+    };
+
+    /// How often each register is written, branches included
+    fn writes(program: &[LinearInstruction]) -> HashMap<String, usize> {
+        fn count(program: &[LinearInstruction], written: &mut HashMap<String, usize>) {
+            for instr in program {
+                for register in instr.defs() {
+                    *written.entry(register.virtual_ident.clone()).or_insert(0) += 1;
+                }
+                if let LinearInstruction::Cond { branc_if_true, .. } = instr {
+                    count(&branc_if_true.program, written);
+                }
+            }
+        }
+        let mut written = HashMap::new();
+        count(program, &mut written);
+        written
+    }
+
+    fn block(src: &str) -> LinearBlock {
+        LinearBlock {
+            ident: "test".into(),
+            program: parse_program(src).unwrap(),
+        }
+    }
+
+    #[test]
+    fn phis_at_the_join() {
+        let mut test = block(
+            "load static0{#t} -> vreg0
+             cond0: if vreg0 {
+                 load static1{1} -> vreg1
+             }
+             cond0: if vreg0 {
+                 ret vreg0
+             }
+             load static2{2} -> vreg1
+             cond0: end
+             push vreg1",
+        );
+        let run = |block: &LinearBlock| Vm::new(&HashMap::new()).run_block(block).unwrap();
+
+        to_ssa(&mut test);
+        assert_eq!(
+            test,
+            block(
+                "load static0{#t} -> vreg0
+                 cond0: if vreg0 {
+                     load static1{1} -> vreg1
+                 }
+                 cond0: if vreg0 {
+                     ret vreg0
+                 }
+                 load static2{2} -> vreg1_1
+                 cond0: end
+                 phi cond0 [0: vreg1 _: vreg1_1] -> vreg1_2
+                 push vreg1_2"
+            )
+        );
+        assert_eq!(run(&test), Value::Integer(1));
+
+        from_ssa(&mut test);
+        assert_eq!(
+            test,
+            block(
+                "load static0{#t} -> vreg0
+                 cond0: if vreg0 {
+                     load static1{1} -> vreg1
+                     move vreg1 -> vreg1_2
+                 }
+                 cond0: if vreg0 {
+                     ret vreg0
+                 }
+                 load static2{2} -> vreg1_1
+                 move vreg1_1 -> vreg1_2
+                 cond0: end
+                 push vreg1_2"
+            )
+        );
+        assert_eq!(run(&test), Value::Integer(1));
+        assert_eq!(verify(&test), Ok(()));
+    }
+
+    #[test]
+    fn translated_let_writes_each_register_once() {
+        // (let ((x 1)) x 2 (+ x 3))
+        let program = Programm::Expression(vec![Expression::Let(
+            vec![("x".into(), int(1))],
+            vec![
+                ident("x"),
+                int(2),
+                call(ident("+"), vec![ident("x"), int(3)]),
+            ],
+        )]);
+        let mut translator = Translator::default();
+        let mut main = translator
+            .ast_to_intermediate_representation(program)
+            .unwrap();
+        stack_to_registers(&mut main);
+        to_ssa(&mut main);
+
+        let written = writes(&main.program);
+        assert!(written.values().all(|count| *count == 1), "{:?}", written);
+        assert_eq!(
+            Vm::new(&HashMap::new()).run_block(&main).unwrap(),
+            Value::Integer(4)
+        );
+        from_ssa(&mut main);
+        assert_eq!(verify(&main), Ok(()));
+    }
+
+    #[test]
+    fn translated_cond_gets_a_phi() {
+        // ((lambda (x) (+ 1 (cond ((< x 0) 0) ((= x 0) 5) (else x)))) 3)
+        let program = Programm::Expression(vec![call(
+            lambda(
+                &["x"],
+                vec![call(
+                    ident("+"),
+                    vec![
+                        int(1),
+                        Expression::Cond(vec![
+                            (call(ident("<"), vec![ident("x"), int(0)]), int(0)),
+                            (call(ident("="), vec![ident("x"), int(0)]), int(5)),
+                            (ident("else"), ident("x")),
+                        ]),
+                    ],
+                )],
+            ),
+            vec![int(3)],
+        )]);
+        let mut module = Translator::default().translate_module(program).unwrap();
+        let lambda = module.blocks.get_mut("_0").unwrap();
+        stack_to_registers(lambda);
+        to_ssa(lambda);
+
+        assert!(lambda
+            .program
+            .iter()
+            .any(|instr| matches!(instr, LinearInstruction::Phi { .. })));
+        let written = writes(&lambda.program);
+        assert!(written.values().all(|count| *count == 1), "{:?}", written);
+        let run = |module: &crate::module::IrModule| Vm::new(&module.blocks).run("main").unwrap();
+        assert_eq!(run(&module), Value::Integer(4));
+
+        from_ssa(module.blocks.get_mut("_0").unwrap());
+        assert_eq!(verify_module(&module), Ok(()));
+        assert_eq!(run(&module), Value::Integer(4));
+    }
+}
@@ -0,0 +1,491 @@
+//! Stack to register conversion.
+//! The Translator pushes the value of every expression and pops it right back into a fresh
+//! register. This pass pairs each push with the pop that takes its value off again, turns the
+//! pair into a Move and then renames Moves away where both registers can share one name.
+//!
+//! Calls run on their own stack in the callee so pairs may span them. Pairs never span a Cond
+//! or EndOfCond though, which of the instructions in between run depends on the condition.
+//! The one exception is the result of a cond: when every path into the EndOfCond ends by
+//! pushing a value and the next pop takes it, each of those pushes becomes a Move into the
+//! popped register instead.
+
+use std::collections::HashMap;
+
+use crate::{module::IrModule, LinearBlock, LinearInstruction, Register};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StackStats {
+    pub pushes_removed: usize,
+    pub pops_removed: usize,
+    /// Moves left behind for pairs whose registers could not be merged
+    pub moves: usize,
+}
+
+impl StackStats {
+    /// Stack operations the pass got rid of
+    pub fn removed(&self) -> usize {
+        self.pushes_removed + self.pops_removed
+    }
+}
+
+pub fn stack_to_registers(block: &mut LinearBlock) -> StackStats {
+    let mut stats = StackStats::default();
+    pair(&mut block.program, &mut stats);
+    while let Some((path, from, to)) = mergeable_move(&block.program) {
+        remove_at(&mut block.program, &path);
+        for instr in block.program.iter_mut() {
+            rename(instr, &to, &from);
+        }
+        stats.moves -= 1;
+    }
+    stats
+}
+
+pub fn stack_to_registers_module(module: &mut IrModule) -> StackStats {
+    let mut stats = StackStats::default();
+    for block in module.blocks.values_mut() {
+        let block_stats = stack_to_registers(block);
+        stats.pushes_removed += block_stats.pushes_removed;
+        stats.pops_removed += block_stats.pops_removed;
+        stats.moves += block_stats.moves;
+    }
+    stats
+}
+
+struct Pushed {
+    index: usize,
+    /// None for the result of a cond group, see `joined`
+    register: Option<Register>,
+    /// Cleared once the register is overwritten or a Cond is in the way
+    removable: bool,
+    /// Set for the result of a cond group, `index` is then its EndOfCond
+    joined: Option<Joined>,
+}
+
+/// Where the paths into an EndOfCond push the result of their cond
+struct Joined {
+    /// Conds whose branch ends with the push
+    branches: Vec<usize>,
+    /// The push on the path taking no clause, None if that path returns
+    fall_through: Option<usize>,
+}
+
+/// The clauses of a cond group seen so far
+struct Group {
+    /// Stack depth at the first Cond
+    depth: usize,
+    last_cond: usize,
+    branches: Vec<usize>,
+    /// Some branch reaches the EndOfCond without pushing a result or the clauses do not all
+    /// start from the same depth
+    mismatched: bool,
+}
+
+/// Rewrites the pairs in `program` and its branches.
+/// Returns false if `program` popped below where it started or left values behind.
+fn pair(program: &mut Vec<LinearInstruction>, stats: &mut StackStats) -> bool {
+    let mut stack: Vec<Pushed> = vec![];
+    let mut contained = true;
+    let mut reachable = true;
+    let mut pairs = vec![];
+    let mut joins = vec![];
+    let mut groups: HashMap<String, Group> = HashMap::new();
+    for (index, instr) in program.iter_mut().enumerate() {
+        match instr {
+            LinearInstruction::PushToStack { register } => stack.push(Pushed {
+                index,
+                register: Some(register.clone()),
+                removable: true,
+                joined: None,
+            }),
+            LinearInstruction::PopFromStack { register } => {
+                match stack.pop() {
+                    Some(Pushed {
+                        removable: true,
+                        joined: Some(joined),
+                        ..
+                    }) => joins.push((joined, index)),
+                    Some(pushed) if pushed.removable => pairs.push((pushed.index, index)),
+                    Some(_) => {}
+                    None => contained = false,
+                }
+                clobber(&mut stack, register);
+            }
+            LinearInstruction::Cond {
+                cond_name,
+                branc_if_true,
+                ..
+            } => {
+                let group = groups.entry(cond_name.clone()).or_insert(Group {
+                    depth: stack.len(),
+                    last_cond: index,
+                    branches: vec![],
+                    mismatched: false,
+                });
+                group.last_cond = index;
+                group.mismatched |= stack.len() != group.depth;
+
+                let branch = &mut branc_if_true.program;
+                let result = match branch.last() {
+                    Some(LinearInstruction::PushToStack { .. }) => branch.pop(),
+                    _ => None,
+                };
+                let balanced = pair(branch, stats);
+                let returns = matches!(
+                    branch.last(),
+                    Some(LinearInstruction::Return { .. } | LinearInstruction::TailCall { .. })
+                );
+                match result {
+                    Some(push) if balanced => {
+                        branch.push(push);
+                        group.branches.push(index);
+                    }
+                    Some(push) => {
+                        branch.push(push);
+                        group.mismatched = true;
+                    }
+                    None => group.mismatched |= !returns,
+                }
+                if !balanced {
+                    // The branch took or left values, we no longer know what is on top
+                    stack.clear();
+                    contained = false;
+                }
+                stack.iter_mut().for_each(|pushed| pushed.removable = false);
+            }
+            LinearInstruction::EndOfCond { cond_name } => {
+                let group = groups.remove(cond_name.as_str());
+                // The push of the path taking no clause has to come after the last one
+                let fall_through = stack
+                    .last()
+                    .filter(|pushed| {
+                        pushed.removable
+                            && pushed.joined.is_none()
+                            && group.as_ref().is_some_and(|group| {
+                                pushed.index > group.last_cond && stack.len() == group.depth + 1
+                            })
+                    })
+                    .map(|pushed| pushed.index);
+                stack.iter_mut().for_each(|pushed| pushed.removable = false);
+                match group {
+                    Some(group) if !group.branches.is_empty() => {
+                        let fall_through = match (reachable, fall_through) {
+                            (false, _) => Some(None),
+                            (true, Some(push)) => Some(Some(push)),
+                            (true, None) => None,
+                        };
+                        match fall_through.filter(|_| !group.mismatched) {
+                            Some(fall_through) => {
+                                if fall_through.is_some() {
+                                    stack.pop();
+                                } else {
+                                    // Only the branches get here, below them we know nothing
+                                    stack.clear();
+                                    contained = false;
+                                }
+                                stack.push(Pushed {
+                                    index,
+                                    register: None,
+                                    removable: true,
+                                    joined: Some(Joined {
+                                        branches: group.branches,
+                                        fall_through,
+                                    }),
+                                });
+                            }
+                            None => {
+                                // The paths disagree on what is on top
+                                stack.clear();
+                                contained = false;
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+                reachable = true;
+            }
+            // Whatever is left dies with the frame
+            LinearInstruction::Return { .. } | LinearInstruction::TailCall { .. } => {
+                stack.clear();
+                reachable = false;
+            }
+            other => {
+                for register in other.defs() {
+                    clobber(&mut stack, register);
+                }
+            }
+        }
+    }
+
+    let mut drop = vec![false; program.len()];
+    for (push, pop) in pairs {
+        let (
+            LinearInstruction::PushToStack { register: from_reg },
+            LinearInstruction::PopFromStack { register: to_reg },
+        ) = (&program[push], &program[pop])
+        else {
+            unreachable!("pairs are made of a push and a pop");
+        };
+        stats.pushes_removed += 1;
+        stats.pops_removed += 1;
+        drop[push] = true;
+        if from_reg == to_reg {
+            drop[pop] = true;
+        } else {
+            program[pop] = LinearInstruction::Move {
+                from_reg: from_reg.clone(),
+                to_reg: to_reg.clone(),
+            };
+            stats.moves += 1;
+        }
+    }
+    for (joined, pop) in joins {
+        let LinearInstruction::PopFromStack { register: to_reg } = &program[pop] else {
+            unreachable!("joins end in a pop");
+        };
+        // The Moves run on different paths before the pop, nothing in between may touch the
+        // register they write
+        if mentions(&program[..pop], to_reg) {
+            continue;
+        }
+        let to_reg = to_reg.clone();
+        let into_move = |push: &mut LinearInstruction| {
+            if let LinearInstruction::PushToStack { register } = push {
+                *push = LinearInstruction::Move {
+                    from_reg: register.clone(),
+                    to_reg: to_reg.clone(),
+                };
+            }
+        };
+        for cond in &joined.branches {
+            if let LinearInstruction::Cond { branc_if_true, .. } = &mut program[*cond] {
+                if let Some(push) = branc_if_true.program.last_mut() {
+                    into_move(push);
+                }
+            }
+        }
+        if let Some(push) = joined.fall_through {
+            into_move(&mut program[push]);
+        }
+        let pushes = joined.branches.len() + usize::from(joined.fall_through.is_some());
+        stats.pushes_removed += pushes;
+        stats.pops_removed += 1;
+        stats.moves += pushes;
+        drop[pop] = true;
+    }
+    let mut drop = drop.into_iter();
+    program.retain(|_| !drop.next().unwrap());
+
+    contained && stack.is_empty()
+}
+
+fn clobber(stack: &mut [Pushed], register: &Register) {
+    for pushed in stack
+        .iter_mut()
+        .filter(|pushed| pushed.register.as_ref() == Some(register))
+    {
+        pushed.removable = false;
+    }
+}
+
+/// Whether `program` or any of its branches reads or writes `register`
+fn mentions(program: &[LinearInstruction], register: &Register) -> bool {
+    program.iter().any(|instr| {
+        instr.uses().contains(&register)
+            || instr.defs().contains(&register)
+            || matches!(instr, LinearInstruction::Cond { branc_if_true, .. }
+                if mentions(&branc_if_true.program, register))
+    })
+}
+
+#[derive(Default)]
+struct Mentions {
+    first: usize,
+    last_read: Option<usize>,
+    writes: Vec<usize>,
+}
+
+/// Walks `program` in textual order, any single run of the block visits positions in this order
+fn collect_mentions<'p>(
+    program: &'p [LinearInstruction],
+    position: &mut usize,
+    prefix: &[usize],
+    mentions: &mut HashMap<&'p str, Mentions>,
+    moves: &mut Vec<(usize, Vec<usize>, &'p Register, &'p Register)>,
+) {
+    for (index, instr) in program.iter().enumerate() {
+        let mut path = prefix.to_vec();
+        path.push(index);
+        let pos = *position;
+        *position += 1;
+        for register in instr.uses() {
+            let entry = mentions
+                .entry(&register.virtual_ident)
+                .or_insert_with(|| Mentions {
+                    first: pos,
+                    ..Default::default()
+                });
+            entry.last_read = Some(pos);
+        }
+        for register in instr.defs() {
+            mentions
+                .entry(&register.virtual_ident)
+                .or_insert_with(|| Mentions {
+                    first: pos,
+                    ..Default::default()
+                })
+                .writes
+                .push(pos);
+        }
+        match instr {
+            LinearInstruction::Move { from_reg, to_reg } => {
+                moves.push((pos, path, from_reg, to_reg))
+            }
+            LinearInstruction::Cond { branc_if_true, .. } => {
+                collect_mentions(&branc_if_true.program, position, &path, mentions, moves)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A Move `from -> to` where `to` can be renamed to `from` everywhere. That needs `to` to be
+/// unused before the Move and `from` to keep its value after it, if `to` is overwritten later
+/// `from` must be dead after the Move too.
+fn mergeable_move(program: &[LinearInstruction]) -> Option<(Vec<usize>, Register, Register)> {
+    let mut mentions = HashMap::new();
+    let mut moves = vec![];
+    collect_mentions(program, &mut 0, &[], &mut mentions, &mut moves);
+    moves
+        .into_iter()
+        .find(|(pos, _, from, to)| {
+            let from = &mentions[from.virtual_ident.as_str()];
+            let to = &mentions[to.virtual_ident.as_str()];
+            to.first == *pos
+                && from.writes.iter().all(|write| write < pos)
+                && (to.writes.len() == 1 || from.last_read <= Some(*pos))
+        })
+        .map(|(_, path, from, to)| (path, from.clone(), to.clone()))
+}
+
+fn remove_at(program: &mut Vec<LinearInstruction>, path: &[usize]) {
+    match path {
+        [index] => {
+            program.remove(*index);
+        }
+        [index, rest @ ..] => match &mut program[*index] {
+            LinearInstruction::Cond { branc_if_true, .. } => {
+                remove_at(&mut branc_if_true.program, rest)
+            }
+            _ => unreachable!("paths only go through Conds"),
+        },
+        [] => {}
+    }
+}
+
+/// Renames `from` to `to` in `instr` and its branch
+fn rename(instr: &mut LinearInstruction, from: &Register, to: &Register) {
+    if let LinearInstruction::Cond { branc_if_true, .. } = instr {
+        for instr in branc_if_true.program.iter_mut() {
+            rename(instr, from, to);
+        }
+    }
+    for register in instr.registers_mut() {
+        if register == from {
+            *register = to.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use little_parser::Programm;
+
+    use super::{stack_to_registers, stack_to_registers_module};
+    use crate::{
+        asm::parse_program,
+        test_ast::{call, ident, int, lambda},
+        value::Value,
+        verify::verify_module,
+        vm::Vm,
+        LinearBlock, LinearInstruction, Translator,
+    };
+
+    fn stack_ops(program: &[LinearInstruction]) -> usize {
+        program
+            .iter()
+            .map(|instr| match instr {
+                LinearInstruction::PushToStack { .. } | LinearInstruction::PopFromStack { .. } => 1,
+                LinearInstruction::Cond { branc_if_true, .. } => stack_ops(&branc_if_true.program),
+                _ => 0,
+            })
+            .sum()
+    }
+
+    #[test]
+    fn calls_lose_their_stack_traffic() {
+        // ((lambda (x) (* x x)) (+ 1 2))
+        let program = Programm::Expression(vec![call(
+            lambda(&["x"], vec![call(ident("*"), vec![ident("x"), ident("x")])]),
+            vec![call(ident("+"), vec![int(1), int(2)])],
+        )]);
+        let mut module = Translator::default().translate_module(program).unwrap();
+        let before: usize = module.iter_blocks().map(|b| stack_ops(&b.program)).sum();
+
+        let stats = stack_to_registers_module(&mut module);
+        let after: usize = module.iter_blocks().map(|b| stack_ops(&b.program)).sum();
+        assert_eq!(stats.removed(), before - after);
+        // main still leaves its result on the stack, nothing else needs it
+        assert_eq!(after, 1);
+        assert_eq!(stats.moves, 0);
+        assert_eq!(verify_module(&module), Ok(()));
+        assert_eq!(
+            Vm::new(&module.blocks).run("main").unwrap(),
+            Value::Integer(9)
+        );
+    }
+
+    #[test]
+    fn keeps_pairs_it_cannot_prove() {
+        let src = "load static0{1} -> vreg0
+                   push vreg0
+                   load static1{2} -> vreg0
+                   pop vreg1
+                   push vreg1
+                   cond0: if vreg1 {
+                       push vreg1
+                       pop vreg2
+                   }
+                   cond0: end
+                   pop vreg3
+                   load static2{3} -> vreg4
+                   push vreg4
+                   pop vreg5
+                   load static3{4} -> vreg5
+                   push vreg4";
+        let mut block = LinearBlock {
+            ident: "test".into(),
+            program: parse_program(src).unwrap(),
+        };
+        let stats = stack_to_registers(&mut block);
+        // Only the pair inside the branch and the vreg4 pair go, the first push is overwritten
+        // before its pop and the second one spans the cond
+        assert_eq!(stats.removed(), 4);
+        // vreg5 is written again while vreg4 is still read afterwards, so its Move stays
+        assert_eq!(stats.moves, 1);
+        let expected = "load static0{1} -> vreg0
+                        push vreg0
+                        load static1{2} -> vreg0
+                        pop vreg1
+                        push vreg1
+                        cond0: if vreg1 {
+                        }
+                        cond0: end
+                        pop vreg3
+                        load static2{3} -> vreg4
+                        move vreg4 -> vreg5
+                        load static3{4} -> vreg5
+                        push vreg4";
+        assert_eq!(block.program, parse_program(expected).unwrap());
+    }
+}
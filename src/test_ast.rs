@@ -0,0 +1,21 @@
+//! Builders for the ASTs the tests feed the translator.
+
+use std::rc::Rc;
+
+use little_parser::{AtomTypes, Expression};
+
+pub fn ident(name: &str) -> Expression {
+    Expression::Identifier(name.into())
+}
+pub fn int(int: i32) -> Expression {
+    Expression::Atom(AtomTypes::Integer(int))
+}
+pub fn boolean(boolean: bool) -> Expression {
+    Expression::Atom(AtomTypes::Boolean(boolean))
+}
+pub fn call(callee: Expression, args: Vec<Expression>) -> Expression {
+    Expression::LambdaCall(Rc::new(callee), args)
+}
+pub fn lambda(formals: &[&str], body: Vec<Expression>) -> Expression {
+    Expression::Lambda(formals.iter().map(|f| f.to_string()).collect(), body)
+}
@@ -0,0 +1,350 @@
+//! Runtime value model for the IR.
+//! What registers, the stack and scopes hold while a program runs, shared by the `vm` and any
+//! backend so they all agree on what a closure or a list built by `LinkedListAdd` is.
+
+use std::{collections::HashMap, fmt, rc::Rc};
+
+use crate::{FunctionPointer, StaticData};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Nil,
+    Void,
+    Integer(i32),
+    Bool(bool),
+    String(String),
+    Symbol(String),
+    Pair(Rc<Value>, Rc<Value>),
+    /// Function pointer plus the scope it was initialized in
+    Closure(FunctionPointer, ScopeHandle),
+    /// Function pointer plus the values of its captured variables, made by MakeClosure
+    FlatClosure(FunctionPointer, Rc<Vec<Value>>),
+    Builtin(Builtin),
+}
+
+impl Value {
+    /// Everything except `#f` counts as true, like in Scheme
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Bool(false))
+    }
+    pub fn from_static_data(data: &StaticData) -> Value {
+        match data {
+            StaticData::Void => Value::Void,
+            StaticData::Bool(boolean) => Value::Bool(*boolean),
+            StaticData::Integer(int) => Value::Integer(*int),
+            StaticData::String(string) => Value::String(string.clone()),
+            StaticData::Identifier(ident) => Value::Symbol(ident.clone()),
+            StaticData::List(list) => {
+                Value::list(list.iter().map(Value::from_static_data).collect())
+            }
+        }
+    }
+    /// The static this value could be loaded from, None for closures, builtins and improper lists
+    pub fn to_static_data(&self) -> Option<StaticData> {
+        match self {
+            Value::Void => Some(StaticData::Void),
+            Value::Integer(int) => Some(StaticData::Integer(*int)),
+            Value::Bool(boolean) => Some(StaticData::Bool(*boolean)),
+            Value::String(string) => Some(StaticData::String(string.clone())),
+            Value::Symbol(symbol) => Some(StaticData::Identifier(symbol.clone())),
+            Value::Nil | Value::Pair(..) => Some(StaticData::List(
+                self.list_items()?
+                    .iter()
+                    .map(Value::to_static_data)
+                    .collect::<Option<_>>()?,
+            )),
+            Value::Closure(..) | Value::FlatClosure(..) | Value::Builtin(_) => None,
+        }
+    }
+    /// Builds a proper cons list ending in `Nil`
+    pub fn list(items: Vec<Value>) -> Value {
+        items.into_iter().rev().fold(Value::Nil, |tail, head| {
+            Value::Pair(Rc::new(head), Rc::new(tail))
+        })
+    }
+    /// Collects a proper list back into a Vec, None if it is not one
+    pub fn list_items(&self) -> Option<Vec<Value>> {
+        let mut items = vec![];
+        let mut current = self;
+        loop {
+            match current {
+                Value::Nil => return Some(items),
+                Value::Pair(head, tail) => {
+                    items.push((**head).clone());
+                    current = tail;
+                }
+                _ => return None,
+            }
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "()"),
+            Value::Void => write!(f, "#<void>"),
+            Value::Integer(int) => write!(f, "{}", int),
+            Value::Bool(true) => write!(f, "#t"),
+            Value::Bool(false) => write!(f, "#f"),
+            Value::String(string) => write!(f, "{:?}", string),
+            Value::Symbol(symbol) => write!(f, "{}", symbol),
+            Value::Pair(head, tail) => {
+                write!(f, "({}", head)?;
+                let mut rest = &**tail;
+                loop {
+                    match rest {
+                        Value::Nil => break,
+                        Value::Pair(head, tail) => {
+                            write!(f, " {}", head)?;
+                            rest = tail;
+                        }
+                        other => {
+                            write!(f, " . {}", other)?;
+                            break;
+                        }
+                    }
+                }
+                write!(f, ")")
+            }
+            Value::Closure(function, _) | Value::FlatClosure(function, _) => {
+                write!(f, "#<procedure {}>", function.actual_func)
+            }
+            Value::Builtin(builtin) => write!(f, "#<builtin {}>", builtin.name()),
+        }
+    }
+}
+
+/// Functions the global scope starts out with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Builtin {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    NumEq,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Not,
+    Car,
+    Cdr,
+    Cons,
+    List,
+    IsNull,
+}
+
+impl Builtin {
+    pub const ALL: [Builtin; 16] = [
+        Builtin::Add,
+        Builtin::Sub,
+        Builtin::Mul,
+        Builtin::Div,
+        Builtin::NumEq,
+        Builtin::Lt,
+        Builtin::Gt,
+        Builtin::Le,
+        Builtin::Ge,
+        Builtin::Eq,
+        Builtin::Not,
+        Builtin::Car,
+        Builtin::Cdr,
+        Builtin::Cons,
+        Builtin::List,
+        Builtin::IsNull,
+    ];
+    pub fn from_name(name: &str) -> Option<Builtin> {
+        Builtin::ALL
+            .into_iter()
+            .find(|builtin| builtin.name() == name)
+    }
+    /// Arguments a PrimOp of this builtin takes, variadic ones stay calls
+    pub fn arity(&self) -> Option<usize> {
+        match self {
+            Builtin::Add
+            | Builtin::Sub
+            | Builtin::Mul
+            | Builtin::Div
+            | Builtin::NumEq
+            | Builtin::Lt
+            | Builtin::Gt
+            | Builtin::Le
+            | Builtin::Ge
+            | Builtin::Eq
+            | Builtin::Cons => Some(2),
+            Builtin::Not | Builtin::Car | Builtin::Cdr | Builtin::IsNull => Some(1),
+            Builtin::List => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Builtin::Add => "+",
+            Builtin::Sub => "-",
+            Builtin::Mul => "*",
+            Builtin::Div => "/",
+            Builtin::NumEq => "=",
+            Builtin::Lt => "<",
+            Builtin::Gt => ">",
+            Builtin::Le => "<=",
+            Builtin::Ge => ">=",
+            Builtin::Eq => "eq?",
+            Builtin::Not => "not",
+            Builtin::Car => "car",
+            Builtin::Cdr => "cdr",
+            Builtin::Cons => "cons",
+            Builtin::List => "list",
+            Builtin::IsNull => "null?",
+        }
+    }
+}
+
+/// Index of one scope inside a `ScopeChain`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ScopeHandle(usize);
+
+#[derive(Debug, Clone)]
+struct ScopeNode {
+    parent: Option<ScopeHandle>,
+    bindings: HashMap<String, Value>,
+    /// Values stored by lexical address, None until something is stored
+    slots: Vec<Option<Value>>,
+}
+
+/// All scopes of a running program, each linked to the one it was attached to.
+/// A closure just keeps its handle around, so a scope is only freed by `release` once no
+/// closure was made in it or below it.
+#[derive(Debug, Clone)]
+pub struct ScopeChain {
+    scopes: Vec<ScopeNode>,
+    /// Scopes below this index may be reachable from a closure
+    pinned: usize,
+}
+
+impl ScopeChain {
+    /// Starts out with only the global scope
+    pub fn new() -> ScopeChain {
+        ScopeChain {
+            scopes: vec![ScopeNode {
+                parent: None,
+                bindings: HashMap::new(),
+                slots: vec![],
+            }],
+            pinned: 1,
+        }
+    }
+    pub fn global(&self) -> ScopeHandle {
+        ScopeHandle(0)
+    }
+    /// New empty scope attached to `parent`, what `NewScopeAttachedToAndReplacingCurrent` and calls make
+    pub fn attach(&mut self, parent: ScopeHandle) -> ScopeHandle {
+        self.scopes.push(ScopeNode {
+            parent: Some(parent),
+            bindings: HashMap::new(),
+            slots: vec![],
+        });
+        ScopeHandle(self.scopes.len() - 1)
+    }
+    pub fn parent(&self, scope: ScopeHandle) -> Option<ScopeHandle> {
+        self.scopes[scope.0].parent
+    }
+    /// Binds `name` in exactly this scope, shadowing anything further up
+    pub fn define(&mut self, scope: ScopeHandle, name: impl Into<String>, value: Value) {
+        self.scopes[scope.0].bindings.insert(name.into(), value);
+    }
+    /// Walks up the parent links until something named `name` is found
+    pub fn lookup(&self, scope: ScopeHandle, name: &str) -> Option<&Value> {
+        let mut current = Some(scope);
+        while let Some(handle) = current {
+            let node = &self.scopes[handle.0];
+            if let Some(value) = node.bindings.get(name) {
+                return Some(value);
+            }
+            current = node.parent;
+        }
+        None
+    }
+    /// The scope `depth` parent links above `scope`
+    pub fn ancestor(&self, scope: ScopeHandle, depth: usize) -> Option<ScopeHandle> {
+        (0..depth).try_fold(scope, |scope, _| self.parent(scope))
+    }
+    pub fn load(&self, scope: ScopeHandle, slot: usize) -> Option<&Value> {
+        self.scopes[scope.0].slots.get(slot)?.as_ref()
+    }
+    pub fn store(&mut self, scope: ScopeHandle, slot: usize, value: Value) {
+        let slots = &mut self.scopes[scope.0].slots;
+        if slots.len() <= slot {
+            slots.resize(slot + 1, None);
+        }
+        slots[slot] = Some(value);
+    }
+    /// Keeps `scope` and everything attached before it alive, for a closure made in it
+    pub fn pin(&mut self, scope: ScopeHandle) {
+        self.pinned = self.pinned.max(scope.0 + 1);
+    }
+    /// Frees the scopes attached since `len` was `mark` that nothing is pinning
+    pub fn release(&mut self, mark: usize) {
+        self.scopes.truncate(mark.max(self.pinned));
+    }
+    pub fn len(&self) -> usize {
+        self.scopes.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.scopes.is_empty()
+    }
+}
+
+impl Default for ScopeChain {
+    fn default() -> Self {
+        ScopeChain::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ScopeChain, Value};
+
+    #[test]
+    fn inner_scope_shadows_and_falls_back() {
+        let mut chain = ScopeChain::new();
+        let global = chain.global();
+        chain.define(global, "x", Value::Integer(1));
+        chain.define(global, "y", Value::Integer(2));
+        let inner = chain.attach(global);
+        chain.define(inner, "x", Value::Integer(3));
+
+        assert_eq!(chain.lookup(inner, "x"), Some(&Value::Integer(3)));
+        assert_eq!(chain.lookup(inner, "y"), Some(&Value::Integer(2)));
+        assert_eq!(chain.lookup(global, "x"), Some(&Value::Integer(1)));
+        assert_eq!(chain.parent(inner), Some(global));
+        assert_eq!(chain.lookup(inner, "z"), None);
+    }
+
+    #[test]
+    fn release_keeps_pinned_scopes() {
+        let mut chain = ScopeChain::new();
+        let mark = chain.len();
+        let captured = chain.attach(chain.global());
+        chain.pin(captured);
+        chain.attach(captured);
+        chain.release(mark);
+        assert_eq!(chain.len(), 2);
+        assert_eq!(chain.parent(captured), Some(chain.global()));
+
+        let mark = chain.len();
+        chain.attach(captured);
+        chain.release(mark);
+        assert_eq!(chain.len(), mark);
+    }
+
+    #[test]
+    fn lists_round_trip() {
+        let list = Value::list(vec![Value::Integer(1), Value::Symbol("a".into())]);
+        assert_eq!(list.to_string(), "(1 a)");
+        assert_eq!(
+            list.list_items(),
+            Some(vec![Value::Integer(1), Value::Symbol("a".into())])
+        );
+    }
+}
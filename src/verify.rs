@@ -0,0 +1,474 @@
+//! Sanity checks for lowered blocks.
+//! Walks every path through a block, including into Cond branches, and tracks stack depth,
+//! open scopes and which registers are definitely written. A taken branch continues at the
+//! EndOfCond of its group, so all states reaching an EndOfCond have to agree. The same goes
+//! for the jumps and the fall-through reaching a Label of a flattened block.
+
+use std::{collections::HashMap, collections::HashSet, fmt};
+
+use crate::{module::IrModule, LinearBlock, LinearInstruction, Register};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    pub block: String,
+    /// Index into the block, followed by indices into nested Cond branches
+    pub path: Vec<usize>,
+    pub kind: VerifyErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyErrorKind {
+    StackUnderflow,
+    UndefinedRegister(String),
+    /// Return with values still on the stack
+    UnbalancedReturn {
+        depth: usize,
+    },
+    PopScopeWithoutNew,
+    /// Scopes still open at a Return or at the end of the block
+    UnclosedScopes {
+        open: usize,
+    },
+    CondWithoutEnd(String),
+    EndWithoutCond(String),
+    /// Paths meeting at an EndOfCond disagree on stack depth or open scopes
+    UnbalancedJoin {
+        cond_name: String,
+        stack_depths: Vec<usize>,
+        scope_depths: Vec<usize>,
+    },
+    /// Jump to a label that is not in the same program
+    UnknownLabel(String),
+    /// Paths meeting at a Label disagree on stack depth or open scopes
+    UnbalancedLabel {
+        label: String,
+        stack_depths: Vec<usize>,
+        scope_depths: Vec<usize>,
+    },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path: Vec<String> = self.path.iter().map(|i| i.to_string()).collect();
+        write!(f, "{}[{}]: ", self.block, path.join("."))?;
+        match &self.kind {
+            VerifyErrorKind::StackUnderflow => write!(f, "pop from empty stack"),
+            VerifyErrorKind::UndefinedRegister(reg) => {
+                write!(f, "register `{}` may be read before written", reg)
+            }
+            VerifyErrorKind::UnbalancedReturn { depth } => {
+                write!(f, "return with {} values left on the stack", depth)
+            }
+            VerifyErrorKind::PopScopeWithoutNew => write!(f, "scope popped that was never pushed"),
+            VerifyErrorKind::UnclosedScopes { open } => write!(f, "{} scopes left open", open),
+            VerifyErrorKind::CondWithoutEnd(name) => write!(f, "`{}` has no EndOfCond", name),
+            VerifyErrorKind::EndWithoutCond(name) => {
+                write!(f, "EndOfCond for `{}` without a Cond", name)
+            }
+            VerifyErrorKind::UnbalancedJoin {
+                cond_name,
+                stack_depths,
+                scope_depths,
+            } => write!(
+                f,
+                "paths joining at `{}` disagree: stack depths {:?}, scope depths {:?}",
+                cond_name, stack_depths, scope_depths
+            ),
+            VerifyErrorKind::UnknownLabel(label) => write!(f, "no label `{}` to jump to", label),
+            VerifyErrorKind::UnbalancedLabel {
+                label,
+                stack_depths,
+                scope_depths,
+            } => write!(
+                f,
+                "paths reaching `{}` disagree: stack depths {:?}, scope depths {:?}",
+                label, stack_depths, scope_depths
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+pub fn verify(block: &LinearBlock) -> Result<(), Vec<VerifyError>> {
+    let mut verifier = Verifier {
+        block: &block.ident,
+        errors: vec![],
+    };
+    let end = verifier.program(&block.program, &[], State::default());
+    if let Some(state) = end {
+        if state.scopes != 0 {
+            verifier.error(
+                vec![block.program.len()],
+                VerifyErrorKind::UnclosedScopes { open: state.scopes },
+            );
+        }
+    }
+    if verifier.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(verifier.errors)
+    }
+}
+
+/// Verifies every block, entry first
+pub fn verify_module(module: &IrModule) -> Result<(), Vec<VerifyError>> {
+    let errors: Vec<VerifyError> = module
+        .iter_blocks()
+        .filter_map(|block| verify(block).err())
+        .flatten()
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct State {
+    depth: usize,
+    scopes: usize,
+    defined: HashSet<String>,
+}
+
+struct Verifier<'b> {
+    block: &'b str,
+    errors: Vec<VerifyError>,
+}
+
+impl Verifier<'_> {
+    fn error(&mut self, path: Vec<usize>, kind: VerifyErrorKind) {
+        self.errors.push(VerifyError {
+            block: self.block.to_string(),
+            path,
+            kind,
+        });
+    }
+    fn read(&mut self, state: &mut State, path: &[usize], register: &Register) {
+        if !state.defined.contains(&register.virtual_ident) {
+            self.error(
+                path.to_vec(),
+                VerifyErrorKind::UndefinedRegister(register.virtual_ident.clone()),
+            );
+            // Only report each register once per path
+            state.defined.insert(register.virtual_ident.clone());
+        }
+    }
+    fn write(state: &mut State, register: &Register) {
+        state.defined.insert(register.virtual_ident.clone());
+    }
+    /// Returns the state falling out of the end of `program`, None if every path returned
+    fn program(
+        &mut self,
+        program: &[LinearInstruction],
+        prefix: &[usize],
+        state: State,
+    ) -> Option<State> {
+        let mut current = Some(state);
+        // Taken branches waiting for their EndOfCond, together with the first Cond's index
+        let mut pending: HashMap<&str, (usize, Vec<State>)> = HashMap::new();
+        let labels: HashSet<&str> = program
+            .iter()
+            .filter_map(|instr| match instr {
+                LinearInstruction::Label { name } => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        // States jumping to labels further down, and the state at labels already passed
+        let mut jumps: HashMap<&str, Vec<State>> = HashMap::new();
+        let mut passed: HashMap<&str, State> = HashMap::new();
+
+        for (index, instr) in program.iter().enumerate() {
+            let mut path = prefix.to_vec();
+            path.push(index);
+
+            if let LinearInstruction::EndOfCond { cond_name } = instr {
+                let mut states = match pending.remove(cond_name.as_str()) {
+                    Some((_, states)) => states,
+                    None => {
+                        self.error(
+                            path.clone(),
+                            VerifyErrorKind::EndWithoutCond(cond_name.clone()),
+                        );
+                        vec![]
+                    }
+                };
+                states.extend(current.take());
+                current = self.join(&path, states, |stack_depths, scope_depths| {
+                    VerifyErrorKind::UnbalancedJoin {
+                        cond_name: cond_name.clone(),
+                        stack_depths,
+                        scope_depths,
+                    }
+                });
+                continue;
+            }
+            if let LinearInstruction::Label { name } = instr {
+                let mut states = jumps.remove(name.as_str()).unwrap_or_default();
+                states.extend(current.take());
+                current = self.join(&path, states, |stack_depths, scope_depths| {
+                    VerifyErrorKind::UnbalancedLabel {
+                        label: name.clone(),
+                        stack_depths,
+                        scope_depths,
+                    }
+                });
+                passed.extend(current.clone().map(|state| (name.as_str(), state)));
+                continue;
+            }
+            // Nothing reaches code after a Return until the next join
+            let Some(state) = current.as_mut() else {
+                continue;
+            };
+            // Each Phi input only has to be defined on its own path, which the join already
+            // forgot about, so only the output is tracked
+            if !matches!(instr, LinearInstruction::Phi { .. }) {
+                for register in instr.uses() {
+                    self.read(state, &path, register);
+                }
+            }
+            for register in instr.defs() {
+                Self::write(state, register);
+            }
+            match instr {
+                LinearInstruction::NewScopeAttachedToAndReplacingCurrent => state.scopes += 1,
+                LinearInstruction::PopScopeAndReplaceWithUpper => {
+                    if state.scopes == 0 {
+                        self.error(path, VerifyErrorKind::PopScopeWithoutNew);
+                    } else {
+                        state.scopes -= 1;
+                    }
+                }
+                LinearInstruction::PushToStack { .. } => state.depth += 1,
+                LinearInstruction::PopFromStack { .. } => {
+                    if state.depth == 0 {
+                        self.error(path, VerifyErrorKind::StackUnderflow);
+                    } else {
+                        state.depth -= 1;
+                    }
+                }
+                LinearInstruction::Cond {
+                    cond_name,
+                    branc_if_true,
+                    ..
+                } => {
+                    let taken = self.program(&branc_if_true.program, &path, state.clone());
+                    let entry = pending
+                        .entry(cond_name.as_str())
+                        .or_insert_with(|| (index, vec![]));
+                    entry.1.extend(taken);
+                }
+                LinearInstruction::Return { .. } => {
+                    if state.depth != 0 {
+                        self.error(
+                            path.clone(),
+                            VerifyErrorKind::UnbalancedReturn { depth: state.depth },
+                        );
+                    }
+                    if state.scopes != 0 {
+                        self.error(path, VerifyErrorKind::UnclosedScopes { open: state.scopes });
+                    }
+                    current = None;
+                }
+                // The frame goes away with whatever is still on the stack or in scope
+                LinearInstruction::TailCall { .. } => current = None,
+                LinearInstruction::Jump { label }
+                | LinearInstruction::JumpIfFalse { label, .. } => {
+                    let jumping = match instr {
+                        LinearInstruction::Jump { .. } => current.take(),
+                        _ => current.clone(),
+                    };
+                    if !labels.contains(label.as_str()) {
+                        self.error(path, VerifyErrorKind::UnknownLabel(label.clone()));
+                    } else if let Some(target) = passed.get(label.as_str()) {
+                        // Jumping back, only the depths can be checked against what is there
+                        let states = vec![target.clone()].into_iter().chain(jumping).collect();
+                        self.join(&path, states, |stack_depths, scope_depths| {
+                            VerifyErrorKind::UnbalancedLabel {
+                                label: label.clone(),
+                                stack_depths,
+                                scope_depths,
+                            }
+                        });
+                    } else {
+                        jumps.entry(label.as_str()).or_default().extend(jumping);
+                    }
+                }
+                LinearInstruction::EndOfCond { .. } | LinearInstruction::Label { .. } => {
+                    unreachable!("handled above")
+                }
+                _ => {}
+            }
+        }
+
+        let mut unterminated: Vec<(&str, usize)> = pending
+            .into_iter()
+            .map(|(name, (index, _))| (name, index))
+            .collect();
+        unterminated.sort_by_key(|(_, index)| *index);
+        for (name, index) in unterminated {
+            let mut path = prefix.to_vec();
+            path.push(index);
+            self.error(path, VerifyErrorKind::CondWithoutEnd(name.to_string()));
+        }
+        current
+    }
+    /// State after the point where `states` meet, `kind` makes the error if they disagree
+    fn join(
+        &mut self,
+        path: &[usize],
+        states: Vec<State>,
+        kind: impl FnOnce(Vec<usize>, Vec<usize>) -> VerifyErrorKind,
+    ) -> Option<State> {
+        let first = states.first()?.clone();
+        let stack_depths: Vec<usize> = states.iter().map(|s| s.depth).collect();
+        let scope_depths: Vec<usize> = states.iter().map(|s| s.scopes).collect();
+        if stack_depths.iter().any(|d| *d != first.depth)
+            || scope_depths.iter().any(|d| *d != first.scopes)
+        {
+            self.error(path.to_vec(), kind(stack_depths, scope_depths));
+        }
+        // Only registers written on every incoming path are safe to read afterwards
+        let defined = states.iter().skip(1).fold(first.defined.clone(), |acc, s| {
+            acc.intersection(&s.defined).cloned().collect()
+        });
+        Some(State { defined, ..first })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use little_parser::{Expression, Programm};
+
+    use super::{verify, verify_module, VerifyErrorKind};
+    use crate::{
+        asm::parse_program,
+        flat::flatten_module,
+        test_ast::{boolean, call, ident, int, lambda},
+        LinearBlock, Translator,
+    };
+
+    fn block(src: &str) -> LinearBlock {
+        LinearBlock {
+            ident: "test".into(),
+            program: parse_program(src).unwrap(),
+        }
+    }
+
+    #[test]
+    fn translated_call_is_clean() {
+        let program =
+            Programm::Expression(vec![call(lambda(&["x"], vec![ident("x")]), vec![int(1)])]);
+        let module = Translator::default().translate_module(program).unwrap();
+        assert_eq!(verify_module(&module), Ok(()));
+    }
+
+    #[test]
+    fn reports_each_problem_with_its_index() {
+        let errors = verify(&block(
+            "pop vreg0
+             push vreg1
+             scope.new
+             ret vreg0",
+        ))
+        .unwrap_err();
+        let found: Vec<(Vec<usize>, VerifyErrorKind)> =
+            errors.into_iter().map(|e| (e.path, e.kind)).collect();
+        assert_eq!(
+            found,
+            vec![
+                (vec![0], VerifyErrorKind::StackUnderflow),
+                (vec![1], VerifyErrorKind::UndefinedRegister("vreg1".into())),
+                (vec![3], VerifyErrorKind::UnbalancedReturn { depth: 1 }),
+                (vec![3], VerifyErrorKind::UnclosedScopes { open: 1 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_cond_groups() {
+        // Only the taken branch pushes, so the two paths into the join disagree
+        let errors = verify(&block(
+            "load static0{#t} -> vreg0
+             cond0: if vreg0 {
+                 push vreg0
+                 pop vreg1
+                 cond1: if vreg1 { }
+             }
+             cond0: end
+             push vreg1",
+        ))
+        .unwrap_err();
+        let kinds: Vec<VerifyErrorKind> = errors.into_iter().map(|e| e.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                VerifyErrorKind::CondWithoutEnd("cond1".into()),
+                VerifyErrorKind::UndefinedRegister("vreg1".into()),
+            ]
+        );
+
+        let errors = verify(&block(
+            "load static0{#t} -> vreg0
+             cond0: if vreg0 {
+                 push vreg0
+             }
+             cond0: end",
+        ))
+        .unwrap_err();
+        assert_eq!(errors[0].path, vec![2]);
+        assert!(matches!(
+            errors[0].kind,
+            VerifyErrorKind::UnbalancedJoin { .. }
+        ));
+    }
+
+    #[test]
+    fn checks_flat_blocks() {
+        // (cond (#f 1) (#t 2))
+        let program = Programm::Expression(vec![Expression::Cond(vec![
+            (boolean(false), int(1)),
+            (boolean(true), int(2)),
+        ])]);
+        let mut module = Translator::default().translate_module(program).unwrap();
+        flatten_module(&mut module);
+        assert_eq!(verify_module(&module), Ok(()));
+
+        // Only the cond result is on the stack after the end label
+        let mut main = module.blocks["main"].clone();
+        let end = main.program.len();
+        main.program
+            .extend(parse_program("pop vreg100\n pop vreg101").unwrap());
+        let errors = verify(&main).unwrap_err();
+        assert_eq!(errors[0].path, vec![end + 1]);
+        assert_eq!(errors[0].kind, VerifyErrorKind::StackUnderflow);
+
+        let errors = verify(&block(
+            "load static0{#t} -> vreg0
+             jump.false vreg0 nowhere",
+        ))
+        .unwrap_err();
+        assert_eq!(errors[0].path, vec![1]);
+        assert_eq!(
+            errors[0].kind,
+            VerifyErrorKind::UnknownLabel("nowhere".into())
+        );
+
+        let errors = verify(&block(
+            "load static0{#t} -> vreg0
+             jump.false vreg0 skip
+             push vreg0
+             label skip",
+        ))
+        .unwrap_err();
+        assert_eq!(
+            errors[0].kind,
+            VerifyErrorKind::UnbalancedLabel {
+                label: "skip".into(),
+                stack_depths: vec![0, 1],
+                scope_depths: vec![0, 0],
+            }
+        );
+    }
+}
@@ -0,0 +1,710 @@
+//! Reference interpreter for the linear IR.
+//! Executes a `LinearBlock` against the blocks in a `lambda_map` so we can check what the
+//! lowering actually does instead of staring at `{:#?}` dumps.
+
+use std::{collections::HashMap, fmt, rc::Rc};
+
+use crate::{
+    value::{Builtin, ScopeChain, ScopeHandle, Value},
+    LinearBlock, LinearInstruction, Register, Scope, StaticData, StaticRef,
+};
+
+impl Builtin {
+    pub fn apply(&self, args: Vec<Value>) -> Result<Value, VmError> {
+        fn int(value: &Value) -> Result<i32, VmError> {
+            match value {
+                Value::Integer(int) => Ok(*int),
+                other => Err(VmError::WrongType {
+                    expected: "integer",
+                    found: other.to_string(),
+                }),
+            }
+        }
+        let arity = |expected: usize| {
+            if args.len() == expected {
+                Ok(())
+            } else {
+                Err(VmError::ArityMismatch {
+                    function: self.name().to_string(),
+                    expected,
+                    got: args.len(),
+                })
+            }
+        };
+        let overflow = || VmError::Overflow(self.name().to_string());
+        match self {
+            Builtin::Add => args
+                .iter()
+                .try_fold(0i32, |acc, x| acc.checked_add(int(x)?).ok_or_else(overflow))
+                .map(Value::Integer),
+            Builtin::Mul => args
+                .iter()
+                .try_fold(1i32, |acc, x| acc.checked_mul(int(x)?).ok_or_else(overflow))
+                .map(Value::Integer),
+            Builtin::Sub => match args.split_first() {
+                None => Err(VmError::ArityMismatch {
+                    function: self.name().to_string(),
+                    expected: 1,
+                    got: 0,
+                }),
+                Some((first, [])) => int(first)?
+                    .checked_neg()
+                    .ok_or_else(overflow)
+                    .map(Value::Integer),
+                Some((first, rest)) => rest
+                    .iter()
+                    .try_fold(int(first)?, |acc, x| {
+                        acc.checked_sub(int(x)?).ok_or_else(overflow)
+                    })
+                    .map(Value::Integer),
+            },
+            Builtin::Div => {
+                arity(2)?;
+                let divisor = int(&args[1])?;
+                if divisor == 0 {
+                    return Err(VmError::DivisionByZero);
+                }
+                int(&args[0])?
+                    .checked_div(divisor)
+                    .ok_or_else(overflow)
+                    .map(Value::Integer)
+            }
+            Builtin::NumEq | Builtin::Lt | Builtin::Gt | Builtin::Le | Builtin::Ge => {
+                arity(2)?;
+                let (a, b) = (int(&args[0])?, int(&args[1])?);
+                Ok(Value::Bool(match self {
+                    Builtin::NumEq => a == b,
+                    Builtin::Lt => a < b,
+                    Builtin::Gt => a > b,
+                    Builtin::Le => a <= b,
+                    _ => a >= b,
+                }))
+            }
+            Builtin::Eq => {
+                arity(2)?;
+                Ok(Value::Bool(args[0] == args[1]))
+            }
+            Builtin::Not => {
+                arity(1)?;
+                Ok(Value::Bool(!args[0].is_truthy()))
+            }
+            Builtin::Car | Builtin::Cdr => {
+                arity(1)?;
+                match &args[0] {
+                    Value::Pair(head, tail) => Ok(if *self == Builtin::Car {
+                        (**head).clone()
+                    } else {
+                        (**tail).clone()
+                    }),
+                    other => Err(VmError::WrongType {
+                        expected: "pair",
+                        found: other.to_string(),
+                    }),
+                }
+            }
+            Builtin::Cons => {
+                arity(2)?;
+                Ok(Value::Pair(
+                    Rc::new(args[0].clone()),
+                    Rc::new(args[1].clone()),
+                ))
+            }
+            Builtin::List => Ok(Value::list(args)),
+            Builtin::IsNull => {
+                arity(1)?;
+                Ok(Value::Bool(args[0] == Value::Nil))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    UnknownBlock(String),
+    UndefinedRegister(String),
+    UnboundIdentifier(String),
+    /// StaticRef used as a name does not hold an identifier or string
+    NotAName(String),
+    StackUnderflow,
+    NotCallable(String),
+    NotAList(String),
+    ArityMismatch {
+        function: String,
+        expected: usize,
+        got: usize,
+    },
+    WrongType {
+        expected: &'static str,
+        found: String,
+    },
+    /// AcceptToFormals outside of a call
+    NoArguments,
+    MissingEndOfCond(String),
+    UnknownLabel(String),
+    /// Phi reached on a path it has no input for
+    NoPhiInput(String),
+    EmptySlot(usize),
+    /// LoadCapture past the values the closure was made with
+    NoCapture(usize),
+    /// LoadLocal of a slot nothing was stored to yet, or above the outermost scope
+    UnsetLocal {
+        depth: usize,
+        slot: usize,
+    },
+    MissingReturn(String),
+    Overflow(String),
+    DivisionByZero,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::UnknownBlock(name) => write!(f, "no block named `{}`", name),
+            VmError::UndefinedRegister(reg) => write!(f, "register `{}` read before written", reg),
+            VmError::UnboundIdentifier(ident) => write!(f, "unbound identifier `{}`", ident),
+            VmError::NotAName(refname) => write!(f, "static `{}` is not a name", refname),
+            VmError::StackUnderflow => write!(f, "pop from empty stack"),
+            VmError::NotCallable(value) => write!(f, "`{}` is not callable", value),
+            VmError::NotAList(value) => write!(f, "`{}` is not a proper list", value),
+            VmError::ArityMismatch {
+                function,
+                expected,
+                got,
+            } => write!(
+                f,
+                "`{}` expects {} arguments but got {}",
+                function, expected, got
+            ),
+            VmError::WrongType { expected, found } => {
+                write!(f, "expected {} but found `{}`", expected, found)
+            }
+            VmError::NoArguments => write!(f, "AcceptToFormals outside of a call"),
+            VmError::MissingEndOfCond(name) => write!(f, "no EndOfCond for `{}`", name),
+            VmError::UnknownLabel(label) => write!(f, "no label `{}` to jump to", label),
+            VmError::NoPhiInput(name) => write!(f, "phi of `{}` has no input for this path", name),
+            VmError::EmptySlot(slot) => write!(f, "slot {} reloaded before spilled to", slot),
+            VmError::NoCapture(index) => write!(f, "closure has no capture {}", index),
+            VmError::UnsetLocal { depth, slot } => {
+                write!(f, "local {}:{} read before it was set", depth, slot)
+            }
+            VmError::MissingReturn(name) => write!(f, "block `{}` ended without Return", name),
+            VmError::Overflow(op) => write!(f, "integer overflow in `{}`", op),
+            VmError::DivisionByZero => write!(f, "division by zero"),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+#[derive(Debug)]
+struct Frame {
+    registers: HashMap<String, Value>,
+    stack: Vec<Value>,
+    /// Spill slots of the register allocator
+    slots: HashMap<usize, Value>,
+    scope: ScopeHandle,
+    /// Set by Call and taken by AcceptToFormals
+    arguments: Option<Value>,
+    /// Values of a FlatClosure being called, read by LoadCapture
+    captures: Rc<Vec<Value>>,
+}
+
+impl Frame {
+    fn new(scope: ScopeHandle, arguments: Option<Value>) -> Frame {
+        Frame {
+            registers: HashMap::new(),
+            stack: vec![],
+            slots: HashMap::new(),
+            scope,
+            arguments,
+            captures: Rc::default(),
+        }
+    }
+    fn read(&self, register: &Register) -> Result<Value, VmError> {
+        self.registers
+            .get(&register.virtual_ident)
+            .cloned()
+            .ok_or_else(|| VmError::UndefinedRegister(register.virtual_ident.clone()))
+    }
+    fn write(&mut self, register: &Register, value: Value) {
+        self.registers.insert(register.virtual_ident.clone(), value);
+    }
+}
+
+enum Flow {
+    Continue,
+    Return(Value),
+    /// Function and arguments to call in place of the returning frame
+    TailCall(Value, Value),
+}
+
+#[derive(Debug)]
+pub struct Vm<'a> {
+    blocks: &'a HashMap<String, LinearBlock>,
+    scopes: ScopeChain,
+}
+
+impl<'a> Vm<'a> {
+    /// `blocks` is usually the Translators `lambda_map`, which also holds `main`
+    pub fn new(blocks: &'a HashMap<String, LinearBlock>) -> Vm<'a> {
+        let mut scopes = ScopeChain::new();
+        for builtin in Builtin::ALL {
+            scopes.define(scopes.global(), builtin.name(), Value::Builtin(builtin));
+        }
+        Vm { blocks, scopes }
+    }
+    pub fn scopes(&self) -> &ScopeChain {
+        &self.scopes
+    }
+    /// Runs the named block as a toplevel program and returns whatever it left on top of the stack
+    pub fn run(&mut self, ident: &str) -> Result<Value, VmError> {
+        let block = self
+            .blocks
+            .get(ident)
+            .ok_or_else(|| VmError::UnknownBlock(ident.to_string()))?;
+        self.run_block(block)
+    }
+    pub fn run_block(&mut self, block: &LinearBlock) -> Result<Value, VmError> {
+        let mut frame = Frame::new(self.scopes.global(), None);
+        match self.exec(&block.program, &mut frame)? {
+            Flow::Return(value) => Ok(value),
+            Flow::TailCall(function, arguments) => self.call(function, arguments),
+            Flow::Continue => Ok(frame.stack.pop().unwrap_or(Value::Nil)),
+        }
+    }
+    /// Tail calls loop here instead of recursing, so they run in constant stack
+    pub fn call(&mut self, mut function: Value, mut arguments: Value) -> Result<Value, VmError> {
+        loop {
+            match function {
+                Value::Closure(..) | Value::FlatClosure(..) => {
+                    let (pointer, scope, captures) = match function {
+                        Value::Closure(pointer, scope) => (pointer, scope, Rc::default()),
+                        // Everything not captured is global
+                        Value::FlatClosure(pointer, captures) => {
+                            (pointer, self.scopes.global(), captures)
+                        }
+                        _ => unreachable!("matched above"),
+                    };
+                    let block = self
+                        .blocks
+                        .get(&pointer.actual_func)
+                        .ok_or_else(|| VmError::UnknownBlock(pointer.actual_func.clone()))?;
+                    // Every call gets a fresh scope below the one the closure was made in
+                    let mark = self.scopes.len();
+                    let call_scope = self.scopes.attach(scope);
+                    let mut frame = Frame::new(call_scope, Some(arguments));
+                    frame.captures = captures;
+                    let flow = self.exec(&block.program, &mut frame)?;
+                    // The scopes this frame made die with it unless a closure captured them
+                    self.scopes.release(mark);
+                    match flow {
+                        Flow::Return(value) => return Ok(value),
+                        Flow::TailCall(callee, args) => {
+                            function = callee;
+                            arguments = args;
+                        }
+                        Flow::Continue => return Err(VmError::MissingReturn(block.ident.clone())),
+                    }
+                }
+                Value::Builtin(builtin) => {
+                    let args = arguments
+                        .list_items()
+                        .ok_or_else(|| VmError::NotAList(arguments.to_string()))?;
+                    return builtin.apply(args);
+                }
+                other => return Err(VmError::NotCallable(other.to_string())),
+            }
+        }
+    }
+    fn exec(&mut self, program: &[LinearInstruction], frame: &mut Frame) -> Result<Flow, VmError> {
+        let mut pc = 0;
+        // Clauses of each cond seen so far and which one was taken, for Phis after the join
+        let mut clauses: HashMap<&str, usize> = HashMap::new();
+        let mut arrived: HashMap<&str, Option<usize>> = HashMap::new();
+        while pc < program.len() {
+            match &program[pc] {
+                LinearInstruction::AcceptToFormals {
+                    static_formals_list,
+                } => {
+                    let arguments = frame.arguments.take().ok_or(VmError::NoArguments)?;
+                    let args = arguments
+                        .list_items()
+                        .ok_or_else(|| VmError::NotAList(arguments.to_string()))?;
+                    let formals = match &static_formals_list.reftype {
+                        StaticData::List(formals) => formals,
+                        _ => return Err(VmError::NotAName(static_formals_list.refname.clone())),
+                    };
+                    if formals.len() != args.len() {
+                        return Err(VmError::ArityMismatch {
+                            function: static_formals_list.refname.clone(),
+                            expected: formals.len(),
+                            got: args.len(),
+                        });
+                    }
+                    // By name for lookups and by position for LoadLocal
+                    for (slot, (formal, arg)) in formals.iter().zip(args).enumerate() {
+                        let name = formal.as_name().ok_or_else(|| {
+                            VmError::NotAName(static_formals_list.refname.clone())
+                        })?;
+                        self.scopes.store(frame.scope, slot, arg.clone());
+                        self.scopes.define(frame.scope, name, arg);
+                    }
+                }
+                LinearInstruction::NewScopeAttachedToAndReplacingCurrent => {
+                    frame.scope = self.scopes.attach(frame.scope);
+                }
+                LinearInstruction::PopScopeAndReplaceWithUpper => {
+                    // Popping the outermost scope just leaves us there
+                    if let Some(parent) = self.scopes.parent(frame.scope) {
+                        frame.scope = parent;
+                    }
+                }
+                LinearInstruction::StaticRefToRegister { static_ref, to_reg } => {
+                    frame.write(to_reg, Value::from_static_data(&static_ref.reftype));
+                }
+                LinearInstruction::PushToStack { register } => {
+                    let value = frame.read(register)?;
+                    frame.stack.push(value);
+                }
+                LinearInstruction::PopFromStack { register } => {
+                    let value = frame.stack.pop().ok_or(VmError::StackUnderflow)?;
+                    frame.write(register, value);
+                }
+                LinearInstruction::LinkedListInit { output_reg } => {
+                    frame.write(output_reg, Value::Nil);
+                }
+                LinearInstruction::LinkedListAdd {
+                    linked_list_reg,
+                    input_reg,
+                    output_reg,
+                } => {
+                    // Appends to the end so arguments stay in source order
+                    let list = frame.read(linked_list_reg)?;
+                    let mut items = list
+                        .list_items()
+                        .ok_or_else(|| VmError::NotAList(list.to_string()))?;
+                    items.push(frame.read(input_reg)?);
+                    frame.write(output_reg, Value::list(items));
+                }
+                LinearInstruction::Assign {
+                    identifier,
+                    from_reg,
+                    scope,
+                } => {
+                    let name = static_name(identifier)?.to_string();
+                    let target = self.resolve_scope(scope, frame)?;
+                    let value = frame.read(from_reg)?;
+                    self.scopes.define(target, name, value);
+                }
+                LinearInstruction::Call {
+                    output_reg,
+                    function_pointer,
+                    arguments,
+                } => {
+                    let function = frame.read(function_pointer)?;
+                    let arguments = frame.read(arguments)?;
+                    let result = self.call(function, arguments)?;
+                    frame.write(output_reg, result);
+                }
+                LinearInstruction::Lookup {
+                    identifier,
+                    to_reg,
+                    scope,
+                } => {
+                    let name = static_name(identifier)?;
+                    let start = self.resolve_scope(scope, frame)?;
+                    let value = self
+                        .scopes
+                        .lookup(start, name)
+                        .cloned()
+                        .ok_or_else(|| VmError::UnboundIdentifier(name.to_string()))?;
+                    frame.write(to_reg, value);
+                }
+                LinearInstruction::Cond {
+                    cond_name,
+                    condition,
+                    branc_if_true,
+                } => {
+                    let clause = clauses.entry(cond_name).or_insert(0);
+                    let index = *clause;
+                    *clause += 1;
+                    if frame.read(condition)?.is_truthy() {
+                        match self.exec(&branc_if_true.program, frame)? {
+                            Flow::Continue => {}
+                            flow => return Ok(flow),
+                        }
+                        // A taken branch skips the remaining clauses of its cond
+                        pc = find_end_of_cond(program, pc, cond_name)
+                            .ok_or_else(|| VmError::MissingEndOfCond(cond_name.clone()))?;
+                        clauses.remove(cond_name.as_str());
+                        arrived.insert(cond_name, Some(index));
+                    }
+                }
+                LinearInstruction::EndOfCond { cond_name } => {
+                    clauses.remove(cond_name.as_str());
+                    arrived.insert(cond_name, None);
+                }
+                LinearInstruction::Return { value } => {
+                    return Ok(Flow::Return(frame.read(value)?));
+                }
+                LinearInstruction::TailCall {
+                    function_pointer,
+                    arguments,
+                } => {
+                    return Ok(Flow::TailCall(
+                        frame.read(function_pointer)?,
+                        frame.read(arguments)?,
+                    ));
+                }
+                LinearInstruction::InitializeFunctionPointer {
+                    function,
+                    from_scope,
+                    outpu_reg,
+                } => {
+                    let scope = self.resolve_scope(from_scope, frame)?;
+                    self.scopes.pin(scope);
+                    frame.write(outpu_reg, Value::Closure(function.clone(), scope));
+                }
+                LinearInstruction::Move { from_reg, to_reg } => {
+                    let value = frame.read(from_reg)?;
+                    frame.write(to_reg, value);
+                }
+                LinearInstruction::Phi {
+                    cond_name,
+                    inputs,
+                    output_reg,
+                } => {
+                    let edge = arrived
+                        .get(cond_name.as_str())
+                        .ok_or_else(|| VmError::NoPhiInput(cond_name.clone()))?;
+                    let (_, input) = inputs
+                        .iter()
+                        .find(|(clause, _)| clause == edge)
+                        .ok_or_else(|| VmError::NoPhiInput(cond_name.clone()))?;
+                    let value = frame.read(input)?;
+                    frame.write(output_reg, value);
+                }
+                LinearInstruction::SpillToSlot { from_reg, slot } => {
+                    let value = frame.read(from_reg)?;
+                    frame.slots.insert(*slot, value);
+                }
+                LinearInstruction::ReloadFromSlot { slot, to_reg } => {
+                    let value = frame
+                        .slots
+                        .get(slot)
+                        .cloned()
+                        .ok_or(VmError::EmptySlot(*slot))?;
+                    frame.write(to_reg, value);
+                }
+                LinearInstruction::MakeClosure {
+                    function,
+                    captures,
+                    output_reg,
+                } => {
+                    let captures = captures
+                        .iter()
+                        .map(|capture| frame.read(capture))
+                        .collect::<Result<_, _>>()?;
+                    let closure = Value::FlatClosure(function.clone(), Rc::new(captures));
+                    frame.write(output_reg, closure);
+                }
+                LinearInstruction::LoadCapture { index, to_reg } => {
+                    let value = frame
+                        .captures
+                        .get(*index)
+                        .cloned()
+                        .ok_or(VmError::NoCapture(*index))?;
+                    frame.write(to_reg, value);
+                }
+                LinearInstruction::PrimOp {
+                    op,
+                    arguments,
+                    output_reg,
+                } => {
+                    let args = arguments
+                        .iter()
+                        .map(|argument| frame.read(argument))
+                        .collect::<Result<_, _>>()?;
+                    frame.write(output_reg, op.apply(args)?);
+                }
+                LinearInstruction::LoadLocal {
+                    depth,
+                    slot,
+                    to_reg,
+                } => {
+                    let unset = || VmError::UnsetLocal {
+                        depth: *depth,
+                        slot: *slot,
+                    };
+                    let scope = self
+                        .scopes
+                        .ancestor(frame.scope, *depth)
+                        .ok_or_else(unset)?;
+                    let value = self.scopes.load(scope, *slot).cloned().ok_or_else(unset)?;
+                    frame.write(to_reg, value);
+                }
+                LinearInstruction::StoreLocal {
+                    depth,
+                    slot,
+                    from_reg,
+                } => {
+                    let scope =
+                        self.scopes
+                            .ancestor(frame.scope, *depth)
+                            .ok_or(VmError::UnsetLocal {
+                                depth: *depth,
+                                slot: *slot,
+                            })?;
+                    let value = frame.read(from_reg)?;
+                    self.scopes.store(scope, *slot, value);
+                }
+                LinearInstruction::Label { .. } => {}
+                LinearInstruction::Jump { label } => {
+                    pc = find_label(program, label)?;
+                }
+                LinearInstruction::JumpIfFalse { condition, label } => {
+                    if !frame.read(condition)?.is_truthy() {
+                        pc = find_label(program, label)?;
+                    }
+                }
+            }
+            pc += 1;
+        }
+        Ok(Flow::Continue)
+    }
+    fn resolve_scope(&self, scope: &Scope, frame: &Frame) -> Result<ScopeHandle, VmError> {
+        match scope {
+            Scope::Global => Ok(self.scopes.global()),
+            Scope::Current => Ok(frame.scope),
+            // A custom scope is the one captured by the closure in the register
+            Scope::Custom(register) => match frame.read(register)? {
+                Value::Closure(_, scope) => Ok(scope),
+                other => Err(VmError::WrongType {
+                    expected: "closure",
+                    found: other.to_string(),
+                }),
+            },
+        }
+    }
+}
+
+fn static_name(static_ref: &StaticRef) -> Result<&str, VmError> {
+    static_ref
+        .reftype
+        .as_name()
+        .ok_or_else(|| VmError::NotAName(static_ref.refname.clone()))
+}
+
+fn find_label(program: &[LinearInstruction], label: &str) -> Result<usize, VmError> {
+    program
+        .iter()
+        .position(|instr| matches!(instr, LinearInstruction::Label { name } if name == label))
+        .ok_or_else(|| VmError::UnknownLabel(label.to_string()))
+}
+
+fn find_end_of_cond(program: &[LinearInstruction], from: usize, name: &str) -> Option<usize> {
+    program[from..]
+        .iter()
+        .position(|instr| matches!(instr, LinearInstruction::EndOfCond { cond_name } if cond_name == name))
+        .map(|offset| from + offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use little_parser::{Expression, Programm};
+
+    use super::{Value, Vm};
+    use crate::{
+        test_ast::{boolean, call, ident, int, lambda},
+        verify::verify_module,
+        Translator,
+    };
+
+    fn run(exprs: Vec<Expression>) -> Value {
+        let mut translator = Translator::default();
+        translator
+            .ast_to_intermediate_representation(Programm::Expression(exprs))
+            .unwrap();
+        Vm::new(&translator.lambda_map).run("main").unwrap()
+    }
+
+    #[test]
+    fn calls_defined_lambda() {
+        let square = lambda(&["x"], vec![call(ident("*"), vec![ident("x"), ident("x")])]);
+        let res = run(vec![
+            Expression::Define("square".into(), Rc::new(square)),
+            call(ident("square"), vec![int(7)]),
+        ]);
+        assert_eq!(res, Value::Integer(49));
+    }
+
+    #[test]
+    fn recursion_through_cond() {
+        // (define fact (lambda (n) (cond ((= n 0) 1) (#t (* n (fact (- n 1)))))))
+        let fact = lambda(
+            &["n"],
+            vec![Expression::Cond(vec![
+                (call(ident("="), vec![ident("n"), int(0)]), int(1)),
+                (
+                    boolean(true),
+                    call(
+                        ident("*"),
+                        vec![
+                            ident("n"),
+                            call(
+                                ident("fact"),
+                                vec![call(ident("-"), vec![ident("n"), int(1)])],
+                            ),
+                        ],
+                    ),
+                ),
+            ])],
+        );
+        let res = run(vec![
+            Expression::Define("fact".into(), Rc::new(fact)),
+            call(ident("fact"), vec![int(5)]),
+        ]);
+        assert_eq!(res, Value::Integer(120));
+    }
+
+    #[test]
+    fn tail_calls_reuse_the_frame() {
+        // (define count (lambda (n acc) (cond ((= n 0) acc) (#t (let () (count (- n 1) (+ acc 1)))))))
+        let count = lambda(
+            &["n", "acc"],
+            vec![Expression::Cond(vec![
+                (call(ident("="), vec![ident("n"), int(0)]), ident("acc")),
+                (
+                    boolean(true),
+                    Expression::Let(
+                        vec![],
+                        vec![call(
+                            ident("count"),
+                            vec![
+                                call(ident("-"), vec![ident("n"), int(1)]),
+                                call(ident("+"), vec![ident("acc"), int(1)]),
+                            ],
+                        )],
+                    ),
+                ),
+            ])],
+        );
+        let exprs = vec![
+            Expression::Define("count".into(), Rc::new(count)),
+            call(ident("count"), vec![int(200_000), int(0)]),
+        ];
+        let module = Translator::default()
+            .translate_module(Programm::Expression(exprs))
+            .unwrap();
+        let lambda = module.iter_lambdas().next().unwrap();
+        assert!(format!("{}", lambda).contains("tailcall"));
+        assert_eq!(verify_module(&module), Ok(()));
+        // Deep enough to overflow the stack if every call nested, and to run out of memory if
+        // every call and let kept its scope
+        let mut vm = Vm::new(&module.blocks);
+        assert_eq!(vm.run("main").unwrap(), Value::Integer(200_000));
+        assert!(vm.scopes().len() < 8, "{} scopes", vm.scopes().len());
+    }
+}
@@ -0,0 +1,772 @@
+//! x86-64 backend.
+//! `emit_module` turns a module into GNU as text (Intel syntax, System V ABI) that links
+//! against `RUNTIME`, a freestanding C runtime doing the lists, scopes, calls and allocation:
+//!
+//! ```text
+//! cc -c -O2 -ffreestanding -fno-builtin -fno-stack-protector -fno-pie runtime.c
+//! as program.s -o program.o
+//! ld program.o runtime.o -o program
+//! ```
+//!
+//! Every block becomes a function `Value *(Value *closure, Value *args)`. Registers and spill
+//! slots live in its frame, the IR stack is the machine stack below them. Conds are flattened
+//! first so run `ssa::from_ssa` before, and `verify` since nothing is checked at runtime that
+//! the vm would only find out about by running into it, like reading an unwritten register.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Write},
+};
+
+use crate::{
+    flat, module::IrModule, value::Builtin, LinearBlock, LinearInstruction, Register, Scope,
+    StaticData, StaticRef,
+};
+
+/// The runtime the emitted assembly links against
+pub const RUNTIME: &str = include_str!("x86_64_runtime.c");
+
+/// Tags of the Value struct in the runtime
+const TAG_NIL: u8 = 0;
+const TAG_VOID: u8 = 1;
+const TAG_INTEGER: u8 = 2;
+const TAG_BOOL: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_SYMBOL: u8 = 5;
+const TAG_PAIR: u8 = 6;
+
+/// Frame slots below rbp, registers and spill slots come after these
+const SAVED_RBX: usize = 8;
+const CLOSURE: usize = 16;
+const ARGUMENTS: usize = 24;
+const SCOPE: usize = 32;
+/// rsp right after the prologue, the IR stack is empty when rsp is back here
+const STACK_BASE: usize = 40;
+const TEMP: usize = 48;
+const FIXED_SLOTS: usize = 6;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodegenError {
+    /// Block still has Phis in it, run `ssa::from_ssa` first
+    Phi(String),
+    UnknownBlock(String),
+    /// StaticRef to something not in the static table
+    UnknownStatic(String),
+    /// StaticRef used as a name or formals list does not hold identifiers or strings
+    NotAName(String),
+    UnknownLabel(String),
+}
+
+impl fmt::Display for CodegenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodegenError::Phi(block) => write!(f, "block `{}` still has phis", block),
+            CodegenError::UnknownBlock(name) => write!(f, "no block named `{}`", name),
+            CodegenError::UnknownStatic(refname) => write!(f, "no static named `{}`", refname),
+            CodegenError::NotAName(refname) => write!(f, "static `{}` is not a name", refname),
+            CodegenError::UnknownLabel(label) => write!(f, "no label `{}` to jump to", label),
+        }
+    }
+}
+
+impl std::error::Error for CodegenError {}
+
+/// Writing to a String cannot fail
+macro_rules! emit {
+    ($out:expr, $($arg:tt)*) => {
+        let _ = writeln!($out, $($arg)*);
+    };
+}
+
+pub fn emit_module(module: &IrModule) -> Result<String, CodegenError> {
+    let entry = module
+        .block(&module.entry)
+        .ok_or_else(|| CodegenError::UnknownBlock(module.entry.clone()))?;
+    let mut out = String::new();
+    emit!(out, ".intel_syntax noprefix");
+    emit!(out, ".text");
+    emit!(out, ".globl lir_entry");
+    emit!(out, ".set lir_entry, {}", block_symbol(&entry.ident));
+    for block in module.iter_blocks() {
+        let mut block = block.clone();
+        flat::flatten(&mut block);
+        BlockEmitter::new(module, &block, &mut out).emit()?;
+    }
+
+    emit!(out, ".section .rodata");
+    for block in module.iter_blocks() {
+        emit!(out, "{}.name:", block_symbol(&block.ident));
+        emit_bytes(&mut out, &block.ident);
+    }
+    let mut refnames: Vec<&String> = module.static_data.keys().collect();
+    refnames.sort();
+    for refname in refnames {
+        let symbol = static_symbol(refname);
+        emit_static(
+            &mut out,
+            &symbol,
+            &module.static_data[refname],
+            &symbol,
+            &mut 0,
+        );
+    }
+    Ok(out)
+}
+
+/// Keeps letters, digits and `_`, everything else becomes `.` and its hex code, so the result
+/// never has `.` followed by anything but two hex digits
+fn mangle(name: &str) -> String {
+    name.bytes()
+        .map(|byte| {
+            if byte.is_ascii_alphanumeric() || byte == b'_' {
+                (byte as char).to_string()
+            } else {
+                format!(".{:02x}", byte)
+            }
+        })
+        .collect()
+}
+
+fn block_symbol(ident: &str) -> String {
+    format!("lir_{}", mangle(ident))
+}
+
+fn static_symbol(refname: &str) -> String {
+    format!("lir_static_{}", mangle(refname))
+}
+
+fn emit_bytes(out: &mut String, text: &str) {
+    let bytes: Vec<String> = text.bytes().chain([0]).map(|b| b.to_string()).collect();
+    emit!(out, "    .byte {}", bytes.join(", "));
+}
+
+fn emit_value(out: &mut String, label: &str, tag: u8, a: &str, b: &str) {
+    emit!(out, "    .balign 8");
+    emit!(out, "{}:", label);
+    emit!(out, "    .quad {}, {}, {}, 0", tag, a, b);
+}
+
+/// Emits `data` as a Value at `label`, what it points to gets labels after `base`
+fn emit_static(out: &mut String, label: &str, data: &StaticData, base: &str, parts: &mut usize) {
+    let mut part = || {
+        *parts += 1;
+        format!("{}.part{}", base, parts)
+    };
+    match data {
+        StaticData::Void => emit_value(out, label, TAG_VOID, "0", "0"),
+        StaticData::Bool(boolean) => {
+            emit_value(out, label, TAG_BOOL, &(*boolean as u8).to_string(), "0")
+        }
+        StaticData::Integer(int) => emit_value(out, label, TAG_INTEGER, &int.to_string(), "0"),
+        StaticData::String(text) | StaticData::Identifier(text) => {
+            let tag = match data {
+                StaticData::String(_) => TAG_STRING,
+                _ => TAG_SYMBOL,
+            };
+            let text_label = part();
+            emit_value(out, label, tag, &text_label, "0");
+            emit!(out, "{}:", text_label);
+            emit_bytes(out, text);
+        }
+        StaticData::List(items) if items.is_empty() => emit_value(out, label, TAG_NIL, "0", "0"),
+        StaticData::List(items) => {
+            let cells: Vec<String> = std::iter::once(label.to_string())
+                .chain((1..items.len()).map(|_| part()))
+                .collect();
+            let items: Vec<(String, &StaticData)> =
+                items.iter().map(|item| (part(), item)).collect();
+            for (index, (item_label, item)) in items.iter().enumerate() {
+                let next = cells.get(index + 1).map_or("rt_nil_value", String::as_str);
+                emit_value(out, &cells[index], TAG_PAIR, item_label, next);
+                emit_static(out, item_label, item, base, parts);
+            }
+        }
+    }
+}
+
+struct BlockEmitter<'a> {
+    module: &'a IrModule,
+    block: &'a LinearBlock,
+    out: &'a mut String,
+    /// Frame offset of every register and spill slot
+    registers: HashMap<&'a str, usize>,
+    spills: HashMap<usize, usize>,
+    labels: HashSet<&'a str>,
+}
+
+impl<'a> BlockEmitter<'a> {
+    fn new(module: &'a IrModule, block: &'a LinearBlock, out: &'a mut String) -> Self {
+        let mut registers = HashMap::new();
+        let mut spills = HashMap::new();
+        let mut labels = HashSet::new();
+        let mut next = FIXED_SLOTS;
+        let mut slot = || {
+            next += 1;
+            next * 8
+        };
+        for instr in &block.program {
+            for register in instr.uses().into_iter().chain(instr.defs()) {
+                registers
+                    .entry(register.virtual_ident.as_str())
+                    .or_insert_with(&mut slot);
+            }
+            match instr {
+                LinearInstruction::SpillToSlot { slot: spill, .. }
+                | LinearInstruction::ReloadFromSlot { slot: spill, .. } => {
+                    spills.entry(*spill).or_insert_with(&mut slot);
+                }
+                LinearInstruction::Label { name } => {
+                    labels.insert(name.as_str());
+                }
+                _ => {}
+            }
+        }
+        BlockEmitter {
+            module,
+            block,
+            out,
+            registers,
+            spills,
+            labels,
+        }
+    }
+    fn symbol(&self) -> String {
+        block_symbol(&self.block.ident)
+    }
+    fn label(&self, name: &str) -> String {
+        format!(".L{}.label_{}", mangle(&self.block.ident), mangle(name))
+    }
+    fn reg(&self, register: &Register) -> String {
+        format!(
+            "qword ptr [rbp - {}]",
+            self.registers[register.virtual_ident.as_str()]
+        )
+    }
+    fn line(&mut self, line: impl fmt::Display) {
+        emit!(self.out, "    {}", line);
+    }
+    /// Runtime call with rsp aligned to 16 whatever is on the IR stack, rbx survives it
+    fn call(&mut self, function: &str) {
+        self.line("mov rbx, rsp");
+        self.line("and rsp, -16");
+        self.line(format!("call {}", function));
+        self.line("mov rsp, rbx");
+    }
+    fn static_ref(&mut self, register: &str, static_ref: &StaticRef) -> Result<(), CodegenError> {
+        if !self.module.static_data.contains_key(&static_ref.refname) {
+            return Err(CodegenError::UnknownStatic(static_ref.refname.clone()));
+        }
+        self.line(format!(
+            "lea {}, [rip + {}]",
+            register,
+            static_symbol(&static_ref.refname)
+        ));
+        Ok(())
+    }
+    fn name(&mut self, register: &str, static_ref: &StaticRef) -> Result<(), CodegenError> {
+        match &static_ref.reftype {
+            StaticData::Identifier(_) | StaticData::String(_) => {
+                self.static_ref(register, static_ref)
+            }
+            _ => Err(CodegenError::NotAName(static_ref.refname.clone())),
+        }
+    }
+    fn scope(&mut self, register: &str, scope: &Scope) {
+        match scope {
+            Scope::Global => self.line(format!(
+                "mov {}, qword ptr [rip + rt_global_scope]",
+                register
+            )),
+            Scope::Current => self.line(format!("mov {}, qword ptr [rbp - {}]", register, SCOPE)),
+            Scope::Custom(closure) => {
+                self.line(format!("mov rdi, {}", self.reg(closure)));
+                self.call("rt_closure_scope");
+                self.line(format!("mov {}, rax", register));
+            }
+        }
+    }
+    fn function(&mut self, ident: &str) -> Result<(String, String), CodegenError> {
+        if self.module.block(ident).is_none() {
+            return Err(CodegenError::UnknownBlock(ident.to_string()));
+        }
+        let symbol = block_symbol(ident);
+        Ok((symbol.clone(), format!("{}.name", symbol)))
+    }
+
+    fn emit(mut self) -> Result<(), CodegenError> {
+        let slots = FIXED_SLOTS + self.registers.len() + self.spills.len();
+        // rbx is already pushed, keep rsp aligned to 16
+        let frame = slots * 8 - SAVED_RBX + (slots + 1) % 2 * 8;
+        let symbol = self.symbol();
+        emit!(self.out, "{}:", symbol);
+        self.line("push rbp");
+        self.line("mov rbp, rsp");
+        self.line("push rbx");
+        self.line(format!("sub rsp, {}", frame));
+        self.line(format!("mov qword ptr [rbp - {}], rdi", CLOSURE));
+        self.line(format!("mov qword ptr [rbp - {}], rsi", ARGUMENTS));
+        self.line("call rt_enter");
+        self.line(format!("mov qword ptr [rbp - {}], rax", SCOPE));
+        self.line(format!("mov qword ptr [rbp - {}], rsp", STACK_BASE));
+
+        for instr in &self.block.program {
+            self.instruction(instr)?;
+        }
+
+        let ident = mangle(&self.block.ident);
+        if self.block.ident == self.module.entry {
+            // Like the vm, whatever is on top of the stack or Nil
+            self.line("lea rax, [rip + rt_nil_value]");
+            self.line(format!("cmp rsp, qword ptr [rbp - {}]", STACK_BASE));
+            self.line(format!("je .L{}.return", ident));
+            self.line("mov rax, qword ptr [rsp]");
+        } else {
+            self.line(format!("lea rdi, [rip + {}.name]", symbol));
+            self.call("rt_missing_return");
+        }
+        emit!(self.out, ".L{}.return:", ident);
+        self.line("lea rsp, [rbp - 8]");
+        self.line("pop rbx");
+        self.line("pop rbp");
+        self.line("ret");
+        emit!(self.out, ".L{}.underflow:", ident);
+        self.call("rt_stack_underflow");
+        Ok(())
+    }
+
+    fn instruction(&mut self, instr: &LinearInstruction) -> Result<(), CodegenError> {
+        let ident = mangle(&self.block.ident);
+        match instr {
+            LinearInstruction::AcceptToFormals {
+                static_formals_list,
+            } => {
+                let StaticData::List(formals) = &static_formals_list.reftype else {
+                    return Err(CodegenError::NotAName(static_formals_list.refname.clone()));
+                };
+                if !formals.iter().all(|formal| {
+                    matches!(formal, StaticData::Identifier(_) | StaticData::String(_))
+                }) {
+                    return Err(CodegenError::NotAName(static_formals_list.refname.clone()));
+                }
+                self.line(format!("mov rdi, qword ptr [rbp - {}]", SCOPE));
+                self.static_ref("rsi", static_formals_list)?;
+                self.line(format!("mov rdx, qword ptr [rbp - {}]", ARGUMENTS));
+                self.line(format!("mov rcx, qword ptr [rbp - {}]", CLOSURE));
+                self.call("rt_accept");
+                // Taken, a second AcceptToFormals fails like in the vm
+                self.line(format!("mov qword ptr [rbp - {}], 0", ARGUMENTS));
+            }
+            LinearInstruction::NewScopeAttachedToAndReplacingCurrent
+            | LinearInstruction::PopScopeAndReplaceWithUpper => {
+                let function = match instr {
+                    LinearInstruction::NewScopeAttachedToAndReplacingCurrent => "rt_attach",
+                    _ => "rt_parent",
+                };
+                self.line(format!("mov rdi, qword ptr [rbp - {}]", SCOPE));
+                self.call(function);
+                self.line(format!("mov qword ptr [rbp - {}], rax", SCOPE));
+            }
+            LinearInstruction::StaticRefToRegister { static_ref, to_reg } => {
+                self.static_ref("rax", static_ref)?;
+                self.line(format!("mov {}, rax", self.reg(to_reg)));
+            }
+            LinearInstruction::PushToStack { register } => {
+                self.line(format!("push {}", self.reg(register)));
+            }
+            LinearInstruction::PopFromStack { register } => {
+                self.line(format!("cmp rsp, qword ptr [rbp - {}]", STACK_BASE));
+                self.line(format!("je .L{}.underflow", ident));
+                self.line(format!("pop {}", self.reg(register)));
+            }
+            LinearInstruction::LinkedListInit { output_reg } => {
+                self.line("lea rax, [rip + rt_nil_value]");
+                self.line(format!("mov {}, rax", self.reg(output_reg)));
+            }
+            LinearInstruction::LinkedListAdd {
+                linked_list_reg,
+                input_reg,
+                output_reg,
+            } => {
+                self.line(format!("mov rdi, {}", self.reg(linked_list_reg)));
+                self.line(format!("mov rsi, {}", self.reg(input_reg)));
+                self.call("rt_list_add");
+                self.line(format!("mov {}, rax", self.reg(output_reg)));
+            }
+            LinearInstruction::Assign {
+                identifier,
+                from_reg,
+                scope,
+            } => {
+                self.scope("rdi", scope);
+                self.name("rsi", identifier)?;
+                self.line(format!("mov rdx, {}", self.reg(from_reg)));
+                self.call("rt_define");
+            }
+            LinearInstruction::Call {
+                output_reg,
+                function_pointer,
+                arguments,
+            } => {
+                self.line(format!("mov rdi, {}", self.reg(function_pointer)));
+                self.line(format!("mov rsi, {}", self.reg(arguments)));
+                self.call("rt_call");
+                self.line(format!("mov {}, rax", self.reg(output_reg)));
+            }
+            LinearInstruction::Lookup {
+                identifier,
+                to_reg,
+                scope,
+            } => {
+                self.scope("rdi", scope);
+                self.name("rsi", identifier)?;
+                self.call("rt_lookup");
+                self.line(format!("mov {}, rax", self.reg(to_reg)));
+            }
+            LinearInstruction::Cond { .. } | LinearInstruction::EndOfCond { .. } => {
+                unreachable!("blocks are flattened before emitting")
+            }
+            LinearInstruction::Phi { .. } => {
+                return Err(CodegenError::Phi(self.block.ident.clone()));
+            }
+            LinearInstruction::Return { value } => {
+                self.line(format!("mov rax, {}", self.reg(value)));
+                self.line(format!("jmp .L{}.return", ident));
+            }
+            LinearInstruction::TailCall {
+                function_pointer,
+                arguments,
+            } => {
+                self.line(format!("mov rdi, {}", self.reg(function_pointer)));
+                self.line(format!("mov rsi, {}", self.reg(arguments)));
+                self.call("rt_tail_call");
+                self.line(format!("jmp .L{}.return", ident));
+            }
+            LinearInstruction::InitializeFunctionPointer {
+                function,
+                from_scope,
+                outpu_reg,
+            } => {
+                let (code, name) = self.function(&function.actual_func)?;
+                self.scope("rsi", from_scope);
+                self.line(format!("lea rdi, [rip + {}]", code));
+                self.line(format!("lea rdx, [rip + {}]", name));
+                self.call("rt_closure");
+                self.line(format!("mov {}, rax", self.reg(outpu_reg)));
+            }
+            LinearInstruction::Move { from_reg, to_reg } => {
+                self.line(format!("mov rax, {}", self.reg(from_reg)));
+                self.line(format!("mov {}, rax", self.reg(to_reg)));
+            }
+            LinearInstruction::SpillToSlot { from_reg, slot } => {
+                self.line(format!("mov rax, {}", self.reg(from_reg)));
+                self.line(format!("mov qword ptr [rbp - {}], rax", self.spills[slot]));
+            }
+            LinearInstruction::ReloadFromSlot { slot, to_reg } => {
+                self.line(format!("mov rax, qword ptr [rbp - {}]", self.spills[slot]));
+                self.line(format!("mov {}, rax", self.reg(to_reg)));
+            }
+            LinearInstruction::MakeClosure {
+                function,
+                captures,
+                output_reg,
+            } => {
+                let (code, name) = self.function(&function.actual_func)?;
+                self.line(format!("lea rdi, [rip + {}]", code));
+                self.line(format!("mov rsi, {}", captures.len()));
+                self.line(format!("lea rdx, [rip + {}]", name));
+                self.call("rt_flat_closure");
+                // The output can be one of the captures, it is only written once they are in
+                self.line(format!("mov qword ptr [rbp - {}], rax", TEMP));
+                for (index, capture) in captures.iter().enumerate() {
+                    self.line(format!("mov rdi, qword ptr [rbp - {}]", TEMP));
+                    self.line(format!("mov rsi, {}", index));
+                    self.line(format!("mov rdx, {}", self.reg(capture)));
+                    self.call("rt_set_capture");
+                }
+                self.line(format!("mov rax, qword ptr [rbp - {}]", TEMP));
+                self.line(format!("mov {}, rax", self.reg(output_reg)));
+            }
+            LinearInstruction::LoadCapture { index, to_reg } => {
+                self.line(format!("mov rdi, qword ptr [rbp - {}]", CLOSURE));
+                self.line(format!("mov rsi, {}", index));
+                self.call("rt_load_capture");
+                self.line(format!("mov {}, rax", self.reg(to_reg)));
+            }
+            LinearInstruction::PrimOp {
+                op,
+                arguments,
+                output_reg,
+            } => {
+                let index = Builtin::ALL
+                    .iter()
+                    .position(|builtin| builtin == op)
+                    .expect("every builtin is in ALL");
+                // Arguments go in an array on the machine stack, dropped right after
+                for argument in arguments.iter().rev() {
+                    self.line(format!("push {}", self.reg(argument)));
+                }
+                self.line(format!("mov rdi, {}", index));
+                self.line(format!("mov rsi, {}", arguments.len()));
+                self.line("mov rdx, rsp");
+                self.call("rt_prim");
+                self.line(format!("add rsp, {}", arguments.len() * 8));
+                self.line(format!("mov {}, rax", self.reg(output_reg)));
+            }
+            LinearInstruction::LoadLocal {
+                depth,
+                slot,
+                to_reg,
+            } => {
+                self.line(format!("mov rdi, qword ptr [rbp - {}]", SCOPE));
+                self.line(format!("mov rsi, {}", depth));
+                self.line(format!("mov rdx, {}", slot));
+                self.call("rt_load_local");
+                self.line(format!("mov {}, rax", self.reg(to_reg)));
+            }
+            LinearInstruction::StoreLocal {
+                depth,
+                slot,
+                from_reg,
+            } => {
+                self.line(format!("mov rdi, qword ptr [rbp - {}]", SCOPE));
+                self.line(format!("mov rsi, {}", depth));
+                self.line(format!("mov rdx, {}", slot));
+                self.line(format!("mov rcx, {}", self.reg(from_reg)));
+                self.call("rt_store_local");
+            }
+            LinearInstruction::Label { name } => {
+                emit!(self.out, "{}:", self.label(name));
+            }
+            LinearInstruction::Jump { label } => {
+                let target = self.jump_target(label)?;
+                self.line(format!("jmp {}", target));
+            }
+            LinearInstruction::JumpIfFalse { condition, label } => {
+                // Only #f is false
+                let target = self.jump_target(label)?;
+                self.line(format!("mov rax, {}", self.reg(condition)));
+                self.line(format!("cmp qword ptr [rax], {}", TAG_BOOL));
+                self.line("jne 1f");
+                self.line("cmp qword ptr [rax + 8], 0");
+                self.line(format!("je {}", target));
+                emit!(self.out, "1:");
+            }
+        }
+        Ok(())
+    }
+    fn jump_target(&self, label: &str) -> Result<String, CodegenError> {
+        if !self.labels.contains(label) {
+            return Err(CodegenError::UnknownLabel(label.to_string()));
+        }
+        Ok(self.label(label))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        process::{Command, Output},
+        rc::Rc,
+    };
+
+    use little_parser::{AtomTypes, Expression, Programm};
+
+    use super::{emit_module, CodegenError, RUNTIME};
+    use crate::{
+        asm::parse_blocks,
+        closure::convert_closures,
+        fold::fold_constants,
+        module::IrModule,
+        regalloc::allocate_registers,
+        stack::stack_to_registers_module,
+        test_ast::{boolean, call, ident, int, lambda},
+        value::Builtin,
+        vm::Vm,
+        StaticData, Translator,
+    };
+
+    /// Assembles, links and runs `module`, None if there is no toolchain to do it with
+    fn run_native(module: &IrModule, name: &str) -> Option<Output> {
+        let dir = std::env::temp_dir().join(format!("lir-x86_64-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("runtime.c"), RUNTIME).unwrap();
+        fs::write(dir.join("program.s"), emit_module(module).unwrap()).unwrap();
+        let steps: [&[&str]; 3] = [
+            &[
+                "cc",
+                "-c",
+                "-O2",
+                "-ffreestanding",
+                "-fno-builtin",
+                "-fno-stack-protector",
+                "-fno-pie",
+                "runtime.c",
+            ],
+            &["as", "program.s", "-o", "program.o"],
+            &["ld", "program.o", "runtime.o", "-o", "program"],
+        ];
+        for step in steps {
+            let output = Command::new(step[0])
+                .args(&step[1..])
+                .current_dir(&dir)
+                .output()
+                .ok()?;
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        let output = Command::new(dir.join("program")).output().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        Some(output)
+    }
+
+    #[test]
+    fn runs_like_the_vm() {
+        // (define count (lambda (n acc) (cond ((= n 0) acc) (else (count (- n 1) (+ acc 1))))))
+        // (define make-adder (lambda (x) (lambda (y) (+ x y))))
+        // (let ((add2 (make-adder 2)) (s "a \"b\"\n"))
+        //   (list (count 100000 0) (add2 40) s 'sym (cons 1 2) (cond (#f 1)) car make-adder))
+        let program = Programm::Expression(vec![
+            Expression::Define(
+                "count".into(),
+                Rc::new(lambda(
+                    &["n", "acc"],
+                    vec![Expression::Cond(vec![
+                        (call(ident("="), vec![ident("n"), int(0)]), ident("acc")),
+                        (
+                            ident("else"),
+                            call(
+                                ident("count"),
+                                vec![
+                                    call(ident("-"), vec![ident("n"), int(1)]),
+                                    call(ident("+"), vec![ident("acc"), int(1)]),
+                                ],
+                            ),
+                        ),
+                    ])],
+                )),
+            ),
+            Expression::Define(
+                "make-adder".into(),
+                Rc::new(lambda(
+                    &["x"],
+                    vec![lambda(
+                        &["y"],
+                        vec![call(ident("+"), vec![ident("x"), ident("y")])],
+                    )],
+                )),
+            ),
+            Expression::Let(
+                vec![
+                    ("add2".into(), call(ident("make-adder"), vec![int(2)])),
+                    (
+                        "s".into(),
+                        Expression::Atom(AtomTypes::String("a \"b\"\n".into())),
+                    ),
+                ],
+                vec![call(
+                    ident("list"),
+                    vec![
+                        call(ident("count"), vec![int(100000), int(0)]),
+                        call(ident("add2"), vec![int(40)]),
+                        ident("s"),
+                        Expression::Quote(AtomTypes::Symbol("sym".into())),
+                        call(ident("cons"), vec![int(1), int(2)]),
+                        Expression::Cond(vec![(boolean(false), int(1))]),
+                        ident("car"),
+                        ident("make-adder"),
+                    ],
+                )],
+            ),
+        ]);
+        let module = Translator::default().translate_module(program).unwrap();
+        let expected = Vm::new(&module.blocks).run("main").unwrap().to_string();
+        assert_eq!(
+            expected,
+            r#"(100000 42 "a \"b\"\n" sym (1 . 2) #<void> #<builtin car> #<procedure _1>)"#
+        );
+
+        // PrimOps, flat closures and spill slots too
+        let mut optimized = module.clone();
+        stack_to_registers_module(&mut optimized);
+        convert_closures(&mut optimized);
+        fold_constants(&mut optimized);
+        for block in optimized.blocks.values_mut() {
+            allocate_registers(block, 4).unwrap();
+        }
+        assert_eq!(
+            Vm::new(&optimized.blocks).run("main").unwrap().to_string(),
+            expected
+        );
+
+        for (module, name) in [(module, "plain"), (optimized, "optimized")] {
+            let Some(output) = run_native(&module, name) else {
+                return;
+            };
+            assert!(output.status.success());
+            assert_eq!(
+                String::from_utf8(output.stdout).unwrap(),
+                format!("{}\n", expected)
+            );
+        }
+    }
+
+    #[test]
+    fn errors_and_runtime_tables() {
+        let module = |src: &str, statics: &[(&str, StaticData)]| IrModule {
+            entry: "main".into(),
+            blocks: parse_blocks(src)
+                .unwrap()
+                .into_iter()
+                .map(|block| (block.ident.clone(), block))
+                .collect(),
+            static_data: statics
+                .iter()
+                .map(|(refname, data)| (refname.to_string(), data.clone()))
+                .collect(),
+            metadata: Default::default(),
+            source_map: Default::default(),
+        };
+        assert_eq!(
+            emit_module(&module(
+                "block main {
+                     load static0{#t} -> vreg0
+                     jump nowhere
+                 }",
+                &[("static0", StaticData::Bool(true))]
+            )),
+            Err(CodegenError::UnknownLabel("nowhere".into()))
+        );
+        // The runtime fails like the vm would
+        let failing = module(
+            "block main {
+                 lookup static0{car} -> vreg0 @global
+                 load static1{5} -> vreg1
+                 list.init -> vreg2
+                 list.add vreg2 <- vreg1 -> vreg2
+                 call vreg0 vreg2 -> vreg3
+             }",
+            &[
+                ("static0", StaticData::Identifier("car".into())),
+                ("static1", StaticData::Integer(5)),
+            ],
+        );
+        if let Some(output) = run_native(&failing, "failing") {
+            assert_eq!(output.status.code(), Some(1));
+            assert_eq!(
+                String::from_utf8(output.stderr).unwrap(),
+                "error: expected pair but found `5`\n"
+            );
+        }
+
+        // PrimOps pass their index into Builtin::ALL
+        let names: Vec<String> = Builtin::ALL
+            .iter()
+            .map(|builtin| format!("{:?}", builtin.name()))
+            .collect();
+        assert!(RUNTIME.contains(&format!("builtin_names[] = {{{}}};", names.join(", "))));
+    }
+}
@@ -0,0 +1,694 @@
+/* Runtime for programs the `x86_64` backend emits.
+ * Freestanding, it talks to Linux through raw syscalls so a program needs nothing else:
+ *
+ *     cc -c -O2 -ffreestanding -fno-builtin -fno-stack-protector -fno-pie runtime.c
+ *     as program.s -o program.o
+ *     ld program.o runtime.o -o program
+ *
+ * Runs the entry block, prints what it gives like `Value`s Display does and exits with 0,
+ * or prints the error the vm would have given to stderr and exits with 1. Nothing is ever
+ * freed, like `ScopeChain` never frees scopes.
+ *
+ * The layout of Value and the tags have to match the static data x86_64.rs emits. */
+
+typedef struct Value Value;
+typedef struct Scope Scope;
+typedef Value *(*Code)(Value *closure, Value *args);
+
+enum Tag { NIL, VOID, INTEGER, BOOL, STRING, SYMBOL, PAIR, CLOSURE, FLAT_CLOSURE, BUILTIN };
+
+struct Value {
+    long tag;
+    union {
+        /* INTEGER, BOOL as 0 or 1, BUILTIN as its index into builtin_names */
+        long integer;
+        /* STRING and SYMBOL, nul terminated */
+        const char *text;
+        struct {
+            Value *car, *cdr;
+        } pair;
+        /* env is the Scope of a CLOSURE and the Captures of a FLAT_CLOSURE */
+        struct {
+            Code code;
+            void *env;
+            const char *name;
+        } procedure;
+    };
+};
+
+typedef struct {
+    long count;
+    Value *values[];
+} Captures;
+
+typedef struct Binding {
+    const char *name;
+    Value *value;
+    struct Binding *next;
+} Binding;
+
+struct Scope {
+    Scope *parent;
+    Binding *bindings;
+    long slot_count;
+    /* By lexical address, NULL until something is stored */
+    Value **slots;
+};
+
+/* In the order of Builtin::ALL, PrimOps pass their index */
+enum { ADD, SUB, MUL, DIV, NUM_EQ, LT, GT, LE, GE, EQ, NOT, CAR, CDR, CONS, LIST, IS_NULL, BUILTIN_COUNT };
+static const char *const builtin_names[] = {"+", "-", "*", "/", "=", "<", ">", "<=", ">=", "eq?", "not", "car", "cdr", "cons", "list", "null?"};
+
+Value rt_nil_value = {.tag = NIL};
+Value rt_void_value = {.tag = VOID};
+static Value true_value = {.tag = BOOL, .integer = 1};
+static Value false_value = {.tag = BOOL, .integer = 0};
+Scope *rt_global_scope;
+
+/* The entry block, `main` unless the module says otherwise */
+extern Value *lir_entry(Value *closure, Value *args);
+
+/* Syscalls */
+
+static long syscall3(long number, long a, long b, long c) {
+    long ret;
+    __asm__ volatile("syscall" : "=a"(ret) : "a"(number), "D"(a), "S"(b), "d"(c) : "rcx", "r11", "memory");
+    return ret;
+}
+
+static long syscall6(long number, long a, long b, long c, long d, long e, long f) {
+    long ret;
+    register long r10 __asm__("r10") = d;
+    register long r8 __asm__("r8") = e;
+    register long r9 __asm__("r9") = f;
+    __asm__ volatile("syscall"
+                     : "=a"(ret)
+                     : "a"(number), "D"(a), "S"(b), "d"(c), "r"(r10), "r"(r8), "r"(r9)
+                     : "rcx", "r11", "memory");
+    return ret;
+}
+
+static _Noreturn void exit_with(long code) {
+    syscall3(60, code, 0, 0);
+    __builtin_unreachable();
+}
+
+/* Output */
+
+typedef struct {
+    int fd;
+    long len;
+    char data[4096];
+} Out;
+
+static Out out = {.fd = 1};
+static Out err = {.fd = 2};
+
+static void flush(Out *to) {
+    long done = 0;
+    while (done < to->len) {
+        long written = syscall3(1, to->fd, (long)(to->data + done), to->len - done);
+        if (written <= 0)
+            break;
+        done += written;
+    }
+    to->len = 0;
+}
+
+static void put_char(Out *to, char c) {
+    if (to->len == sizeof to->data)
+        flush(to);
+    to->data[to->len++] = c;
+}
+
+static void put(Out *to, const char *text) {
+    while (*text)
+        put_char(to, *text++);
+}
+
+static void put_int(Out *to, long value) {
+    char digits[24];
+    int count = 0;
+    unsigned long magnitude = value < 0 ? -(unsigned long)value : (unsigned long)value;
+    if (value < 0)
+        put_char(to, '-');
+    do {
+        digits[count++] = '0' + magnitude % 10;
+        magnitude /= 10;
+    } while (magnitude);
+    while (count)
+        put_char(to, digits[--count]);
+}
+
+static void put_hex(Out *to, unsigned value) {
+    const char *hex = "0123456789abcdef";
+    int shift = 28;
+    while (shift > 0 && !(value >> shift))
+        shift -= 4;
+    for (; shift >= 0; shift -= 4)
+        put_char(to, hex[(value >> shift) & 15]);
+}
+
+/* Rust's Debug of a str, which is what the vm prints strings with */
+static void put_quoted(Out *to, const char *text) {
+    put_char(to, '"');
+    for (; *text; text++) {
+        unsigned char c = *text;
+        switch (c) {
+        case '"': put(to, "\\\""); break;
+        case '\\': put(to, "\\\\"); break;
+        case '\n': put(to, "\\n"); break;
+        case '\t': put(to, "\\t"); break;
+        case '\r': put(to, "\\r"); break;
+        case '\0': put(to, "\\0"); break;
+        default:
+            if (c < 0x20 || c == 0x7f) {
+                put(to, "\\u{");
+                put_hex(to, c);
+                put_char(to, '}');
+            } else {
+                put_char(to, c);
+            }
+        }
+    }
+    put_char(to, '"');
+}
+
+static void put_value(Out *to, Value *value) {
+    switch (value->tag) {
+    case NIL: put(to, "()"); break;
+    case VOID: put(to, "#<void>"); break;
+    case INTEGER: put_int(to, value->integer); break;
+    case BOOL: put(to, value->integer ? "#t" : "#f"); break;
+    case STRING: put_quoted(to, value->text); break;
+    case SYMBOL: put(to, value->text); break;
+    case PAIR:
+        put_char(to, '(');
+        put_value(to, value->pair.car);
+        for (value = value->pair.cdr; value->tag == PAIR; value = value->pair.cdr) {
+            put_char(to, ' ');
+            put_value(to, value->pair.car);
+        }
+        if (value->tag != NIL) {
+            put(to, " . ");
+            put_value(to, value);
+        }
+        put_char(to, ')');
+        break;
+    case CLOSURE:
+    case FLAT_CLOSURE:
+        put(to, "#<procedure ");
+        put(to, value->procedure.name);
+        put_char(to, '>');
+        break;
+    case BUILTIN:
+        put(to, "#<builtin ");
+        put(to, builtin_names[value->integer]);
+        put_char(to, '>');
+        break;
+    }
+}
+
+/* Errors, worded like VmError */
+
+static Out *error_begin(void) {
+    flush(&out);
+    put(&err, "error: ");
+    return &err;
+}
+
+static _Noreturn void error_end(void) {
+    put_char(&err, '\n');
+    flush(&err);
+    exit_with(1);
+}
+
+static _Noreturn void fail(const char *message) {
+    put(error_begin(), message);
+    error_end();
+}
+
+static _Noreturn void fail_value(const char *before, Value *value, const char *after) {
+    Out *to = error_begin();
+    put(to, before);
+    put_value(to, value);
+    put(to, after);
+    error_end();
+}
+
+static _Noreturn void fail_arity(const char *function, long expected, long got) {
+    Out *to = error_begin();
+    put_char(to, '`');
+    put(to, function);
+    put(to, "` expects ");
+    put_int(to, expected);
+    put(to, " arguments but got ");
+    put_int(to, got);
+    error_end();
+}
+
+static _Noreturn void fail_type(const char *expected, Value *found) {
+    Out *to = error_begin();
+    put(to, "expected ");
+    put(to, expected);
+    put(to, " but found `");
+    put_value(to, found);
+    put_char(to, '`');
+    error_end();
+}
+
+static _Noreturn void fail_overflow(long op) {
+    Out *to = error_begin();
+    put(to, "integer overflow in `");
+    put(to, builtin_names[op]);
+    put_char(to, '`');
+    error_end();
+}
+
+static _Noreturn void fail_local(long depth, long slot) {
+    Out *to = error_begin();
+    put(to, "local ");
+    put_int(to, depth);
+    put_char(to, ':');
+    put_int(to, slot);
+    put(to, " read before it was set");
+    error_end();
+}
+
+_Noreturn void rt_stack_underflow(void) {
+    fail("pop from empty stack");
+}
+
+_Noreturn void rt_missing_return(const char *block) {
+    Out *to = error_begin();
+    put(to, "block `");
+    put(to, block);
+    put(to, "` ended without Return");
+    error_end();
+}
+
+/* Allocation, a bump pointer through chunks from mmap */
+
+#define CHUNK (1L << 20)
+
+static char *heap;
+static char *heap_end;
+
+static void *alloc(unsigned long size) {
+    size = (size + 15) & ~15UL;
+    if ((unsigned long)(heap_end - heap) < size) {
+        unsigned long chunk = size > CHUNK ? size : CHUNK;
+        /* PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, comes back zeroed */
+        long mapped = syscall6(9, 0, chunk, 3, 0x22, -1, 0);
+        if (mapped < 0 && mapped > -4096)
+            fail("out of memory");
+        heap = (char *)mapped;
+        heap_end = heap + chunk;
+    }
+    void *block = heap;
+    heap += size;
+    return block;
+}
+
+static Value *make(long tag) {
+    Value *value = alloc(sizeof(Value));
+    value->tag = tag;
+    return value;
+}
+
+static Value *integer(long integer) {
+    Value *value = make(INTEGER);
+    value->integer = integer;
+    return value;
+}
+
+static Value *boolean(int condition) {
+    return condition ? &true_value : &false_value;
+}
+
+static Value *cons(Value *car, Value *cdr) {
+    Value *value = make(PAIR);
+    value->pair.car = car;
+    value->pair.cdr = cdr;
+    return value;
+}
+
+static int is_truthy(Value *value) {
+    return !(value->tag == BOOL && value->integer == 0);
+}
+
+static int text_equal(const char *a, const char *b) {
+    while (*a && *a == *b) {
+        a++;
+        b++;
+    }
+    return *a == *b;
+}
+
+/* Same as == on Value */
+static int equal(Value *a, Value *b) {
+    if (a == b)
+        return 1;
+    if (a->tag != b->tag)
+        return 0;
+    switch (a->tag) {
+    case NIL:
+    case VOID:
+        return 1;
+    case INTEGER:
+    case BOOL:
+    case BUILTIN:
+        return a->integer == b->integer;
+    case STRING:
+    case SYMBOL:
+        return text_equal(a->text, b->text);
+    case PAIR:
+        return equal(a->pair.car, b->pair.car) && equal(a->pair.cdr, b->pair.cdr);
+    case CLOSURE:
+        return a->procedure.code == b->procedure.code && a->procedure.env == b->procedure.env;
+    case FLAT_CLOSURE: {
+        Captures *left = a->procedure.env, *right = b->procedure.env;
+        if (a->procedure.code != b->procedure.code || left->count != right->count)
+            return 0;
+        for (long i = 0; i < left->count; i++)
+            if (!equal(left->values[i], right->values[i]))
+                return 0;
+        return 1;
+    }
+    }
+    return 0;
+}
+
+/* Items of a proper list, fails on anything else */
+static long list_length(Value *list) {
+    long length = 0;
+    Value *current = list;
+    for (; current->tag == PAIR; current = current->pair.cdr)
+        length++;
+    if (current->tag != NIL)
+        fail_value("`", list, "` is not a proper list");
+    return length;
+}
+
+/* Builtins */
+
+static long int_of(Value *value) {
+    if (value->tag != INTEGER)
+        fail_type("integer", value);
+    return value->integer;
+}
+
+static void arity(long op, long expected, long got) {
+    if (expected != got)
+        fail_arity(builtin_names[op], expected, got);
+}
+
+static Value *apply(long op, long argc, Value **argv) {
+    int result;
+    switch (op) {
+    case ADD:
+    case MUL:
+        result = op == ADD ? 0 : 1;
+        for (long i = 0; i < argc; i++) {
+            int overflow = op == ADD ? __builtin_add_overflow(result, (int)int_of(argv[i]), &result)
+                                     : __builtin_mul_overflow(result, (int)int_of(argv[i]), &result);
+            if (overflow)
+                fail_overflow(op);
+        }
+        return integer(result);
+    case SUB:
+        if (argc == 0)
+            fail_arity(builtin_names[op], 1, 0);
+        result = int_of(argv[0]);
+        if (argc == 1) {
+            if (__builtin_sub_overflow(0, result, &result))
+                fail_overflow(op);
+            return integer(result);
+        }
+        for (long i = 1; i < argc; i++)
+            if (__builtin_sub_overflow(result, (int)int_of(argv[i]), &result))
+                fail_overflow(op);
+        return integer(result);
+    case DIV: {
+        arity(op, 2, argc);
+        int divisor = int_of(argv[1]);
+        if (divisor == 0)
+            fail("division by zero");
+        int dividend = int_of(argv[0]);
+        if (dividend == -2147483647 - 1 && divisor == -1)
+            fail_overflow(op);
+        return integer(dividend / divisor);
+    }
+    case NUM_EQ:
+    case LT:
+    case GT:
+    case LE:
+    case GE: {
+        arity(op, 2, argc);
+        long a = int_of(argv[0]), b = int_of(argv[1]);
+        switch (op) {
+        case NUM_EQ: return boolean(a == b);
+        case LT: return boolean(a < b);
+        case GT: return boolean(a > b);
+        case LE: return boolean(a <= b);
+        default: return boolean(a >= b);
+        }
+    }
+    case EQ:
+        arity(op, 2, argc);
+        return boolean(equal(argv[0], argv[1]));
+    case NOT:
+        arity(op, 1, argc);
+        return boolean(!is_truthy(argv[0]));
+    case CAR:
+    case CDR:
+        arity(op, 1, argc);
+        if (argv[0]->tag != PAIR)
+            fail_type("pair", argv[0]);
+        return op == CAR ? argv[0]->pair.car : argv[0]->pair.cdr;
+    case CONS:
+        arity(op, 2, argc);
+        return cons(argv[0], argv[1]);
+    case LIST: {
+        Value *list = &rt_nil_value;
+        for (long i = argc; i > 0; i--)
+            list = cons(argv[i - 1], list);
+        return list;
+    }
+    case IS_NULL:
+        arity(op, 1, argc);
+        return boolean(argv[0]->tag == NIL);
+    }
+    fail("unknown builtin");
+}
+
+Value *rt_prim(long op, long argc, Value **argv) {
+    return apply(op, argc, argv);
+}
+
+/* Scopes */
+
+Scope *rt_attach(Scope *parent) {
+    Scope *scope = alloc(sizeof(Scope));
+    scope->parent = parent;
+    return scope;
+}
+
+/* Popping the outermost scope just leaves us there */
+Scope *rt_parent(Scope *scope) {
+    return scope->parent ? scope->parent : scope;
+}
+
+void rt_define(Scope *scope, Value *name, Value *value) {
+    for (Binding *binding = scope->bindings; binding; binding = binding->next) {
+        if (text_equal(binding->name, name->text)) {
+            binding->value = value;
+            return;
+        }
+    }
+    Binding *binding = alloc(sizeof(Binding));
+    binding->name = name->text;
+    binding->value = value;
+    binding->next = scope->bindings;
+    scope->bindings = binding;
+}
+
+Value *rt_lookup(Scope *scope, Value *name) {
+    for (; scope; scope = scope->parent)
+        for (Binding *binding = scope->bindings; binding; binding = binding->next)
+            if (text_equal(binding->name, name->text))
+                return binding->value;
+    Out *to = error_begin();
+    put(to, "unbound identifier `");
+    put(to, name->text);
+    put_char(to, '`');
+    error_end();
+}
+
+static Scope *ancestor(Scope *scope, long depth, long slot) {
+    for (long i = 0; i < depth && scope; i++)
+        scope = scope->parent;
+    if (!scope)
+        fail_local(depth, slot);
+    return scope;
+}
+
+Value *rt_load_local(Scope *scope, long depth, long slot) {
+    scope = ancestor(scope, depth, slot);
+    if (slot >= scope->slot_count || !scope->slots[slot])
+        fail_local(depth, slot);
+    return scope->slots[slot];
+}
+
+void rt_store_local(Scope *scope, long depth, long slot, Value *value) {
+    scope = ancestor(scope, depth, slot);
+    if (slot >= scope->slot_count) {
+        long count = slot + 1 > 2 * scope->slot_count ? slot + 1 : 2 * scope->slot_count;
+        Value **slots = alloc(count * sizeof(Value *));
+        for (long i = 0; i < scope->slot_count; i++)
+            slots[i] = scope->slots[i];
+        scope->slots = slots;
+        scope->slot_count = count;
+    }
+    scope->slots[slot] = value;
+}
+
+/* The scope a closure was made in, for Scope::Custom */
+Scope *rt_closure_scope(Value *closure) {
+    if (closure->tag != CLOSURE)
+        fail_type("closure", closure);
+    return closure->procedure.env;
+}
+
+/* Every call gets a fresh scope below the one the closure was made in, the entry block runs
+ * straight in the global scope */
+Scope *rt_enter(Value *closure) {
+    if (!closure)
+        return rt_global_scope;
+    return rt_attach(closure->tag == CLOSURE ? closure->procedure.env : rt_global_scope);
+}
+
+/* Binds the arguments by name for lookups and by position for LoadLocal */
+void rt_accept(Scope *scope, Value *formals, Value *args, Value *closure) {
+    if (!args)
+        fail("AcceptToFormals outside of a call");
+    long expected = list_length(formals), got = list_length(args);
+    if (expected != got)
+        fail_arity(closure->procedure.name, expected, got);
+    for (long slot = 0; slot < got; slot++) {
+        rt_store_local(scope, 0, slot, args->pair.car);
+        rt_define(scope, formals->pair.car, args->pair.car);
+        formals = formals->pair.cdr;
+        args = args->pair.cdr;
+    }
+}
+
+/* Lists and closures */
+
+/* Appends to a copy, lists are values */
+Value *rt_list_add(Value *list, Value *item) {
+    long length = list_length(list);
+    Value *head = cons(item, &rt_nil_value);
+    Value **tail = &head;
+    for (long i = 0; i < length; i++, list = list->pair.cdr) {
+        Value *copy = cons(list->pair.car, *tail);
+        *tail = copy;
+        tail = &copy->pair.cdr;
+    }
+    return head;
+}
+
+Value *rt_closure(Code code, Scope *scope, const char *name) {
+    Value *closure = make(CLOSURE);
+    closure->procedure.code = code;
+    closure->procedure.env = scope;
+    closure->procedure.name = name;
+    return closure;
+}
+
+Value *rt_flat_closure(Code code, long count, const char *name) {
+    Captures *captures = alloc(sizeof(Captures) + count * sizeof(Value *));
+    captures->count = count;
+    Value *closure = make(FLAT_CLOSURE);
+    closure->procedure.code = code;
+    closure->procedure.env = captures;
+    closure->procedure.name = name;
+    return closure;
+}
+
+void rt_set_capture(Value *closure, long index, Value *value) {
+    ((Captures *)closure->procedure.env)->values[index] = value;
+}
+
+Value *rt_load_capture(Value *closure, long index) {
+    Captures *captures = closure && closure->tag == FLAT_CLOSURE ? closure->procedure.env : 0;
+    if (!captures || index >= captures->count) {
+        Out *to = error_begin();
+        put(to, "closure has no capture ");
+        put_int(to, index);
+        error_end();
+    }
+    return captures->values[index];
+}
+
+/* Calls. A tail call returns this marker to whoever called the function and they make the
+ * call instead, so tail calls run in constant stack. */
+
+static Value tail_marker;
+static Value *pending_function;
+static Value *pending_arguments;
+
+static Value *invoke(Value *function, Value *args) {
+    switch (function->tag) {
+    case CLOSURE:
+    case FLAT_CLOSURE:
+        return function->procedure.code(function, args);
+    case BUILTIN: {
+        long argc = list_length(args);
+        Value **argv = alloc(argc * sizeof(Value *) + 1);
+        for (long i = 0; i < argc; i++, args = args->pair.cdr)
+            argv[i] = args->pair.car;
+        return apply(function->integer, argc, argv);
+    }
+    }
+    fail_value("`", function, "` is not callable");
+}
+
+static Value *trampoline(Value *result) {
+    while (result == &tail_marker)
+        result = invoke(pending_function, pending_arguments);
+    return result;
+}
+
+Value *rt_call(Value *function, Value *args) {
+    return trampoline(invoke(function, args));
+}
+
+Value *rt_tail_call(Value *function, Value *args) {
+    pending_function = function;
+    pending_arguments = args;
+    return &tail_marker;
+}
+
+/* Startup */
+
+_Noreturn void rt_start(void) {
+    rt_global_scope = rt_attach(0);
+    for (long op = BUILTIN_COUNT; op > 0; op--) {
+        Value *builtin = make(BUILTIN);
+        builtin->integer = op - 1;
+        Value name = {.tag = SYMBOL, .text = builtin_names[op - 1]};
+        rt_define(rt_global_scope, &name, builtin);
+    }
+    Value *result = trampoline(lir_entry(0, 0));
+    put_value(&out, result);
+    put_char(&out, '\n');
+    flush(&out);
+    exit_with(0);
+}
+
+__asm__(".globl _start\n"
+        "_start:\n"
+        "    xor %ebp, %ebp\n"
+        "    and $-16, %rsp\n"
+        "    call rt_start\n");